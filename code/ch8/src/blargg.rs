@@ -0,0 +1,159 @@
+// Headless runner for blargg's test ROMs (instr_test-v5, ppu_vbl_nmi, apu_test, ...)
+// https://github.com/christopherpow/nes-test-roms
+//
+// The newer suites report through PRG-RAM: once $6001-$6003 hold DE B0 61,
+// $6000 is the status and $6004 a zero-terminated message.
+//
+// The ROMs are not part of the repo, point BLARGG_ROMS to a directory of them:
+// BLARGG_ROMS=~/nes-test-roms/instr_test-v5 cargo test --release blargg -- --ignored --nocapture
+use crate::bus::Bus;
+use crate::cartridge::Rom;
+use crate::cpu::Mem;
+use crate::cpu::CPU;
+use std::any::Any;
+use std::fs;
+use std::panic;
+use std::path::Path;
+use std::path::PathBuf;
+
+const DEFAULT_ROMS_DIR: &str = "test_roms";
+
+const STATUS: u16 = 0x6000;
+const SIGNATURE: u16 = 0x6001;
+const MESSAGE: u16 = 0x6004;
+const STATUS_RUNNING: u8 = 0x80;
+const STATUS_RESET_REQUESTED: u8 = 0x81;
+
+// two emulated minutes, the combined ROMs like all_instrs.nes run for tens of seconds
+const MAX_FRAMES: usize = 60 * 120;
+// the reset has to come at least 100ms after it was requested
+const RESET_DELAY_FRAMES: usize = 6;
+
+// $6000 once the test has written its signature
+fn test_status(bus: &Bus) -> Option<u8> {
+    let signature = [
+        bus.peek(SIGNATURE),
+        bus.peek(SIGNATURE + 1),
+        bus.peek(SIGNATURE + 2),
+    ];
+    if signature == [0xde, 0xb0, 0x61] {
+        Some(bus.peek(STATUS))
+    } else {
+        None
+    }
+}
+
+fn test_message(bus: &Bus) -> String {
+    let mut message = String::new();
+    let mut addr = MESSAGE;
+    while addr < 0x8000 && bus.peek(addr) != 0 {
+        message.push(bus.peek(addr) as char);
+        addr += 1;
+    }
+    message
+}
+
+// boots the ROM and polls $6000 once a frame, returns the result code and the message
+fn run_test_rom(path: &Path) -> Result<(u8, String), String> {
+    let bytes = fs::read(path).map_err(|e| e.to_string())?;
+    let rom = Rom::new(&bytes).map_err(|e| format!("{:?}", e))?;
+    let mut cpu = CPU::new(Bus::new(rom, |_ppu, _joypad| {}));
+    cpu.reset();
+
+    let mut reset_at = None;
+    for frame in 0..MAX_FRAMES {
+        cpu.run_frame();
+        if cpu.is_halted() {
+            return Err(format!("CPU jammed at ${:04x}", cpu.program_counter));
+        }
+        match test_status(&cpu.bus) {
+            Some(STATUS_RUNNING) | None => {}
+            Some(STATUS_RESET_REQUESTED) => match reset_at {
+                None => reset_at = Some(frame + RESET_DELAY_FRAMES),
+                Some(at) if at == frame => {
+                    cpu.reset();
+                    reset_at = None;
+                }
+                Some(_) => {}
+            },
+            Some(code) => return Ok((code, test_message(&cpu.bus))),
+        }
+    }
+    Err(format!("no result after {} frames", MAX_FRAMES))
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast::<&str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "panicked".to_string(),
+        },
+    }
+}
+
+fn find_roms(dir: &Path, roms: &mut Vec<PathBuf>) {
+    let mut entries: Vec<PathBuf> = fs::read_dir(dir)
+        .unwrap_or_else(|e| panic!("can't read {}: {}", dir.display(), e))
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            find_roms(&path, roms);
+        } else if path.extension().is_some_and(|ext| ext == "nes") {
+            roms.push(path);
+        }
+    }
+}
+
+#[test]
+#[ignore]
+fn blargg_test_roms() {
+    let dir = std::env::var("BLARGG_ROMS").unwrap_or_else(|_| DEFAULT_ROMS_DIR.into());
+    let mut roms = vec![];
+    find_roms(Path::new(&dir), &mut roms);
+
+    let mut failed = 0;
+    for path in roms.iter() {
+        let name = path
+            .strip_prefix(&dir)
+            .unwrap_or(path)
+            .display()
+            .to_string();
+        // a ROM that crashes the emulator shouldn't stop the rest of the run
+        let result = panic::catch_unwind(|| run_test_rom(path))
+            .unwrap_or_else(|payload| Err(format!("panic: {}", panic_message(payload))));
+        let (verdict, message) = match result {
+            Ok((0, message)) => ("passed".to_string(), message),
+            Ok((code, message)) => (format!("failed #{}", code), message),
+            Err(error) => ("error".to_string(), error),
+        };
+        if verdict != "passed" {
+            failed += 1;
+        }
+        let message = message.trim().replace('\n', " | ");
+        println!("{:<48} {:<10} {}", name, verdict, message);
+    }
+    println!("{} of {} passed", roms.len() - failed, roms.len());
+    assert_eq!(failed, 0);
+}
+
+#[test]
+fn test_result_protocol() {
+    let mut bus = Bus::new(crate::cartridge::test::test_rom(), |_ppu, _joypad| {});
+    bus.mem_write(STATUS, STATUS_RUNNING);
+    assert_eq!(test_status(&bus), None);
+
+    for (i, byte) in [0xde, 0xb0, 0x61].iter().enumerate() {
+        bus.mem_write(SIGNATURE + i as u16, *byte);
+    }
+    assert_eq!(test_status(&bus), Some(STATUS_RUNNING));
+
+    bus.mem_write(STATUS, 0x03);
+    for (i, byte) in b"\nFailed\n\0".iter().enumerate() {
+        bus.mem_write(MESSAGE + i as u16, *byte);
+    }
+    assert_eq!(test_status(&bus), Some(0x03));
+    assert_eq!(test_message(&bus), "\nFailed\n");
+}
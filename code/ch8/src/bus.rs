@@ -1,8 +1,16 @@
 use crate::cartridge::Rom;
+use crate::cpu::Clock;
 use crate::cpu::Mem;
+use crate::mapper;
+use crate::mapper::Mapper;
 use crate::ppu::NesPPU;
 use crate::ppu::PPU;
 use crate::joypad::Joypad;
+use std::cell::RefCell;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::rc::Rc;
 
 //  _______________ $10000  _______________
 // | PRG-ROM       |       |               |
@@ -33,15 +41,43 @@ use crate::joypad::Joypad;
 // |_______________| $0000 |_______________|
 const RAM: u16 = 0x0000;
 const RAM_MIRRORS_END: u16 = 0x1FFF;
-const PPU_REGISTERS: u16 = 0x2000;
 const PPU_REGISTERS_MIRRORS_END: u16 = 0x3FFF;
+const EXPANSION: u16 = 0x4020;
+const EXPANSION_END: u16 = 0x5FFF;
+const PRG_RAM: u16 = 0x6000;
+const PRG_RAM_END: u16 = 0x7FFF;
+const PRG_RAM_SIZE: usize = 0x2000;
+const TRAINER: u16 = 0x7000;
+const CPU_CLOCK_HZ: usize = 1_789_773;
+pub const AUDIO_SAMPLE_RATE: usize = 44_100;
+
+bitflags! {
+    /// Devices that can pull the CPU IRQ line low.
+    ///
+    /// The line is level-triggered: it stays asserted for as long as any source
+    /// holds it, until the device acknowledges the interrupt.
+    pub struct IrqSource: u8 {
+        const MAPPER        = 0b00000001;
+        const FRAME_COUNTER = 0b00000010;
+        const DMC           = 0b00000100;
+    }
+}
 
 pub struct Bus<'call> {
     cpu_vram: [u8; 2048],
-    prg_rom: Vec<u8>,
+    mapper: Rc<RefCell<dyn Mapper>>,
+    // always mapped, an iNES 1.0 header has no way to say a board has work RAM
+    // without a battery; the battery flag only decides whether it is saved
+    prg_ram: [u8; PRG_RAM_SIZE],
+    trainer: Option<Vec<u8>>,
+    battery: bool,
     ppu: NesPPU,
 
     cycles: usize,
+    frame: usize,
+    // cartridge audio at AUDIO_SAMPLE_RATE, up to a second of it until the frontend takes it
+    audio_samples: Vec<f32>,
+    irq_sources: IrqSource,
     gameloop_callback: Box<dyn FnMut(&NesPPU, &mut Joypad) + 'call>,
     joypad1: Joypad,
 }
@@ -51,32 +87,118 @@ impl<'a> Bus<'a> {
     where
         F: FnMut(&NesPPU, &mut Joypad) + 'call,
     {
-        let ppu = NesPPU::new(rom.chr_rom, rom.screen_mirroring);
+        let battery = rom.battery;
+        let trainer = rom.trainer.clone();
+        let mapper = mapper::from_rom(rom);
+        let ppu = NesPPU::new(mapper.clone());
 
-        Bus {
+        let mut bus = Bus {
             cpu_vram: [0; 2048],
-            prg_rom: rom.prg_rom,
+            mapper,
+            prg_ram: [0; PRG_RAM_SIZE],
+            trainer,
+            battery,
             ppu: ppu,
             cycles: 0,
+            frame: 0,
+            audio_samples: Vec::new(),
+            irq_sources: IrqSource::empty(),
             gameloop_callback: Box::from(gameloop_callback),
             joypad1: Joypad::new()
+        };
+        bus.map_trainer();
+        bus
+    }
+
+    // trainers are copied to $7000-$71FF before the game starts
+    fn map_trainer(&mut self) {
+        if let Some(trainer) = &self.trainer {
+            let start = (TRAINER - PRG_RAM) as usize;
+            self.prg_ram[start..start + trainer.len()].copy_from_slice(trainer);
+        }
+    }
+
+    pub fn has_battery(&self) -> bool {
+        self.battery
+    }
+
+    pub fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram
+    }
+
+    pub fn load_prg_ram(&mut self, data: &[u8]) {
+        let len = data.len().min(PRG_RAM_SIZE);
+        self.prg_ram[..len].copy_from_slice(&data[..len]);
+        // the save doesn't replace the trainer, it's still copied in at power-on
+        self.map_trainer();
+    }
+
+    // battery-backed carts keep PRG-RAM in a .sav file next to the rom,
+    // disk games keep the disk there
+    pub fn load_save_file(&mut self, rom_path: &Path) -> io::Result<()> {
+        let save_path = rom_path.with_extension("sav");
+        if !save_path.exists() {
+            return Ok(());
         }
+        let data = fs::read(save_path)?;
+        if self.battery {
+            self.load_prg_ram(&data);
+        } else {
+            self.mapper.borrow_mut().load_save_data(&data);
+        }
+        Ok(())
     }
 
-    fn read_prg_rom(&self, mut addr: u16) -> u8 {
-        addr -= 0x8000;
-        if self.prg_rom.len() == 0x4000 && addr >= 0x4000 {
-            //mirror if needed
-            addr = addr % 0x4000;
+    pub fn flush_save_file(&self, rom_path: &Path) -> io::Result<()> {
+        if self.battery {
+            fs::write(rom_path.with_extension("sav"), &self.prg_ram[..])?;
+        } else if let Some(data) = self.mapper.borrow().save_data() {
+            fs::write(rom_path.with_extension("sav"), data)?;
         }
-        self.prg_rom[addr as usize]
+        Ok(())
+    }
+
+    pub fn ppu(&self) -> &NesPPU {
+        &self.ppu
+    }
+
+    // expansion audio produced since the last call, for the frontend to mix and play
+    pub fn take_audio_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.audio_samples)
+    }
+
+    pub fn assert_irq(&mut self, source: IrqSource) {
+        self.irq_sources.insert(source);
+    }
+
+    pub fn acknowledge_irq(&mut self, source: IrqSource) {
+        self.irq_sources.remove(source);
+    }
+}
+
+impl Clock for Bus<'_> {
+    fn cycles(&self) -> usize {
+        self.cycles
+    }
+
+    fn frame(&self) -> usize {
+        self.frame
     }
 
-    pub fn tick(&mut self, cycles: u8) {
+    fn tick(&mut self, cycles: u8) {
+        let samples_before = self.cycles * AUDIO_SAMPLE_RATE / CPU_CLOCK_HZ;
         self.cycles += cycles as usize;
+        self.mapper.borrow_mut().tick(cycles);
+        let samples_after = self.cycles * AUDIO_SAMPLE_RATE / CPU_CLOCK_HZ;
+        if samples_after > samples_before && self.audio_samples.len() < AUDIO_SAMPLE_RATE {
+            let level = self.mapper.borrow().expansion_audio();
+            self.audio_samples.push(level);
+        }
 
         let nmi_before = self.ppu.nmi_interrupt.is_some();
-        self.ppu.tick(cycles *3);
+        if self.ppu.tick(cycles as u16 * 3) {
+            self.frame += 1;
+        }
         let nmi_after = self.ppu.nmi_interrupt.is_some();
         
         if !nmi_before && nmi_after {
@@ -84,9 +206,16 @@ impl<'a> Bus<'a> {
         }
     }
     
-    pub fn poll_nmi_status(&mut self) -> Option<u8> {
+    fn poll_nmi_status(&mut self) -> Option<u8> {
         self.ppu.poll_nmi_interrupt()
     }
+
+    fn poll_irq_status(&mut self) -> bool {
+        // mappers own their IRQ state, their line is sampled when the CPU looks at it
+        let mapper_irq = self.mapper.borrow().irq_pending();
+        self.irq_sources.set(IrqSource::MAPPER, mapper_irq);
+        !self.irq_sources.is_empty()
+    }
 }
 
 impl Mem for Bus<'_> {
@@ -121,7 +250,9 @@ impl Mem for Bus<'_> {
                 let mirror_down_addr = addr & 0b00100000_00000111;
                 self.mem_read(mirror_down_addr)
             }
-            0x8000..=0xFFFF => self.read_prg_rom(addr),
+            EXPANSION..=EXPANSION_END => self.mapper.borrow_mut().read_expansion(addr),
+            PRG_RAM..=PRG_RAM_END => self.prg_ram[(addr - PRG_RAM) as usize],
+            0x8000..=0xFFFF => self.mapper.borrow().read_prg(addr),
 
             _ => {
                 // println!("Ignoring mem access at {:x}", addr);
@@ -174,18 +305,22 @@ impl Mem for Bus<'_> {
             }
 
             // https://www.nesdev.org/wiki/PPU_programmer_reference#OAMDMA_-_Sprite_DMA_($4014_write)
+            // The CPU is halted for a cycle, plus one more when the write landed on an
+            // odd cycle, then every byte takes a read and a write: 513 or 514 cycles.
             0x4014 => {
-                let mut buffer: [u8; 256] = [0; 256];
+                let align = self.cycles % 2 == 1;
+                self.tick(1);
+                if align {
+                    self.tick(1);
+                }
+
                 let hi: u16 = (data as u16) << 8;
                 for i in 0..256u16 {
-                    buffer[i as usize] = self.mem_read(hi + i);
+                    self.tick(1);
+                    let value = self.mem_read(hi + i);
+                    self.tick(1);
+                    self.ppu.write_to_oam_data(value);
                 }
-
-                self.ppu.write_oam_dma(&buffer);
-
-                // todo: handle this eventually
-                // let add_cycles: u16 = if self.cycles % 2 == 1 { 514 } else { 513 };
-                // self.tick(add_cycles); //todo this will cause weird effects as PPU will have 513/514 * 3 ticks
             }
 
             0x2008..=PPU_REGISTERS_MIRRORS_END => {
@@ -193,13 +328,32 @@ impl Mem for Bus<'_> {
                 self.mem_write(mirror_down_addr, data);
                 // todo!("PPU is not supported yet");
             }
-            0x8000..=0xFFFF => panic!("Attempt to write to Cartridge ROM space: {:x}", addr),
+            EXPANSION..=EXPANSION_END => self.mapper.borrow_mut().write_expansion(addr, data),
+            PRG_RAM..=PRG_RAM_END => {
+                self.prg_ram[(addr - PRG_RAM) as usize] = data;
+            }
+            0x8000..=0xFFFF => self.mapper.borrow_mut().write_prg(addr, data),
 
             _ => {
                 println!("Ignoring mem write-access at {:x}", addr);
             }
         }
     }
+
+    fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            RAM..=RAM_MIRRORS_END => self.cpu_vram[(addr & 0b00000111_11111111) as usize],
+            0x2002 => self.ppu.peek_status(),
+            0x2004 => self.ppu.read_oam_data(),
+            0x2007 => self.ppu.peek_data(),
+            0x2008..=PPU_REGISTERS_MIRRORS_END => self.peek(addr & 0b00100000_00000111),
+            0x4016 => self.joypad1.peek(),
+            EXPANSION..=EXPANSION_END => self.mapper.borrow().peek_expansion(addr),
+            PRG_RAM..=PRG_RAM_END => self.prg_ram[(addr - PRG_RAM) as usize],
+            0x8000..=0xFFFF => self.mapper.borrow().read_prg(addr),
+            _ => 0,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -213,4 +367,129 @@ mod test {
         bus.mem_write(0x01, 0x55);
         assert_eq!(bus.mem_read(0x01), 0x55);
     }
+
+    #[test]
+    fn test_trainer_is_mapped_at_0x7000() {
+        let mut rom = test::test_rom();
+        rom.trainer = Some(vec![0x77; 512]);
+        let mut bus = Bus::new(rom, |_ppu, _joypad| {});
+
+        assert_eq!(bus.mem_read(0x6FFF), 0);
+        assert_eq!(bus.mem_read(0x7000), 0x77);
+        assert_eq!(bus.mem_read(0x71FF), 0x77);
+        assert_eq!(bus.mem_read(0x7200), 0);
+    }
+
+    #[test]
+    fn test_save_file_keeps_the_trainer() {
+        let mut rom = test::test_rom();
+        rom.trainer = Some(vec![0x77; 512]);
+        let mut bus = Bus::new(rom, |_ppu, _joypad| {});
+        bus.load_prg_ram(&[0x55; PRG_RAM_SIZE]);
+
+        assert_eq!(bus.mem_read(0x6FFF), 0x55);
+        assert_eq!(bus.mem_read(0x7000), 0x77);
+        assert_eq!(bus.mem_read(0x71FF), 0x77);
+        assert_eq!(bus.mem_read(0x7200), 0x55);
+    }
+
+    #[test]
+    fn test_oam_dma_takes_513_or_514_cycles() {
+        let mut bus = Bus::new(test::test_rom(), |_ppu, _joypad| {});
+        for i in 0..256u16 {
+            bus.mem_write(0x0200 + i, i as u8);
+        }
+
+        bus.mem_write(0x4014, 0x02);
+        assert_eq!(bus.cycles(), 513);
+        assert_eq!(bus.ppu.oam_data[0], 0);
+        assert_eq!(bus.ppu.oam_data[255], 255);
+
+        bus.mem_write(0x4014, 0x02);
+        assert_eq!(bus.cycles(), 513 + 514);
+    }
+
+    #[test]
+    fn test_irq_line_stays_asserted_until_acknowledged() {
+        let mut bus = Bus::new(test::test_rom(), |_ppu, _joypad| {});
+        assert!(!bus.poll_irq_status());
+
+        bus.assert_irq(IrqSource::FRAME_COUNTER);
+        bus.assert_irq(IrqSource::DMC);
+        assert!(bus.poll_irq_status());
+        assert!(bus.poll_irq_status());
+
+        bus.acknowledge_irq(IrqSource::FRAME_COUNTER);
+        assert!(bus.poll_irq_status());
+        bus.acknowledge_irq(IrqSource::DMC);
+        assert!(!bus.poll_irq_status());
+    }
+
+    #[test]
+    fn test_peek_has_no_side_effects() {
+        let mut bus = Bus::new(test::test_rom(), |_ppu, _joypad| {});
+        bus.ppu.status.set_vblank_status(true);
+        bus.mem_write(0x2006, 0x23);
+        bus.mem_write(0x2006, 0x05);
+        bus.mem_write(0x2007, 0x66);
+        bus.mem_write(0x2006, 0x23);
+        bus.mem_write(0x2006, 0x05);
+        bus.mem_read(0x2007);
+
+        assert_eq!(bus.peek(0x2002) & 0b1000_0000, 0b1000_0000);
+        assert_eq!(bus.peek(0x200a) & 0b1000_0000, 0b1000_0000);
+        assert_eq!(bus.peek(0x2007), 0x66);
+        assert_eq!(bus.peek(0x2007), 0x66);
+        assert_eq!(bus.ppu.addr.get(), 0x2306);
+
+        assert_eq!(bus.mem_read(0x2002) & 0b1000_0000, 0b1000_0000);
+        assert_eq!(bus.mem_read(0x2007), 0x66);
+    }
+
+    #[test]
+    fn test_prg_ram_export_import() {
+        let mut bus = Bus::new(test::test_rom(), |_ppu, _joypad| {});
+        bus.mem_write(0x6000, 0x55);
+        bus.mem_write(0x7FFF, 0x66);
+
+        let saved = bus.prg_ram().to_vec();
+        assert_eq!(saved[0], 0x55);
+        assert_eq!(saved[0x1FFF], 0x66);
+
+        let mut other = Bus::new(test::test_rom(), |_ppu, _joypad| {});
+        other.load_prg_ram(&saved);
+        assert_eq!(other.mem_read(0x6000), 0x55);
+        assert_eq!(other.mem_read(0x7FFF), 0x66);
+    }
+
+    #[test]
+    fn test_long_tick_moves_the_ppu_three_dots_per_cycle() {
+        let mut bus = Bus::new(test::test_rom(), |_ppu, _joypad| {});
+        bus.tick(100);
+        bus.tick(100);
+        assert_eq!(bus.ppu.scanline, 1);
+    }
+
+    #[test]
+    fn test_prg_ram_without_battery() {
+        let mut rom = test::test_rom();
+        rom.battery = false;
+        let mut bus = Bus::new(rom, |_ppu, _joypad| {});
+        bus.mem_write(0x6000, 0x55);
+        assert_eq!(bus.mem_read(0x6000), 0x55);
+        assert!(!bus.has_battery());
+    }
+
+    #[test]
+    fn test_audio_is_sampled_at_44100_hz() {
+        let mut bus = Bus::new(test::test_rom(), |_ppu, _joypad| {});
+        // a tenth of a second
+        for _ in 0..CPU_CLOCK_HZ / 10 {
+            bus.tick(1);
+        }
+        let samples = bus.take_audio_samples();
+        assert!((AUDIO_SAMPLE_RATE / 10 - samples.len()) <= 1);
+        assert!(samples.iter().all(|&sample| sample == 0.0));
+        assert!(bus.take_audio_samples().is_empty());
+    }
 }
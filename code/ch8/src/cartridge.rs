@@ -1,34 +1,220 @@
+use crate::mapper;
+use crate::mapper::fds;
+use crate::romdb;
+use crate::romdb::GameInfo;
+use std::fmt;
+use std::io;
+use std::io::Read;
+
 const NES_TAG: [u8; 4] = [0x4E, 0x45, 0x53, 0x1A];
+const UNIF_TAG: &[u8] = b"UNIF";
+const UNIF_HEADER_SIZE: usize = 32;
+const UNIF_CHUNK_HEADER_SIZE: usize = 8;
+const FDS_TAG: &[u8] = b"FDS\x1A";
+const FDS_DISK_MAGIC: &[u8] = b"\x01*NINTENDO-HVC*";
+const FDS_SIDE_SIZE: usize = fds::SIDE_SIZE;
+const FDS_MAPPER: u16 = 20;
+const HEADER_SIZE: usize = 16;
+const TRAINER_SIZE: usize = 512;
 const PRG_ROM_PAGE_SIZE: usize = 16384;
 const CHR_ROM_PAGE_SIZE: usize = 8192;
+// Some old dumping tools wrote their signature over bytes 7-15 of the header
+const DISKDUDE: &[u8] = b"DiskDude!";
+
+#[derive(Debug)]
+pub enum RomError {
+    BadMagic,
+    TruncatedHeader,
+    TruncatedTrainer { expected: usize, actual: usize },
+    TruncatedPrg { expected: usize, actual: usize },
+    TruncatedChr { expected: usize, actual: usize },
+    PrgTooSmall { mapper: u16, minimum: usize, actual: usize },
+    UnsupportedMapper(u16),
+    UnsupportedBoard(String),
+    UnsupportedFormat,
+    TruncatedChunk(String),
+    MissingChunk(&'static str),
+    MissingBios,
+    InvalidBios { expected: usize, actual: usize },
+    TruncatedDisk { expected: usize, actual: usize },
+    Io(io::Error),
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RomError::BadMagic => write!(f, "File is not in iNES or UNIF file format"),
+            RomError::TruncatedHeader => write!(f, "File is too short to contain an iNES header"),
+            RomError::TruncatedTrainer { expected, actual } => write!(
+                f,
+                "Trainer is truncated: expected {} bytes, got {}",
+                expected, actual
+            ),
+            RomError::TruncatedPrg { expected, actual } => write!(
+                f,
+                "PRG-ROM is truncated: expected {} bytes, got {}",
+                expected, actual
+            ),
+            RomError::TruncatedChr { expected, actual } => write!(
+                f,
+                "CHR-ROM is truncated: expected {} bytes, got {}",
+                expected, actual
+            ),
+            RomError::PrgTooSmall {
+                mapper,
+                minimum,
+                actual,
+            } => write!(
+                f,
+                "PRG-ROM is too small for mapper {}: expected at least {} bytes, got {}",
+                mapper, minimum, actual
+            ),
+            RomError::UnsupportedMapper(mapper) => write!(f, "Mapper {} is not supported", mapper),
+            RomError::UnsupportedBoard(board) => write!(f, "Board {} is not supported", board),
+            RomError::UnsupportedFormat => write!(f, "Unsupported iNES header version"),
+            RomError::TruncatedChunk(id) => write!(f, "UNIF chunk {} is truncated", id),
+            RomError::MissingChunk(id) => write!(f, "UNIF file has no {} chunk", id),
+            RomError::MissingBios => write!(f, "FDS disk images need the disksys.rom BIOS"),
+            RomError::InvalidBios { expected, actual } => {
+                write!(f, "FDS BIOS should be {} bytes, got {}", expected, actual)
+            }
+            RomError::TruncatedDisk { expected, actual } => write!(
+                f,
+                "FDS disk is truncated: expected {} bytes, got {}",
+                expected, actual
+            ),
+            RomError::Io(err) => write!(f, "Failed to read rom: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for RomError {}
+
+impl From<io::Error> for RomError {
+    fn from(err: io::Error) -> Self {
+        RomError::Io(err)
+    }
+}
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Mirroring {
     Vertical,
     Horizontal,
     FourScreen,
+    SingleScreenLower,
+    SingleScreenUpper,
 }
 
-pub struct Rom {
-    pub prg_rom: Vec<u8>,
-    pub chr_rom: Vec<u8>,
-    pub mapper: u8,
-    pub screen_mirroring: Mirroring,
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum HeaderFormat {
+    INes,
+    Nes2,
+    Unif,
+    Fds,
 }
 
-impl Rom {
-    pub fn new(raw: &Vec<u8>) -> Result<Rom, String> {
-        if &raw[0..4] != NES_TAG {
-            return Err("File is not in iNES file format".to_string());
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Timing {
+    Ntsc,
+    Pal,
+    MultiRegion,
+    Dendy,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ConsoleType {
+    Nes,
+    VsSystem { ppu_type: u8, hardware_type: u8 },
+    Playchoice10,
+    Extended(u8),
+}
+
+// A header field that didn't match the rom database and was overridden
+#[derive(Debug, PartialEq, Clone)]
+pub enum Correction {
+    DiskDudeSignature,
+    Mapper {
+        header: u16,
+        database: u16,
+    },
+    Mirroring {
+        header: Mirroring,
+        database: Mirroring,
+    },
+    Timing {
+        header: Timing,
+        database: Timing,
+    },
+    Battery {
+        header: bool,
+        database: bool,
+    },
+}
+
+impl fmt::Display for Correction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Correction::DiskDudeSignature => write!(f, "ignored \"DiskDude!\" garbage in header"),
+            Correction::Mapper { header, database } => {
+                write!(f, "mapper corrected from {} to {}", header, database)
+            }
+            Correction::Mirroring { header, database } => {
+                write!(f, "mirroring corrected from {:?} to {:?}", header, database)
+            }
+            Correction::Timing { header, database } => {
+                write!(f, "region corrected from {:?} to {:?}", header, database)
+            }
+            Correction::Battery { header, database } => {
+                write!(f, "battery corrected from {} to {}", header, database)
+            }
         }
+    }
+}
 
-        let mapper = (raw[7] & 0b1111_0000) | (raw[6] >> 4);
+// https://www.nesdev.org/wiki/NES_2.0
+//
+// Sizes are in bytes. For iNES 1.0 headers the fields NES 2.0 added are
+// filled with the conventional defaults.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Header {
+    pub format: HeaderFormat,
+    pub mapper: u16,
+    pub submapper: u8,
+    pub prg_rom_size: usize,
+    pub chr_rom_size: usize,
+    pub prg_ram_size: usize,
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+    pub chr_nvram_size: usize,
+    pub screen_mirroring: Mirroring,
+    pub battery: bool,
+    pub trainer: bool,
+    pub timing: Timing,
+    pub console_type: ConsoleType,
+    pub misc_roms: u8,
+    pub expansion_device: u8,
+}
 
-        let ines_ver = (raw[7] >> 2) & 0b11;
-        if ines_ver != 0 {
-            return Err("NES2.0 format is not supported".to_string());
+impl Header {
+    pub fn parse(raw: &[u8]) -> Result<Header, RomError> {
+        if raw.len() < NES_TAG.len() || raw[0..4] != NES_TAG {
+            return Err(RomError::BadMagic);
+        }
+        if raw.len() < HEADER_SIZE {
+            return Err(RomError::TruncatedHeader);
+        }
+        if has_diskdude_signature(raw) {
+            let mut clean = raw[..HEADER_SIZE].to_vec();
+            clean[7..].iter_mut().for_each(|b| *b = 0);
+            return Header::parse(&clean);
         }
 
+        let format = match (raw[7] >> 2) & 0b11 {
+            0 => HeaderFormat::INes,
+            2 => HeaderFormat::Nes2,
+            _ => return Err(RomError::UnsupportedFormat),
+        };
+
         let four_screen = raw[6] & 0b1000 != 0;
         let vertical_mirroring = raw[6] & 0b1 != 0;
         let screen_mirroring = match (four_screen, vertical_mirroring) {
@@ -37,23 +223,468 @@ impl Rom {
             (false, false) => Mirroring::Horizontal,
         };
 
-        let prg_rom_size = raw[4] as usize * PRG_ROM_PAGE_SIZE;
-        let chr_rom_size = raw[5] as usize * CHR_ROM_PAGE_SIZE;
+        let battery = raw[6] & 0b10 != 0;
+        let trainer = raw[6] & 0b100 != 0;
+        let mapper = ((raw[7] & 0b1111_0000) | (raw[6] >> 4)) as u16;
+
+        match format {
+            HeaderFormat::Nes2 => Ok(Header {
+                format,
+                mapper: mapper | ((raw[8] as u16 & 0b1111) << 8),
+                submapper: raw[8] >> 4,
+                prg_rom_size: rom_size(raw[4], raw[9] & 0b1111, PRG_ROM_PAGE_SIZE),
+                chr_rom_size: rom_size(raw[5], raw[9] >> 4, CHR_ROM_PAGE_SIZE),
+                prg_ram_size: ram_size(raw[10] & 0b1111),
+                prg_nvram_size: ram_size(raw[10] >> 4),
+                chr_ram_size: ram_size(raw[11] & 0b1111),
+                chr_nvram_size: ram_size(raw[11] >> 4),
+                screen_mirroring,
+                battery,
+                trainer,
+                timing: match raw[12] & 0b11 {
+                    0 => Timing::Ntsc,
+                    1 => Timing::Pal,
+                    2 => Timing::MultiRegion,
+                    _ => Timing::Dendy,
+                },
+                console_type: console_type(raw),
+                misc_roms: raw[14] & 0b11,
+                expansion_device: raw[15] & 0b11_1111,
+            }),
+            _ => Ok(Header {
+                format,
+                mapper,
+                submapper: 0,
+                prg_rom_size: raw[4] as usize * PRG_ROM_PAGE_SIZE,
+                chr_rom_size: raw[5] as usize * CHR_ROM_PAGE_SIZE,
+                // 0 infers 8KB for compatibility
+                prg_ram_size: (raw[8].max(1) as usize) * 0x2000,
+                prg_nvram_size: 0,
+                chr_ram_size: if raw[5] == 0 { CHR_ROM_PAGE_SIZE } else { 0 },
+                chr_nvram_size: 0,
+                screen_mirroring,
+                battery,
+                trainer,
+                timing: if raw[9] & 1 == 0 {
+                    Timing::Ntsc
+                } else {
+                    Timing::Pal
+                },
+                console_type: console_type(raw),
+                misc_roms: 0,
+                expansion_device: 0,
+            }),
+        }
+    }
+
+    fn prg_rom_start(&self) -> usize {
+        HEADER_SIZE + if self.trainer { TRAINER_SIZE } else { 0 }
+    }
+
+    // the PRG-ROM of the image the header was read from, whether or not the mapper is supported
+    pub fn prg_rom<'a>(&self, raw: &'a [u8]) -> Result<&'a [u8], RomError> {
+        let prg_rom_start = self.prg_rom_start();
+        if raw.len() < prg_rom_start {
+            return Err(RomError::TruncatedTrainer {
+                expected: prg_rom_start - HEADER_SIZE,
+                actual: raw.len() - HEADER_SIZE,
+            });
+        }
+        section(raw, prg_rom_start, self.prg_rom_size).ok_or(RomError::TruncatedPrg {
+            expected: self.prg_rom_size,
+            actual: raw.len() - prg_rom_start,
+        })
+    }
+
+    // overrides the fields the database knows better, returns what was changed
+    pub fn correct(&mut self, game: &GameInfo) -> Vec<Correction> {
+        let mut corrections = vec![];
+        if self.mapper != game.mapper {
+            corrections.push(Correction::Mapper {
+                header: self.mapper,
+                database: game.mapper,
+            });
+            self.mapper = game.mapper;
+            self.submapper = 0;
+        }
+        // the header bit is left alone on boards that switch mirroring themselves
+        if let Some(mirroring) = &game.screen_mirroring {
+            if self.screen_mirroring != *mirroring {
+                corrections.push(Correction::Mirroring {
+                    header: self.screen_mirroring.clone(),
+                    database: mirroring.clone(),
+                });
+                self.screen_mirroring = mirroring.clone();
+            }
+        }
+        if self.timing != game.timing {
+            corrections.push(Correction::Timing {
+                header: self.timing,
+                database: game.timing,
+            });
+            self.timing = game.timing;
+        }
+        if self.battery != game.battery {
+            corrections.push(Correction::Battery {
+                header: self.battery,
+                database: game.battery,
+            });
+            self.battery = game.battery;
+        }
+        corrections
+    }
+}
+
+pub fn is_fds(raw: &[u8]) -> bool {
+    raw.starts_with(FDS_TAG) || raw.starts_with(FDS_DISK_MAGIC)
+}
+
+fn has_diskdude_signature(raw: &[u8]) -> bool {
+    raw.get(7..7 + DISKDUDE.len()) == Some(DISKDUDE)
+}
 
-        let skip_trainer = raw[6] & 0b100 != 0;
+// ROM sizes are either a plain count of pages (with 4 extra high bits in byte 9)
+// or, when those high bits are all set, in exponent-multiplier notation:
+//   EEEEEEMM -> 2^E * (MM * 2 + 1) bytes
+// Sizes that don't fit in usize saturate, no file can be that long anyway.
+fn rom_size(lsb: u8, msb: u8, page_size: usize) -> usize {
+    if msb == 0b1111 {
+        let exponent = (lsb >> 2) as u32;
+        let multiplier = (lsb & 0b11) as usize * 2 + 1;
+        2usize
+            .checked_pow(exponent)
+            .and_then(|size| size.checked_mul(multiplier))
+            .unwrap_or(usize::MAX)
+    } else {
+        (((msb as usize) << 8) | lsb as usize) * page_size
+    }
+}
+
+// RAM sizes are shift counts: 64 << n bytes, 0 means none
+fn ram_size(shift: u8) -> usize {
+    if shift == 0 {
+        0
+    } else {
+        64 << shift
+    }
+}
+
+fn console_type(raw: &[u8]) -> ConsoleType {
+    match raw[7] & 0b11 {
+        0 => ConsoleType::Nes,
+        1 => ConsoleType::VsSystem {
+            ppu_type: raw[13] & 0b1111,
+            hardware_type: raw[13] >> 4,
+        },
+        2 => ConsoleType::Playchoice10,
+        _ => ConsoleType::Extended(raw[13] & 0b1111),
+    }
+}
 
-        let prg_rom_start = 16 + if skip_trainer { 512 } else { 0 };
-        let chr_rom_start = prg_rom_start + prg_rom_size;
+pub struct Rom {
+    pub prg_rom: Vec<u8>,
+    pub chr_rom: Vec<u8>,
+    pub mapper: u16,
+    pub screen_mirroring: Mirroring,
+    pub battery: bool,
+    pub trainer: Option<Vec<u8>>,
+    pub header: Header,
+    pub corrections: Vec<Correction>,
+    // FDS disk sides in .fds layout, empty for cartridges
+    pub disk_sides: Vec<Vec<u8>>,
+}
+
+// manufacturer prefixes of UNIF board names, "NES-NROM-256" is an NROM-256
+const UNIF_BOARD_PREFIXES: [&str; 8] = [
+    "NES-", "HVC-", "UNL-", "BTL-", "BMC-", "IREM-", "KONAMI-", "TENGEN-",
+];
+
+// UNIF identifies the board by name instead of a mapper number.
+fn unif_board_mapper(board: &str) -> Option<u16> {
+    let name = UNIF_BOARD_PREFIXES
+        .iter()
+        .find_map(|prefix| board.strip_prefix(prefix))
+        .unwrap_or(board);
+    let mapper = match name {
+        "NROM" | "NROM-128" | "NROM-256" | "RROM" | "RROM-128" => 0,
+        "SAROM" | "SBROM" | "SCROM" | "SEROM" | "SFROM" | "SGROM" | "SHROM" | "SJROM" | "SKROM"
+        | "SLROM" | "SL1ROM" | "SNROM" | "SOROM" | "SUROM" | "SXROM" => 1,
+        "UNROM" | "UOROM" => 2,
+        "CNROM" => 3,
+        "TBROM" | "TEROM" | "TFROM" | "TGROM" | "TKROM" | "TLROM" | "TL1ROM" | "TR1ROM"
+        | "TSROM" | "TVROM" => 4,
+        "AMROM" | "ANROM" | "AN1ROM" | "AOROM" => 7,
+        "COLORDREAMS" | "COLORDREAMS-74*377" => 11,
+        "GNROM" | "MHROM" => 66,
+        _ => return None,
+    };
+    Some(mapper)
+}
+
+fn section(raw: &[u8], start: usize, size: usize) -> Option<&[u8]> {
+    raw.get(start..start.checked_add(size)?)
+}
+
+impl Rom {
+    pub fn new(raw: &[u8]) -> Result<Rom, RomError> {
+        if raw.starts_with(UNIF_TAG) {
+            return Rom::from_unif(raw);
+        }
+        if is_fds(raw) {
+            return Err(RomError::MissingBios);
+        }
+
+        let header = Header::parse(raw)?;
+
+        let prg_rom = header.prg_rom(raw)?;
+        let prg_rom_start = header.prg_rom_start();
+        let chr_rom_start = prg_rom_start.saturating_add(header.prg_rom_size);
+        let chr_rom =
+            section(raw, chr_rom_start, header.chr_rom_size).ok_or(RomError::TruncatedChr {
+                expected: header.chr_rom_size,
+                actual: raw.len() - chr_rom_start,
+            })?;
+
+        let mut corrections = vec![];
+        if has_diskdude_signature(raw) {
+            corrections.push(Correction::DiskDudeSignature);
+        }
+
+        let trainer = if header.trainer {
+            Some(raw[HEADER_SIZE..prg_rom_start].to_vec())
+        } else {
+            None
+        };
+
+        Rom::build(
+            header,
+            prg_rom.to_vec(),
+            chr_rom.to_vec(),
+            trainer,
+            corrections,
+        )
+    }
+
+    // https://www.nesdev.org/wiki/UNIF
+    //
+    // 32 byte header ("UNIF", revision, padding) followed by chunks:
+    // 4 byte id, 4 byte little-endian length, data.
+    //
+    //  MAPR       board name, null-terminated
+    //  PRG0-PRGF  PRG-ROM pieces, concatenated in order
+    //  CHR0-CHRF  CHR-ROM pieces, concatenated in order
+    //  MIRR       0: horizontal, 1: vertical, 2/3: single screen, 4: four screen, 5: mapper controlled
+    //  BATR       present if the board has battery-backed RAM
+    //  TVCI       0: NTSC, 1: PAL, 2: both
+    pub fn from_unif(raw: &[u8]) -> Result<Rom, RomError> {
+        if !raw.starts_with(UNIF_TAG) {
+            return Err(RomError::BadMagic);
+        }
+        if raw.len() < UNIF_HEADER_SIZE {
+            return Err(RomError::TruncatedHeader);
+        }
+
+        let mut board = None;
+        let mut prg_chunks: [Option<&[u8]>; 16] = [None; 16];
+        let mut chr_chunks: [Option<&[u8]>; 16] = [None; 16];
+        let mut screen_mirroring = Mirroring::Horizontal;
+        let mut battery = false;
+        let mut timing = Timing::Ntsc;
+
+        let mut pos = UNIF_HEADER_SIZE;
+        while pos < raw.len() {
+            let chunk_header =
+                section(raw, pos, UNIF_CHUNK_HEADER_SIZE).ok_or(RomError::TruncatedHeader)?;
+            let id = &chunk_header[0..4];
+            let len = u32::from_le_bytes([
+                chunk_header[4],
+                chunk_header[5],
+                chunk_header[6],
+                chunk_header[7],
+            ]) as usize;
+            let data = section(raw, pos + UNIF_CHUNK_HEADER_SIZE, len)
+                .ok_or_else(|| RomError::TruncatedChunk(String::from_utf8_lossy(id).into()))?;
+            pos += UNIF_CHUNK_HEADER_SIZE + len;
+
+            match (&id[0..3], data.first()) {
+                (b"MAP", _) if id[3] == b'R' => {
+                    let name = data.split(|&b| b == 0).next().unwrap_or(&[]);
+                    board = Some(String::from_utf8_lossy(name).into_owned());
+                }
+                (b"PRG", _) => {
+                    if let Some(idx) = (id[3] as char).to_digit(16) {
+                        prg_chunks[idx as usize] = Some(data);
+                    }
+                }
+                (b"CHR", _) => {
+                    if let Some(idx) = (id[3] as char).to_digit(16) {
+                        chr_chunks[idx as usize] = Some(data);
+                    }
+                }
+                (b"MIR", Some(&mirr)) if id[3] == b'R' => {
+                    screen_mirroring = match mirr {
+                        1 => Mirroring::Vertical,
+                        2 => Mirroring::SingleScreenLower,
+                        3 => Mirroring::SingleScreenUpper,
+                        4 => Mirroring::FourScreen,
+                        _ => Mirroring::Horizontal,
+                    };
+                }
+                (b"BAT", _) if id[3] == b'R' => battery = true,
+                (b"TVC", Some(&tvci)) if id[3] == b'I' => {
+                    timing = match tvci {
+                        0 => Timing::Ntsc,
+                        1 => Timing::Pal,
+                        _ => Timing::MultiRegion,
+                    };
+                }
+                // NAME, READ, DINF, CTRL, CCK*, PCK*, VROR...
+                _ => {}
+            }
+        }
+
+        let board = board.ok_or(RomError::MissingChunk("MAPR"))?;
+        let mapper = unif_board_mapper(&board).ok_or(RomError::UnsupportedBoard(board))?;
+        if prg_chunks[0].is_none() {
+            return Err(RomError::MissingChunk("PRG0"));
+        }
+        let prg_rom: Vec<u8> = prg_chunks
+            .iter()
+            .flatten()
+            .flat_map(|c| c.iter())
+            .copied()
+            .collect();
+        let chr_rom: Vec<u8> = chr_chunks
+            .iter()
+            .flatten()
+            .flat_map(|c| c.iter())
+            .copied()
+            .collect();
+
+        let header = Header {
+            format: HeaderFormat::Unif,
+            mapper,
+            submapper: 0,
+            prg_rom_size: prg_rom.len(),
+            chr_rom_size: chr_rom.len(),
+            prg_ram_size: 0x2000,
+            prg_nvram_size: 0,
+            chr_ram_size: if chr_rom.is_empty() {
+                CHR_ROM_PAGE_SIZE
+            } else {
+                0
+            },
+            chr_nvram_size: 0,
+            screen_mirroring,
+            battery,
+            trainer: false,
+            timing,
+            console_type: ConsoleType::Nes,
+            misc_roms: 0,
+            expansion_device: 0,
+        };
+
+        Rom::build(header, prg_rom, chr_rom, None, vec![])
+    }
+
+    fn build(
+        mut header: Header,
+        prg_rom: Vec<u8>,
+        chr_rom: Vec<u8>,
+        trainer: Option<Vec<u8>>,
+        mut corrections: Vec<Correction>,
+    ) -> Result<Rom, RomError> {
+        if let Some(game) = romdb::lookup(&prg_rom, &chr_rom) {
+            corrections.extend(header.correct(&game));
+        }
+
+        if !mapper::is_supported(header.mapper) {
+            return Err(RomError::UnsupportedMapper(header.mapper));
+        }
+        let minimum = mapper::min_prg_rom_size(header.mapper);
+        if prg_rom.len() < minimum {
+            return Err(RomError::PrgTooSmall {
+                mapper: header.mapper,
+                minimum,
+                actual: prg_rom.len(),
+            });
+        }
 
         Ok(Rom {
-            prg_rom: raw[prg_rom_start..(prg_rom_start + prg_rom_size)].to_vec(),
-            chr_rom: raw[chr_rom_start..(chr_rom_start + chr_rom_size)].to_vec(),
-            mapper: mapper,
-            screen_mirroring: screen_mirroring,
+            prg_rom,
+            chr_rom,
+            mapper: header.mapper,
+            screen_mirroring: header.screen_mirroring.clone(),
+            battery: header.battery,
+            trainer,
+            header,
+            corrections,
+            disk_sides: vec![],
         })
     }
+
+    // https://www.nesdev.org/wiki/FDS_file_format
+    //
+    // Optional 16 byte header ("FDS\x1A", number of sides), followed by 65500 byte disk sides.
+    // The disk system has no ROM of its own besides the 8KB BIOS, which the user has to supply.
+    pub fn from_fds(raw: &[u8], bios: &[u8]) -> Result<Rom, RomError> {
+        if bios.len() != fds::BIOS_SIZE {
+            return Err(RomError::InvalidBios {
+                expected: fds::BIOS_SIZE,
+                actual: bios.len(),
+            });
+        }
+
+        let disk = if raw.starts_with(FDS_TAG) {
+            raw.get(HEADER_SIZE..).ok_or(RomError::TruncatedHeader)?
+        } else {
+            raw
+        };
+        if !disk.starts_with(FDS_DISK_MAGIC) {
+            return Err(RomError::BadMagic);
+        }
+        if disk.len() % FDS_SIDE_SIZE != 0 {
+            return Err(RomError::TruncatedDisk {
+                expected: (disk.len() / FDS_SIDE_SIZE + 1) * FDS_SIDE_SIZE,
+                actual: disk.len(),
+            });
+        }
+
+        let header = Header {
+            format: HeaderFormat::Fds,
+            mapper: FDS_MAPPER,
+            submapper: 0,
+            prg_rom_size: bios.len(),
+            chr_rom_size: 0,
+            prg_ram_size: 0x8000,
+            prg_nvram_size: 0,
+            chr_ram_size: CHR_ROM_PAGE_SIZE,
+            chr_nvram_size: 0,
+            screen_mirroring: Mirroring::Horizontal,
+            battery: false,
+            trainer: false,
+            timing: Timing::Ntsc,
+            console_type: ConsoleType::Nes,
+            misc_roms: 0,
+            expansion_device: 0,
+        };
+
+        let mut rom = Rom::build(header, bios.to_vec(), vec![], None, vec![])?;
+        rom.disk_sides = disk
+            .chunks(FDS_SIDE_SIZE)
+            .map(|side| side.to_vec())
+            .collect();
+        Ok(rom)
+    }
+
+    pub fn from_reader<R: Read>(mut reader: R) -> Result<Rom, RomError> {
+        let mut raw = vec![];
+        reader.read_to_end(&mut raw)?;
+        Rom::new(&raw)
+    }
 }
 
+#[cfg(test)]
 pub mod test {
 
     use super::*;
@@ -120,6 +751,7 @@ pub mod test {
         assert_eq!(rom.prg_rom, vec!(1; 2 * PRG_ROM_PAGE_SIZE));
         assert_eq!(rom.mapper, 3);
         assert_eq!(rom.screen_mirroring, Mirroring::Vertical);
+        assert!(!rom.battery);
     }
 
     #[test]
@@ -143,7 +775,7 @@ pub mod test {
                 00,
                 00,
             ],
-            trainer: Some(vec![0; 512]),
+            trainer: Some(vec![3; 512]),
             pgp_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
             chr_rom: vec![2; 1 * CHR_ROM_PAGE_SIZE],
         });
@@ -154,22 +786,455 @@ pub mod test {
         assert_eq!(rom.prg_rom, vec!(1; 2 * PRG_ROM_PAGE_SIZE));
         assert_eq!(rom.mapper, 3);
         assert_eq!(rom.screen_mirroring, Mirroring::Vertical);
+        assert_eq!(rom.trainer, Some(vec!(3; 512)));
     }
 
     #[test]
-    fn test_nes2_is_not_supported() {
+    fn test_nes2_header() {
         let test_rom = create_rom(TestRom {
             header: vec![
-                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x31, 0x8, 00, 00, 00, 00, 00, 00, 00, 00,
+                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x43, 0x09, 0x50, 00, 0x70, 0x07, 0x03, 0x02,
+                0x01, 0x2A,
             ],
             trainer: None,
-            pgp_rom: vec![1; 1 * PRG_ROM_PAGE_SIZE],
-            chr_rom: vec![2; 1 * CHR_ROM_PAGE_SIZE],
+            pgp_rom: vec![1; PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
         });
-        let rom = Rom::new(&test_rom);
-        match rom {
-            Result::Ok(_) => assert!(false, "should not load rom"),
-            Result::Err(str) => assert_eq!(str, "NES2.0 format is not supported"),
+
+        let rom = Rom::new(&test_rom).unwrap();
+
+        assert_eq!(rom.mapper, 4);
+        assert_eq!(rom.prg_rom, vec!(1; PRG_ROM_PAGE_SIZE));
+        assert_eq!(rom.chr_rom, vec!(2; CHR_ROM_PAGE_SIZE));
+        assert!(rom.battery);
+
+        let header = rom.header;
+        assert_eq!(header.format, HeaderFormat::Nes2);
+        assert_eq!(header.submapper, 5);
+        assert_eq!(header.prg_ram_size, 0);
+        assert_eq!(header.prg_nvram_size, 8192);
+        assert_eq!(header.chr_ram_size, 8192);
+        assert_eq!(header.chr_nvram_size, 0);
+        assert_eq!(header.timing, Timing::Dendy);
+        assert_eq!(
+            header.console_type,
+            ConsoleType::VsSystem {
+                ppu_type: 2,
+                hardware_type: 0
+            }
+        );
+        assert_eq!(header.misc_roms, 1);
+        assert_eq!(header.expansion_device, 0x2A);
+    }
+
+    #[test]
+    fn test_nes2_exponent_multiplier_rom_size() {
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E,
+                0x45,
+                0x53,
+                0x1A,
+                0b0011_1001, // 2^14 * 3
+                0x00,
+                0x00,
+                0x08,
+                00,
+                0x0F,
+                00,
+                00,
+                00,
+                00,
+                00,
+                00,
+            ],
+            trainer: None,
+            pgp_rom: vec![1; 3 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![],
+        });
+
+        let rom = Rom::new(&test_rom).unwrap();
+
+        assert_eq!(rom.header.prg_rom_size, 3 * PRG_ROM_PAGE_SIZE);
+        assert_eq!(rom.prg_rom.len(), 3 * PRG_ROM_PAGE_SIZE);
+        assert_eq!(rom.chr_rom.len(), 0);
+    }
+
+    #[test]
+    fn test_nes2_12bit_mapper_is_not_supported() {
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x30, 0x18, 0x02, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            pgp_rom: vec![1; PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+        });
+
+        assert_eq!(Header::parse(&test_rom).unwrap().mapper, 0x213);
+        assert!(matches!(
+            Rom::new(&test_rom),
+            Err(RomError::UnsupportedMapper(0x213))
+        ));
+    }
+
+    #[test]
+    fn test_truncated_files_fail_cleanly() {
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E,
+                0x45,
+                0x53,
+                0x1A,
+                0x02,
+                0x01,
+                0x31 | 0b100,
+                00,
+                00,
+                00,
+                00,
+                00,
+                00,
+                00,
+                00,
+                00,
+            ],
+            trainer: Some(vec![0; 512]),
+            pgp_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+        });
+
+        assert!(matches!(Rom::new(&test_rom[..3]), Err(RomError::BadMagic)));
+        assert!(matches!(
+            Rom::new(&test_rom[..10]),
+            Err(RomError::TruncatedHeader)
+        ));
+        assert!(matches!(
+            Rom::new(&test_rom[..100]),
+            Err(RomError::TruncatedTrainer { .. })
+        ));
+        assert!(matches!(
+            Rom::new(&test_rom[..1000]),
+            Err(RomError::TruncatedPrg { .. })
+        ));
+        assert!(matches!(
+            Rom::new(&test_rom[..test_rom.len() - 1]),
+            Err(RomError::TruncatedChr {
+                expected: CHR_ROM_PAGE_SIZE,
+                actual: 8191
+            })
+        ));
+
+        for len in 0..test_rom.len() {
+            assert!(Rom::new(&test_rom[..len]).is_err());
+        }
+        assert!(Rom::from_reader(&test_rom[..]).is_ok());
+    }
+
+    // NES 2.0 header with the PRG-ROM size given as 2^exponent, 0 for an empty PRG-ROM
+    fn nes2_rom(mapper: u8, prg_exponent: u8) -> Vec<u8> {
+        let prg_size = if prg_exponent == 0 {
+            0
+        } else {
+            1 << prg_exponent
+        };
+        create_rom(TestRom {
+            header: vec![
+                0x4E,
+                0x45,
+                0x53,
+                0x1A,
+                prg_exponent << 2,
+                0x01,
+                mapper << 4,
+                (mapper & 0xF0) | 0x08,
+                00,
+                if prg_exponent == 0 { 0x00 } else { 0x0F },
+                00,
+                00,
+                00,
+                00,
+                00,
+                00,
+            ],
+            trainer: None,
+            pgp_rom: vec![0xEA; prg_size],
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+        })
+    }
+
+    #[test]
+    fn test_prg_smaller_than_a_bank_is_rejected() {
+        // mapper, smallest PRG-ROM as a power of two
+        let boards = [
+            (0, 14),
+            (1, 14),
+            (2, 14),
+            (3, 14),
+            (4, 13),
+            (7, 14),
+            (11, 15),
+            (66, 15),
+        ];
+        for (mapper_id, exponent) in boards.iter() {
+            let mapper_id = *mapper_id;
+            for small in [0, 12, exponent - 1].iter() {
+                assert!(
+                    matches!(
+                        Rom::new(&nes2_rom(mapper_id, *small)),
+                        Err(RomError::PrgTooSmall { mapper, .. }) if mapper == mapper_id as u16
+                    ),
+                    "mapper {} with 2^{} bytes of PRG-ROM",
+                    mapper_id,
+                    small
+                );
+            }
+
+            let rom = Rom::new(&nes2_rom(mapper_id, *exponent)).unwrap();
+            let mapper = mapper::from_rom(rom);
+            assert_eq!(mapper.borrow().read_prg(0x8000), 0xEA);
+            assert_eq!(mapper.borrow().read_prg(0xFFFC), 0xEA);
+        }
+    }
+
+    #[test]
+    fn test_diskdude_garbage_is_ignored() {
+        let mut header = vec![0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x31];
+        header.extend(b"DiskDude!");
+        let test_rom = create_rom(TestRom {
+            header,
+            trainer: None,
+            pgp_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+        });
+
+        let rom = Rom::new(&test_rom).unwrap();
+
+        assert_eq!(rom.mapper, 3);
+        assert_eq!(rom.header.format, HeaderFormat::INes);
+        assert_eq!(rom.corrections, vec!(Correction::DiskDudeSignature));
+    }
+
+    #[test]
+    fn test_header_correction_from_database() {
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x32, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            pgp_rom: vec![1; PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+        });
+        let mut header = Header::parse(&test_rom).unwrap();
+        let game = GameInfo {
+            name: "test".to_string(),
+            sha1: [0; 20],
+            mapper: 0,
+            screen_mirroring: Some(Mirroring::Vertical),
+            timing: Timing::Ntsc,
+            battery: false,
+        };
+
+        let corrections = header.correct(&game);
+
+        assert_eq!(
+            corrections,
+            vec!(
+                Correction::Mapper {
+                    header: 3,
+                    database: 0
+                },
+                Correction::Mirroring {
+                    header: Mirroring::Horizontal,
+                    database: Mirroring::Vertical
+                },
+                Correction::Battery {
+                    header: true,
+                    database: false
+                },
+            )
+        );
+        assert_eq!(header.mapper, 0);
+        assert_eq!(header.screen_mirroring, Mirroring::Vertical);
+        assert!(!header.battery);
+        assert!(header.correct(&game).is_empty());
+    }
+
+    #[test]
+    fn test_mapper_controlled_mirroring_is_not_corrected() {
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x11, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            pgp_rom: vec![1; PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+        });
+        let mut header = Header::parse(&test_rom).unwrap();
+        let game = GameInfo {
+            name: "test".to_string(),
+            sha1: [0; 20],
+            mapper: 1,
+            screen_mirroring: None,
+            timing: Timing::Ntsc,
+            battery: false,
+        };
+
+        assert!(header.correct(&game).is_empty());
+        assert_eq!(header.screen_mirroring, Mirroring::Vertical);
+    }
+
+    #[test]
+    fn test_rom_new_corrects_header_from_database() {
+        let mut bytes = std::fs::read("nestest.nes").unwrap();
+        // nestest is NROM with horizontal mirroring, claim MMC1 with vertical mirroring instead
+        bytes[6] = 0x11;
+
+        let rom = Rom::new(&bytes).unwrap();
+
+        assert_eq!(rom.mapper, 0);
+        assert_eq!(rom.screen_mirroring, Mirroring::Horizontal);
+        assert_eq!(
+            rom.corrections,
+            vec!(
+                Correction::Mapper {
+                    header: 1,
+                    database: 0
+                },
+                Correction::Mirroring {
+                    header: Mirroring::Vertical,
+                    database: Mirroring::Horizontal
+                },
+            )
+        );
+    }
+
+    fn unif_chunk(id: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut chunk = id.to_vec();
+        chunk.extend(&(data.len() as u32).to_le_bytes());
+        chunk.extend(data);
+        chunk
+    }
+
+    fn unif_rom(chunks: Vec<Vec<u8>>) -> Vec<u8> {
+        let mut raw = b"UNIF".to_vec();
+        raw.extend(&7u32.to_le_bytes());
+        raw.resize(UNIF_HEADER_SIZE, 0);
+        for chunk in chunks {
+            raw.extend(chunk);
         }
+        raw
+    }
+
+    #[test]
+    fn test_unif() {
+        let raw = unif_rom(vec![
+            unif_chunk(b"MAPR", b"NES-SNROM\0"),
+            unif_chunk(b"NAME", b"Test\0"),
+            unif_chunk(b"PRG1", &[2; PRG_ROM_PAGE_SIZE]),
+            unif_chunk(b"PRG0", &[1; PRG_ROM_PAGE_SIZE]),
+            unif_chunk(b"CHR0", &[3; CHR_ROM_PAGE_SIZE]),
+            unif_chunk(b"MIRR", &[1]),
+            unif_chunk(b"BATR", &[1]),
+            unif_chunk(b"TVCI", &[1]),
+        ]);
+
+        let rom = Rom::new(&raw).unwrap();
+
+        assert_eq!(rom.mapper, 1);
+        assert_eq!(rom.prg_rom.len(), 2 * PRG_ROM_PAGE_SIZE);
+        assert_eq!(rom.prg_rom[0], 1);
+        assert_eq!(rom.prg_rom[PRG_ROM_PAGE_SIZE], 2);
+        assert_eq!(rom.chr_rom, vec!(3; CHR_ROM_PAGE_SIZE));
+        assert_eq!(rom.screen_mirroring, Mirroring::Vertical);
+        assert!(rom.battery);
+        assert_eq!(rom.header.format, HeaderFormat::Unif);
+        assert_eq!(rom.header.timing, Timing::Pal);
+    }
+
+    #[test]
+    fn test_unif_board_names() {
+        assert_eq!(unif_board_mapper("NROM-128"), Some(0));
+        assert_eq!(unif_board_mapper("NROM-256"), Some(0));
+        assert_eq!(unif_board_mapper("RROM-128"), Some(0));
+        assert_eq!(unif_board_mapper("NES-NROM-256"), Some(0));
+        assert_eq!(unif_board_mapper("HVC-SNROM"), Some(1));
+        assert_eq!(unif_board_mapper("NES-TLROM"), Some(4));
+        assert_eq!(unif_board_mapper("UNL-COLORDREAMS-74*377"), Some(11));
+        assert_eq!(unif_board_mapper("ROM-128"), None);
+        assert_eq!(unif_board_mapper("NES-MYSTERY"), None);
+    }
+
+    #[test]
+    fn test_unif_errors() {
+        let prg = unif_chunk(b"PRG0", &[1; PRG_ROM_PAGE_SIZE]);
+
+        assert!(matches!(
+            Rom::new(&unif_rom(vec![prg.clone()])),
+            Err(RomError::MissingChunk("MAPR"))
+        ));
+        assert!(matches!(
+            Rom::new(&unif_rom(vec![
+                unif_chunk(b"MAPR", b"UNL-MYSTERY\0"),
+                prg.clone()
+            ])),
+            Err(RomError::UnsupportedBoard(_))
+        ));
+        assert!(matches!(
+            Rom::new(&unif_rom(vec![unif_chunk(b"MAPR", b"NES-NROM-128\0")])),
+            Err(RomError::MissingChunk("PRG0"))
+        ));
+
+        let raw = unif_rom(vec![unif_chunk(b"MAPR", b"NES-NROM-128\0"), prg]);
+        assert!(Rom::new(&raw).is_ok());
+        assert!(matches!(
+            Rom::new(&raw[..raw.len() - 1]),
+            Err(RomError::TruncatedChunk(_))
+        ));
+    }
+
+    #[test]
+    fn test_fds() {
+        let mut disk = b"FDS\x1A\x02".to_vec();
+        disk.resize(HEADER_SIZE, 0);
+        for _ in 0..2 {
+            let mut side = FDS_DISK_MAGIC.to_vec();
+            side.resize(FDS_SIDE_SIZE, 0);
+            disk.extend(side);
+        }
+        let bios = vec![0xEA; fds::BIOS_SIZE];
+
+        assert!(matches!(Rom::new(&disk), Err(RomError::MissingBios)));
+        assert!(matches!(
+            Rom::from_fds(&disk, &bios[1..]),
+            Err(RomError::InvalidBios { .. })
+        ));
+        assert!(matches!(
+            Rom::from_fds(&disk[..disk.len() - 1], &bios),
+            Err(RomError::TruncatedDisk { .. })
+        ));
+
+        let rom = Rom::from_fds(&disk, &bios).unwrap();
+        assert_eq!(rom.mapper, FDS_MAPPER);
+        assert_eq!(rom.prg_rom, bios);
+        assert!(rom.chr_rom.is_empty());
+        assert_eq!(rom.disk_sides.len(), 2);
+        assert!(rom.disk_sides[1].starts_with(FDS_DISK_MAGIC));
+
+        // headerless images work too
+        let rom = Rom::from_fds(&disk[HEADER_SIZE..], &bios).unwrap();
+        assert_eq!(rom.disk_sides.len(), 2);
+    }
+
+    #[test]
+    fn test_oversized_nes2_rom_fails_cleanly() {
+        let header = vec![
+            0x4E, 0x45, 0x53, 0x1A, 0xFF, 0xFF, 00, 0x08, 00, 0xFF, 00, 00, 00, 00, 00, 00,
+        ];
+
+        assert!(matches!(
+            Rom::new(&header),
+            Err(RomError::TruncatedPrg { .. })
+        ));
     }
 }
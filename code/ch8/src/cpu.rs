@@ -1,6 +1,4 @@
-use crate::bus::Bus;
 use crate::opcodes;
-use std::collections::HashMap;
 
 bitflags! {
     /// # Status Register (P) https://www.nesdev.org/wiki/Status_flags
@@ -27,20 +25,54 @@ bitflags! {
     }
 }
 
+/// Which member of the 6502 family the core behaves as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Variant {
+    /// Ricoh 2A03/2A07, the NES CPU: an NMOS 6502 without decimal mode
+    Ricoh2A03,
+    /// NMOS 6502 with BCD arithmetic, unofficial opcodes included
+    Nmos6502,
+    /// WDC 65C02: fixed JMP indirect, extra opcodes and the (zp) addressing mode
+    Cmos65C02,
+}
+
+impl Variant {
+    pub fn opcodes(self) -> &'static [Option<opcodes::OpCode>; 256] {
+        match self {
+            Variant::Cmos65C02 => &opcodes::CMOS_OPCODES,
+            _ => &opcodes::OPCODES,
+        }
+    }
+}
+
 const STACK: u16 = 0x0100;
 const STACK_RESET: u8 = 0xfd;
 
-pub struct CPU<'a> {
+// LXA and XAA OR the accumulator with a chip and temperature dependent constant
+// before the AND. 0xEE is what most NMOS parts settle on.
+// http://visual6502.org/wiki/index.php?title=6502_Opcode_8B_%28XAA,_ANE%29
+const UNSTABLE_MAGIC: u8 = 0xee;
+
+pub struct CPU<M> {
     pub register_a: u8,
     pub register_x: u8,
     pub register_y: u8,
     pub status: CpuFlags,
     pub program_counter: u16,
     pub stack_pointer: u8,
-    pub bus: Bus<'a>,
+    pub bus: M,
+    // IRQ line as sampled at the end of the previous instruction
+    irq_pending: bool,
+    // set by JAM, only a reset gets the CPU going again
+    halted: bool,
+    // set by WAI, cleared when an interrupt line is asserted
+    waiting: bool,
+    // set when the instruction moved PC itself, so execute doesn't step over its operands
+    jumped: bool,
+    variant: Variant,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 #[allow(non_camel_case_types)]
 pub enum AddressingMode {
     Immediate,
@@ -52,6 +84,8 @@ pub enum AddressingMode {
     Absolute_Y,
     Indirect_X,
     Indirect_Y,
+    // (zp), 65C02 only
+    Indirect,
     NoneAddressing,
 }
 
@@ -60,9 +94,18 @@ pub trait Mem {
 
     fn mem_write(&mut self, addr: u16, data: u8);
 
+    // what a read would return, without the side effects of reading I/O registers
+    fn peek(&self, addr: u16) -> u8;
+
+    fn peek_u16(&self, pos: u16) -> u16 {
+        let lo = self.peek(pos) as u16;
+        let hi = self.peek(pos.wrapping_add(1)) as u16;
+        (hi << 8) | lo
+    }
+
     fn mem_read_u16(&mut self, pos: u16) -> u16 {
         let lo = self.mem_read(pos) as u16;
-        let hi = self.mem_read(pos + 1) as u16;
+        let hi = self.mem_read(pos.wrapping_add(1)) as u16;
         (hi << 8) | (lo as u16)
     }
 
@@ -70,34 +113,62 @@ pub trait Mem {
         let hi = (data >> 8) as u8;
         let lo = (data & 0xff) as u8;
         self.mem_write(pos, lo);
-        self.mem_write(pos + 1, hi);
+        self.mem_write(pos.wrapping_add(1), hi);
+    }
+}
+
+// Everything else the CPU is wired to: the clock that every bus access advances,
+// and the interrupt lines. Machines without a PPU or IRQ sources keep the defaults.
+pub trait Clock {
+    fn tick(&mut self, cycles: u8);
+
+    // CPU cycles since power on
+    fn cycles(&self) -> usize;
+
+    // number of frames the video chip has completed
+    fn frame(&self) -> usize {
+        0
+    }
+
+    fn poll_nmi_status(&mut self) -> Option<u8> {
+        None
+    }
+
+    // level of the IRQ line
+    fn poll_irq_status(&mut self) -> bool {
+        false
     }
 }
 
-impl Mem for CPU<'_> {
+// Every bus access takes one CPU cycle, so the PPU and the mapper see reads and
+// writes at the cycle they happen on, not at the end of the instruction.
+impl<M: Mem + Clock> Mem for CPU<M> {
     fn mem_read(&mut self, addr: u16) -> u8 {
+        self.bus.tick(1);
         self.bus.mem_read(addr)
     }
 
     fn mem_write(&mut self, addr: u16, data: u8) {
+        self.bus.tick(1);
         self.bus.mem_write(addr, data)
     }
-    fn mem_read_u16(&mut self, addr: u16) -> u16 {
-        self.bus.mem_read_u16(addr)
-    }
 
-    fn mem_write_u16(&mut self, addr: u16, data: u16) {
-        self.bus.mem_write_u16(addr, data)
+    // peeking takes no time
+    fn peek(&self, addr: u16) -> u8 {
+        self.bus.peek(addr)
     }
 }
 fn page_cross(addr1: u16, addr2: u16) -> bool {
     addr1 & 0xFF00 != addr2 & 0xFF00
 }
 
+pub use interrupt::InterruptType;
+
 mod interrupt {
-    #[derive(PartialEq, Eq)]
+    #[derive(Debug, PartialEq, Eq, Clone, Copy)]
     pub enum InterruptType {
         NMI,
+        IRQ,
         BRK,
     }
 
@@ -106,27 +177,42 @@ mod interrupt {
         pub(super) itype: InterruptType,
         pub(super) vector_addr: u16,
         pub(super) b_flag_mask: u8,
-        pub(super) cpu_cycles: u8,
     }
 
     pub(super) const NMI: Interrupt = Interrupt {
         itype: InterruptType::NMI,
         vector_addr: 0xfffA,
         b_flag_mask: 0b00100000,
-        cpu_cycles: 2,
+    };
+
+    pub(super) const IRQ: Interrupt = Interrupt {
+        itype: InterruptType::IRQ,
+        vector_addr: 0xfffe,
+        b_flag_mask: 0b00100000,
     };
 
     pub(super) const BRK: Interrupt = Interrupt {
         itype: InterruptType::BRK,
         vector_addr: 0xfffe,
         b_flag_mask: 0b00110000,
-        cpu_cycles: 1,
     };
 
 }
 
-impl<'a> CPU<'a> {
-    pub fn new<'b>(bus: Bus<'b>) -> CPU<'b> {
+// what happened during a single CPU::step
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct StepResult {
+    pub cycles: usize,
+    pub interrupt: Option<InterruptType>,
+    pub frame_complete: bool,
+}
+
+impl<M: Mem + Clock> CPU<M> {
+    pub fn new(bus: M) -> CPU<M> {
+        CPU::with_variant(bus, Variant::Ricoh2A03)
+    }
+
+    pub fn with_variant(bus: M, variant: Variant) -> CPU<M> {
         CPU {
             register_a: 0,
             register_x: 0,
@@ -135,55 +221,72 @@ impl<'a> CPU<'a> {
             program_counter: 0x8000,
             status: CpuFlags::from_bits_truncate(0b100100),
             bus: bus,
+            irq_pending: false,
+            halted: false,
+            waiting: false,
+            jumped: false,
+            variant,
         }
     }
 
+    pub fn variant(&self) -> Variant {
+        self.variant
+    }
+
     // returns (address, page_cross flag)
-    pub fn get_absolute_address(&mut self, mode: &AddressingMode, addr: u16) -> (u16, bool) {
+    // reads straight from the bus without spending cycles, meant for the tracer
+    pub fn get_absolute_address(&self, mode: &AddressingMode, addr: u16) -> (u16, bool) {
         match mode {
-            AddressingMode::ZeroPage => (self.mem_read(addr) as u16, false),
+            AddressingMode::ZeroPage => (self.bus.peek(addr) as u16, false),
 
-            AddressingMode::Absolute => (self.mem_read_u16(addr), false),
+            AddressingMode::Absolute => (self.bus.peek_u16(addr), false),
 
             AddressingMode::ZeroPage_X => {
-                let pos = self.mem_read(addr);
+                let pos = self.bus.peek(addr);
                 let addr = pos.wrapping_add(self.register_x) as u16;
                 (addr, false)
             }
             AddressingMode::ZeroPage_Y => {
-                let pos = self.mem_read(addr);
+                let pos = self.bus.peek(addr);
                 let addr = pos.wrapping_add(self.register_y) as u16;
                 (addr, false)
             }
 
             AddressingMode::Absolute_X => {
-                let base = self.mem_read_u16(addr);
+                let base = self.bus.peek_u16(addr);
                 let addr = base.wrapping_add(self.register_x as u16);
                 (addr, page_cross(base, addr))
             }
             AddressingMode::Absolute_Y => {
-                let base = self.mem_read_u16(addr);
+                let base = self.bus.peek_u16(addr);
                 let addr = base.wrapping_add(self.register_y as u16);
                 (addr, page_cross(base, addr))
             }
 
             AddressingMode::Indirect_X => {
-                let base = self.mem_read(addr);
+                let base = self.bus.peek(addr);
 
                 let ptr: u8 = (base as u8).wrapping_add(self.register_x);
-                let lo = self.mem_read(ptr as u16);
-                let hi = self.mem_read(ptr.wrapping_add(1) as u16);
+                let lo = self.bus.peek(ptr as u16);
+                let hi = self.bus.peek(ptr.wrapping_add(1) as u16);
                 ((hi as u16) << 8 | (lo as u16), false)
             }
             AddressingMode::Indirect_Y => {
-                let base = self.mem_read(addr);
+                let base = self.bus.peek(addr);
 
-                let lo = self.mem_read(base as u16);
-                let hi = self.mem_read((base as u8).wrapping_add(1) as u16);
+                let lo = self.bus.peek(base as u16);
+                let hi = self.bus.peek((base as u8).wrapping_add(1) as u16);
                 let deref_base = (hi as u16) << 8 | (lo as u16);
                 let deref = deref_base.wrapping_add(self.register_y as u16);
                 (deref, page_cross(deref, deref_base))
             }
+            AddressingMode::Indirect => {
+                let base = self.bus.peek(addr);
+
+                let lo = self.bus.peek(base as u16);
+                let hi = self.bus.peek(base.wrapping_add(1) as u16);
+                ((hi as u16) << 8 | (lo as u16), false)
+            }
 
             _ => {
                 panic!("mode {:?} is not supported", mode);
@@ -191,44 +294,140 @@ impl<'a> CPU<'a> {
         }
     }
 
-    fn get_operand_address(&mut self, mode: &AddressingMode) -> (u16, bool) {
+    // address of the operand for instructions that read it, fetched cycle by cycle
+    // https://www.nesdev.org/6502_cpu.txt
+    fn get_operand_address(&mut self, mode: &AddressingMode) -> u16 {
+        self.fetch_operand_address(mode, false)
+    }
+
+    // stores and read-modify-write instructions always spend the page fix-up cycle
+    fn get_store_address(&mut self, mode: &AddressingMode) -> u16 {
+        self.fetch_operand_address(mode, true)
+    }
+
+    fn fetch_operand_address(&mut self, mode: &AddressingMode, always_fix_up: bool) -> u16 {
         match mode {
-            AddressingMode::Immediate => (self.program_counter, false),
-            _ => self.get_absolute_address(mode, self.program_counter),
+            AddressingMode::Immediate => self.program_counter,
+
+            AddressingMode::ZeroPage => self.mem_read(self.program_counter) as u16,
+
+            AddressingMode::Absolute => self.mem_read_u16(self.program_counter),
+
+            AddressingMode::ZeroPage_X | AddressingMode::ZeroPage_Y => {
+                let pos = self.mem_read(self.program_counter);
+                // the CPU reads the unindexed address while it adds the index
+                self.mem_read(pos as u16);
+                let index = match mode {
+                    AddressingMode::ZeroPage_X => self.register_x,
+                    _ => self.register_y,
+                };
+                pos.wrapping_add(index) as u16
+            }
+
+            AddressingMode::Absolute_X => {
+                let base = self.mem_read_u16(self.program_counter);
+                self.add_index(base, self.register_x, always_fix_up)
+            }
+            AddressingMode::Absolute_Y => {
+                let base = self.mem_read_u16(self.program_counter);
+                self.add_index(base, self.register_y, always_fix_up)
+            }
+
+            AddressingMode::Indirect_X => {
+                let base = self.mem_read(self.program_counter);
+                self.mem_read(base as u16);
+
+                let ptr: u8 = base.wrapping_add(self.register_x);
+                let lo = self.mem_read(ptr as u16);
+                let hi = self.mem_read(ptr.wrapping_add(1) as u16);
+                (hi as u16) << 8 | (lo as u16)
+            }
+            AddressingMode::Indirect_Y => {
+                let base = self.mem_read(self.program_counter);
+
+                let lo = self.mem_read(base as u16);
+                let hi = self.mem_read(base.wrapping_add(1) as u16);
+                let deref_base = (hi as u16) << 8 | (lo as u16);
+                self.add_index(deref_base, self.register_y, always_fix_up)
+            }
+            AddressingMode::Indirect => {
+                let base = self.mem_read(self.program_counter);
+
+                let lo = self.mem_read(base as u16);
+                let hi = self.mem_read(base.wrapping_add(1) as u16);
+                (hi as u16) << 8 | (lo as u16)
+            }
+
+            _ => {
+                panic!("mode {:?} is not supported", mode);
+            }
+        }
+    }
+
+    fn add_index(&mut self, base: u16, index: u8, always_fix_up: bool) -> u16 {
+        let addr = base.wrapping_add(index as u16);
+        if always_fix_up || page_cross(base, addr) {
+            // the low byte is added first, the read happens before the high byte is fixed up
+            self.mem_read((base & 0xFF00) | (addr & 0x00FF));
         }
+        addr
+    }
+
+    // AHX, SHX, SHY and TAS store the value ANDed with the high byte of the base
+    // address plus one. When the index crosses a page the stored value also
+    // replaces the high byte of the address it goes to.
+    // https://www.nesdev.org/wiki/Programming_with_unofficial_opcodes
+    fn store_and_high_byte(&mut self, mode: &AddressingMode, value: u8) {
+        let addr = self.get_store_address(mode);
+        let index = match mode {
+            AddressingMode::Absolute_X => self.register_x,
+            _ => self.register_y,
+        };
+        let base = addr.wrapping_sub(index as u16);
+        let data = value & ((base >> 8) as u8).wrapping_add(1);
+        let addr = if page_cross(base, addr) {
+            (data as u16) << 8 | (addr & 0x00FF)
+        } else {
+            addr
+        };
+        self.mem_write(addr, data);
+    }
+
+    // read-modify-write instructions write the unmodified value back before the result,
+    // the 65C02 reads it a second time instead
+    fn read_modify(&mut self, mode: &AddressingMode) -> (u16, u8) {
+        let addr = self.get_store_address(mode);
+        let data = self.mem_read(addr);
+        if self.variant == Variant::Cmos65C02 {
+            self.mem_read(addr);
+        } else {
+            self.mem_write(addr, data);
+        }
+        (addr, data)
     }
 
     fn ldy(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(mode);
+        let addr = self.get_operand_address(mode);
         let data = self.mem_read(addr);
         self.register_y = data;
         self.update_zero_and_negative_flags(self.register_y);
-        if page_cross {
-            self.bus.tick(1);
-        }
     }
 
     fn ldx(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(mode);
+        let addr = self.get_operand_address(mode);
         let data = self.mem_read(addr);
         self.register_x = data;
         self.update_zero_and_negative_flags(self.register_x);
-        if page_cross {
-            self.bus.tick(1);
-        }
     }
 
     fn lda(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(&mode);
+        let addr = self.get_operand_address(mode);
         let value = self.mem_read(addr);
         self.set_register_a(value);
-        if page_cross {
-            self.bus.tick(1);
-        }
     }
 
     fn sta(&mut self, mode: &AddressingMode) {
-        let (addr, _) = self.get_operand_address(mode);
+        let addr = self.get_store_address(mode);
         self.mem_write(addr, self.register_a);
     }
 
@@ -238,32 +437,21 @@ impl<'a> CPU<'a> {
     }
 
     fn and(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(mode);
+        let addr = self.get_operand_address(mode);
         let data = self.mem_read(addr);
         self.set_register_a(data & self.register_a);
-
-        if page_cross {
-            self.bus.tick(1);
-        }
     }
 
     fn eor(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(mode);
+        let addr = self.get_operand_address(mode);
         let data = self.mem_read(addr);
         self.set_register_a(data ^ self.register_a);
-
-        if page_cross {
-            self.bus.tick(1);
-        }
     }
 
     fn ora(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(mode);
+        let addr = self.get_operand_address(mode);
         let data = self.mem_read(addr);
         self.set_register_a(data | self.register_a);
-        if page_cross {
-            self.bus.tick(1);
-        }
     }
 
     fn tax(&mut self) {
@@ -321,10 +509,21 @@ impl<'a> CPU<'a> {
         self.register_a = 0;
         self.register_x = 0;
         self.register_y = 0;
-        self.stack_pointer = STACK_RESET;
         self.status = CpuFlags::from_bits_truncate(0b100100);
+        self.halted = false;
+        self.waiting = false;
         // self.memory = [0; 0xFFFF];
 
+        // reset is an interrupt sequence with the stack writes turned into reads:
+        // 7 cycles, and the three pushes are where SP = $FD comes from
+        self.mem_read(self.program_counter);
+        self.mem_read(self.program_counter);
+        self.stack_pointer = STACK_RESET.wrapping_add(3);
+        for _ in 0..3 {
+            self.stack_dummy_read();
+            self.stack_pointer = self.stack_pointer.wrapping_sub(1);
+        }
+
         self.program_counter = self.mem_read_u16(0xFFFC);
     }
 
@@ -336,9 +535,29 @@ impl<'a> CPU<'a> {
         self.status.remove(CpuFlags::CARRY)
     }
 
-    /// note: ignoring decimal mode
-    /// http://www.righto.com/2012/12/the-6502-overflow-flag-explained.html
+    // the 2A03 has the decimal mode adder cut out, the flag is there but does nothing
+    fn decimal_mode(&self) -> bool {
+        self.variant != Variant::Ricoh2A03 && self.status.contains(CpuFlags::DECIMAL_MODE)
+    }
+
     fn add_to_register_a(&mut self, data: u8) {
+        if self.decimal_mode() {
+            self.add_decimal(data);
+        } else {
+            self.add_binary(data);
+        }
+    }
+
+    fn sub_from_register_a(&mut self, data: u8) {
+        if self.decimal_mode() {
+            self.sub_decimal(data);
+        } else {
+            self.add_binary(((data as i8).wrapping_neg().wrapping_sub(1)) as u8);
+        }
+    }
+
+    /// http://www.righto.com/2012/12/the-6502-overflow-flag-explained.html
+    fn add_binary(&mut self, data: u8) {
         let sum = self.register_a as u16
             + data as u16
             + (if self.status.contains(CpuFlags::CARRY) {
@@ -366,8 +585,74 @@ impl<'a> CPU<'a> {
         self.set_register_a(result);
     }
 
-    fn sub_from_register_a(&mut self, data: u8) {
-        self.add_to_register_a(((data as i8).wrapping_neg().wrapping_sub(1)) as u8);
+    // http://www.6502.org/tutorials/decimal_mode.html#A
+    fn add_decimal(&mut self, data: u8) {
+        let a = self.register_a as u16;
+        let m = data as u16;
+        let carry = self.status.contains(CpuFlags::CARRY) as u16;
+
+        let mut lo = (a & 0x0f) + (m & 0x0f) + carry;
+        if lo >= 0x0a {
+            lo = ((lo + 0x06) & 0x0f) + 0x10;
+        }
+        let mut sum = (a & 0xf0) + (m & 0xf0) + lo;
+
+        // NMOS parts take N and V from the sum before the high digit is adjusted
+        let signed = (a & 0xf0) as u8 as i8 as i16 + (m & 0xf0) as u8 as i8 as i16 + lo as i16;
+        self.status.set(CpuFlags::NEGATIV, signed & 0x80 != 0);
+        self.status
+            .set(CpuFlags::OVERFLOW, !(-128..=127).contains(&signed));
+
+        if sum >= 0xa0 {
+            sum += 0x60;
+        }
+        self.status.set(CpuFlags::CARRY, sum >= 0x100);
+        self.register_a = sum as u8;
+
+        // and Z from the binary sum, the 65C02 fixes both up afterwards
+        self.status.set(CpuFlags::ZERO, (a + m + carry) & 0xff == 0);
+    }
+
+    // http://www.6502.org/tutorials/decimal_mode.html#A
+    fn sub_decimal(&mut self, data: u8) {
+        let a = self.register_a as i16;
+        let m = data as i16;
+        let borrow = !self.status.contains(CpuFlags::CARRY) as i16;
+
+        // C, V and, on NMOS, N and Z are the same as for a binary subtraction
+        self.add_binary(!data);
+
+        let result = if self.variant == Variant::Cmos65C02 {
+            let lo = (a & 0x0f) - (m & 0x0f) - borrow;
+            let mut result = a - m - borrow;
+            if result < 0 {
+                result -= 0x60;
+            }
+            if lo < 0 {
+                result -= 0x06;
+            }
+            result
+        } else {
+            let mut lo = (a & 0x0f) - (m & 0x0f) - borrow;
+            if lo < 0 {
+                lo = ((lo - 0x06) & 0x0f) - 0x10;
+            }
+            let mut result = (a & 0xf0) - (m & 0xf0) + lo;
+            if result < 0 {
+                result -= 0x60;
+            }
+            result
+        };
+        self.register_a = result as u8;
+    }
+
+    // the 65C02 spends an extra cycle, reading the operand again, to set N and Z
+    // from the decimal result
+    fn fix_decimal_flags(&mut self, addr: u16) {
+        if self.variant == Variant::Cmos65C02 && self.decimal_mode() {
+            self.mem_read(addr);
+            self.update_zero_and_negative_flags(self.register_a);
+        }
     }
 
     fn and_with_register_a(&mut self, data: u8) {
@@ -383,23 +668,22 @@ impl<'a> CPU<'a> {
     }
 
     fn sbc(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(&mode);
+        let addr = self.get_operand_address(mode);
         let data = self.mem_read(addr);
-        self.add_to_register_a(((data as i8).wrapping_neg().wrapping_sub(1)) as u8);
-
-        if page_cross {
-            self.bus.tick(1);
-        }
+        self.sub_from_register_a(data);
+        self.fix_decimal_flags(addr);
     }
 
     fn adc(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(mode);
+        let addr = self.get_operand_address(mode);
         let value = self.mem_read(addr);
         self.add_to_register_a(value);
+        self.fix_decimal_flags(addr);
+    }
 
-        if page_cross {
-            self.bus.tick(1);
-        }
+    // pulls spend a cycle reading the stack while the pointer is incremented
+    fn stack_dummy_read(&mut self) {
+        self.mem_read(STACK + self.stack_pointer as u16);
     }
 
     fn stack_pop(&mut self) -> u8 {
@@ -438,8 +722,7 @@ impl<'a> CPU<'a> {
     }
 
     fn asl(&mut self, mode: &AddressingMode) -> u8 {
-        let (addr, _) = self.get_operand_address(mode);
-        let mut data = self.mem_read(addr);
+        let (addr, mut data) = self.read_modify(mode);
         if data >> 7 == 1 {
             self.set_carry_flag();
         } else {
@@ -463,8 +746,7 @@ impl<'a> CPU<'a> {
     }
 
     fn lsr(&mut self, mode: &AddressingMode) -> u8 {
-        let (addr, _) = self.get_operand_address(mode);
-        let mut data = self.mem_read(addr);
+        let (addr, mut data) = self.read_modify(mode);
         if data & 1 == 1 {
             self.set_carry_flag();
         } else {
@@ -477,8 +759,7 @@ impl<'a> CPU<'a> {
     }
 
     fn rol(&mut self, mode: &AddressingMode) -> u8 {
-        let (addr, _) = self.get_operand_address(mode);
-        let mut data = self.mem_read(addr);
+        let (addr, mut data) = self.read_modify(mode);
         let old_carry = self.status.contains(CpuFlags::CARRY);
 
         if data >> 7 == 1 {
@@ -512,8 +793,7 @@ impl<'a> CPU<'a> {
     }
 
     fn ror(&mut self, mode: &AddressingMode) -> u8 {
-        let (addr, _) = self.get_operand_address(mode);
-        let mut data = self.mem_read(addr);
+        let (addr, mut data) = self.read_modify(mode);
         let old_carry = self.status.contains(CpuFlags::CARRY);
 
         if data & 1 == 1 {
@@ -547,8 +827,7 @@ impl<'a> CPU<'a> {
     }
 
     fn inc(&mut self, mode: &AddressingMode) -> u8 {
-        let (addr, _) = self.get_operand_address(mode);
-        let mut data = self.mem_read(addr);
+        let (addr, mut data) = self.read_modify(mode);
         data = data.wrapping_add(1);
         self.mem_write(addr, data);
         self.update_zero_and_negative_flags(data);
@@ -566,8 +845,7 @@ impl<'a> CPU<'a> {
     }
 
     fn dec(&mut self, mode: &AddressingMode) -> u8 {
-        let (addr, _) = self.get_operand_address(mode);
-        let mut data = self.mem_read(addr);
+        let (addr, mut data) = self.read_modify(mode);
         data = data.wrapping_sub(1);
         self.mem_write(addr, data);
         self.update_zero_and_negative_flags(data);
@@ -575,11 +853,13 @@ impl<'a> CPU<'a> {
     }
 
     fn pla(&mut self) {
+        self.stack_dummy_read();
         let data = self.stack_pop();
         self.set_register_a(data);
     }
 
     fn plp(&mut self) {
+        self.stack_dummy_read();
         self.status.bits = self.stack_pop();
         self.status.remove(CpuFlags::BREAK);
         self.status.insert(CpuFlags::BREAK2);
@@ -594,7 +874,7 @@ impl<'a> CPU<'a> {
     }
 
     fn bit(&mut self, mode: &AddressingMode) {
-        let (addr, _) = self.get_operand_address(mode);
+        let addr = self.get_operand_address(mode);
         let data = self.mem_read(addr);
         let and = self.register_a & data;
         if and == 0 {
@@ -608,7 +888,7 @@ impl<'a> CPU<'a> {
     }
 
     fn compare(&mut self, mode: &AddressingMode, compare_with: u8) {
-        let (addr, page_cross) = self.get_operand_address(mode);
+        let addr = self.get_operand_address(mode);
         let data = self.mem_read(addr);
         if data <= compare_with {
             self.status.insert(CpuFlags::CARRY);
@@ -617,41 +897,71 @@ impl<'a> CPU<'a> {
         }
 
         self.update_zero_and_negative_flags(compare_with.wrapping_sub(data));
-
-        if page_cross {
-            self.bus.tick(1);
-        }
     }
 
     fn branch(&mut self, condition: bool) {
+        let jump: i8 = self.mem_read(self.program_counter) as i8;
         if condition {
-            self.bus.tick(1);
-
-            let jump: i8 = self.mem_read(self.program_counter) as i8;
-            let jump_addr = self
-                .program_counter
-                .wrapping_add(1)
-                .wrapping_add(jump as u16);
-
-            if self.program_counter.wrapping_add(1) & 0xFF00 != jump_addr & 0xFF00 {
-                self.bus.tick(1);
+            let next_addr = self.program_counter.wrapping_add(1);
+            let jump_addr = next_addr.wrapping_add(jump as u16);
+
+            // the opcode at the next address is fetched and thrown away,
+            // and once more from the wrong page if the branch crosses one
+            self.mem_read(next_addr);
+            if page_cross(next_addr, jump_addr) {
+                self.mem_read((next_addr & 0xFF00) | (jump_addr & 0x00FF));
             }
 
-            self.program_counter = jump_addr;
+            self.jump(jump_addr);
         }
     }
 
+    fn jump(&mut self, addr: u16) {
+        self.program_counter = addr;
+        self.jumped = true;
+    }
+
     fn interrupt(&mut self, interrupt: interrupt::Interrupt) {
         self.stack_push_u16(self.program_counter);
         let mut flag = self.status.clone();
-        flag.set(CpuFlags::BREAK, interrupt.b_flag_mask & 0b010000 == 1);
-        flag.set(CpuFlags::BREAK2, interrupt.b_flag_mask & 0b100000 == 1);
+        flag.set(CpuFlags::BREAK, interrupt.b_flag_mask & 0b010000 != 0);
+        flag.set(CpuFlags::BREAK2, interrupt.b_flag_mask & 0b100000 != 0);
 
         self.stack_push(flag.bits);
         self.status.insert(CpuFlags::INTERRUPT_DISABLE);
+        if self.variant == Variant::Cmos65C02 {
+            self.status.remove(CpuFlags::DECIMAL_MODE);
+        }
+
+        // https://www.nesdev.org/wiki/CPU_interrupts#Interrupt_hijacking
+        // an NMI that shows up while BRK/IRQ is pushing its state takes over the vector fetch
+        let vector_addr = if interrupt.itype != interrupt::InterruptType::NMI
+            && self.bus.poll_nmi_status().is_some()
+        {
+            interrupt::NMI.vector_addr
+        } else {
+            interrupt.vector_addr
+        };
+        self.program_counter = self.mem_read_u16(vector_addr);
+        self.jumped = true;
+    }
+
+    fn handle_interrupts(&mut self) -> Option<InterruptType> {
+        let interrupt = if let Some(_nmi) = self.bus.poll_nmi_status() {
+            interrupt::NMI
+        } else if self.irq_pending {
+            interrupt::IRQ
+        } else {
+            return None;
+        };
 
-        self.bus.tick(interrupt.cpu_cycles);
-        self.program_counter = self.mem_read_u16(interrupt.vector_addr);
+        // the CPU fetches the next opcode twice and discards it, BRK does the same
+        // with its opcode and padding byte
+        let itype = interrupt.itype;
+        self.mem_read(self.program_counter);
+        self.mem_read(self.program_counter);
+        self.interrupt(interrupt);
+        Some(itype)
     }
 
     pub fn run(&mut self) {
@@ -660,564 +970,773 @@ impl<'a> CPU<'a> {
 
     pub fn run_with_callback<F>(&mut self, mut callback: F)
     where
-        F: FnMut(&mut CPU),
+        F: FnMut(&mut CPU<M>),
     {
-        let ref opcodes: HashMap<u8, &'static opcodes::OpCode> = *opcodes::OPCODES_MAP;
-
-        loop {
-            if let Some(_nmi) = self.bus.poll_nmi_status() {
-                self.interrupt(interrupt::NMI);
-            }
-
+        while !self.halted {
+            self.handle_interrupts();
             callback(self);
-            let code = self.mem_read(self.program_counter);
-            self.program_counter += 1;
-            let program_counter_state = self.program_counter;
-
-            let opcode = opcodes
-                .get(&code)
-                .expect(&format!("OpCode {:x} is not recognized", code));
-
-            // if opcode.code == 0x24 {
-            //     panic!(format!("mem 01 = {}", self.mem_read(0x01)));
-            // }
-            match code {
-                0xa9 | 0xa5 | 0xb5 | 0xad | 0xbd | 0xb9 | 0xa1 | 0xb1 => {
-                    self.lda(&opcode.mode);
-                }
-
-                0xAA => self.tax(),
-                0xe8 => self.inx(),
-                0x00 => return,
-                // 0x00 => {
-                //     self.program_counter += 1;
-                //     if !self.status.contains(CpuFlags::INTERRUPT_DISABLE) {
-                //         self.interrupt(interrupt::BRK);
-                //     }
-                // }
-    
-                /* CLD */ 0xd8 => self.status.remove(CpuFlags::DECIMAL_MODE),
+            self.execute();
+        }
+    }
 
-                /* CLI */ 0x58 => self.status.remove(CpuFlags::INTERRUPT_DISABLE),
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
 
-                /* CLV */ 0xb8 => self.status.remove(CpuFlags::OVERFLOW),
+    // runs a pending interrupt, if any, and then one instruction
+    // a halted CPU only lets the rest of the console run for one cycle
+    pub fn step(&mut self) -> StepResult {
+        let cycles = self.bus.cycles();
+        let frame = self.bus.frame();
 
-                /* CLC */ 0x18 => self.clear_carry_flag(),
+        if self.halted {
+            self.bus.tick(1);
+            return StepResult {
+                cycles: 1,
+                interrupt: None,
+                frame_complete: self.bus.frame() != frame,
+            };
+        }
 
-                /* SEC */ 0x38 => self.set_carry_flag(),
+        if self.waiting {
+            // WAI idles until an interrupt line goes active,
+            // a masked IRQ resumes after the WAI without taking the interrupt
+            self.bus.tick(1);
+            let irq_line = self.bus.poll_irq_status();
+            self.irq_pending =
+                irq_line && !self.status.contains(CpuFlags::INTERRUPT_DISABLE);
+            let interrupt = self.handle_interrupts();
+            self.waiting = interrupt.is_none() && !irq_line;
+            return StepResult {
+                cycles: self.bus.cycles() - cycles,
+                interrupt,
+                frame_complete: self.bus.frame() != frame,
+            };
+        }
 
-                /* SEI */ 0x78 => self.status.insert(CpuFlags::INTERRUPT_DISABLE),
+        let interrupt = self.handle_interrupts();
+        let code = self.execute();
 
-                /* SED */ 0xf8 => self.status.insert(CpuFlags::DECIMAL_MODE),
+        StepResult {
+            cycles: self.bus.cycles() - cycles,
+            interrupt: interrupt.or(if code == 0x00 {
+                Some(InterruptType::BRK)
+            } else {
+                None
+            }),
+            frame_complete: self.bus.frame() != frame,
+        }
+    }
 
-                /* PHA */ 0x48 => self.stack_push(self.register_a),
+    // runs until the PPU finishes the current frame
+    pub fn run_frame(&mut self) {
+        while !self.step().frame_complete {}
+    }
 
-                /* PLA */
-                0x68 => {
-                    self.pla();
-                }
+    // runs until the predicate holds, it is checked before every instruction
+    pub fn run_until<F>(&mut self, mut predicate: F)
+    where
+        F: FnMut(&mut CPU<M>) -> bool,
+    {
+        while !predicate(self) {
+            self.step();
+        }
+    }
 
-                /* PHP */
-                0x08 => {
-                    self.php();
-                }
+    // 65C02 opcodes that differ from the NMOS ones, returns false for the shared ones
+    // http://www.6502.org/tutorials/65c02opcodes.html
+    fn execute_65c02(&mut self, opcode: &opcodes::OpCode) -> bool {
+        match opcode.code {
+            /* ORA (zp) */ 0x12 => self.ora(&opcode.mode),
+            /* AND (zp) */ 0x32 => self.and(&opcode.mode),
+            /* EOR (zp) */ 0x52 => self.eor(&opcode.mode),
+            /* ADC (zp) */ 0x72 => self.adc(&opcode.mode),
+            /* STA (zp) */ 0x92 => self.sta(&opcode.mode),
+            /* LDA (zp) */ 0xb2 => self.lda(&opcode.mode),
+            /* CMP (zp) */ 0xd2 => self.compare(&opcode.mode, self.register_a),
+            /* SBC (zp) */ 0xf2 => self.sbc(&opcode.mode),
+
+            /* BIT */
+            0x89 => {
+                // the immediate form only sets Z
+                let data = self.mem_read(self.program_counter);
+                self.status
+                    .set(CpuFlags::ZERO, self.register_a & data == 0);
+            }
+            0x34 | 0x3c => self.bit(&opcode.mode),
+
+            /* TSB */
+            0x04 | 0x0c => {
+                let (addr, data) = self.read_modify(&opcode.mode);
+                self.status
+                    .set(CpuFlags::ZERO, self.register_a & data == 0);
+                self.mem_write(addr, data | self.register_a);
+            }
 
-                /* PLP */
-                0x28 => {
-                    self.plp();
-                }
+            /* TRB */
+            0x14 | 0x1c => {
+                let (addr, data) = self.read_modify(&opcode.mode);
+                self.status
+                    .set(CpuFlags::ZERO, self.register_a & data == 0);
+                self.mem_write(addr, data & !self.register_a);
+            }
 
-                /* ADC */
-                0x69 | 0x65 | 0x75 | 0x6d | 0x7d | 0x79 | 0x61 | 0x71 => {
-                    self.adc(&opcode.mode);
-                }
+            /* STZ */
+            0x64 | 0x74 | 0x9c | 0x9e => {
+                let addr = self.get_store_address(&opcode.mode);
+                self.mem_write(addr, 0);
+            }
 
-                /* SBC */
-                0xe9 | 0xe5 | 0xf5 | 0xed | 0xfd | 0xf9 | 0xe1 | 0xf1 => {
-                    self.sbc(&opcode.mode);
-                }
+            /* INC A */ 0x1a => self.set_register_a(self.register_a.wrapping_add(1)),
 
-                /* AND */
-                0x29 | 0x25 | 0x35 | 0x2d | 0x3d | 0x39 | 0x21 | 0x31 => {
-                    self.and(&opcode.mode);
-                }
+            /* DEC A */ 0x3a => self.set_register_a(self.register_a.wrapping_sub(1)),
 
-                /* EOR */
-                0x49 | 0x45 | 0x55 | 0x4d | 0x5d | 0x59 | 0x41 | 0x51 => {
-                    self.eor(&opcode.mode);
-                }
+            /* PHY */ 0x5a => self.stack_push(self.register_y),
 
-                /* ORA */
-                0x09 | 0x05 | 0x15 | 0x0d | 0x1d | 0x19 | 0x01 | 0x11 => {
-                    self.ora(&opcode.mode);
-                }
+            /* PHX */ 0xda => self.stack_push(self.register_x),
 
-                /* LSR */ 0x4a => self.lsr_accumulator(),
+            /* PLY */
+            0x7a => {
+                self.stack_dummy_read();
+                self.register_y = self.stack_pop();
+                self.update_zero_and_negative_flags(self.register_y);
+            }
 
-                /* LSR */
-                0x46 | 0x56 | 0x4e | 0x5e => {
-                    self.lsr(&opcode.mode);
-                }
+            /* PLX */
+            0xfa => {
+                self.stack_dummy_read();
+                self.register_x = self.stack_pop();
+                self.update_zero_and_negative_flags(self.register_x);
+            }
 
-                /*ASL*/ 0x0a => self.asl_accumulator(),
+            /* BRA */ 0x80 => self.branch(true),
 
-                /* ASL */
-                0x06 | 0x16 | 0x0e | 0x1e => {
-                    self.asl(&opcode.mode);
-                }
+            /* JMP Indirect, without the page wrap bug */
+            0x6c => {
+                let mem_address = self.mem_read_u16(self.program_counter);
+                self.mem_read(self.program_counter.wrapping_add(1));
+                let target = self.mem_read_u16(mem_address);
+                self.jump(target);
+            }
 
-                /*ROL*/ 0x2a => self.rol_accumulator(),
+            /* JMP (abs,X) */
+            0x7c => {
+                let base = self.mem_read_u16(self.program_counter);
+                self.mem_read(self.program_counter.wrapping_add(1));
+                let mem_address = base.wrapping_add(self.register_x as u16);
+                let target = self.mem_read_u16(mem_address);
+                self.jump(target);
+            }
 
-                /* ROL */
-                0x26 | 0x36 | 0x2e | 0x3e => {
-                    self.rol(&opcode.mode);
+            /* RMB, SMB */
+            0x07 | 0x17 | 0x27 | 0x37 | 0x47 | 0x57 | 0x67 | 0x77 | 0x87 | 0x97 | 0xa7 | 0xb7
+            | 0xc7 | 0xd7 | 0xe7 | 0xf7 => {
+                let mask = 1 << ((opcode.code >> 4) & 0b111);
+                let (addr, data) = self.read_modify(&opcode.mode);
+                if opcode.code & 0x80 == 0 {
+                    self.mem_write(addr, data & !mask);
+                } else {
+                    self.mem_write(addr, data | mask);
                 }
+            }
 
-                /* ROR */ 0x6a => self.ror_accumulator(),
-
-                /* ROR */
-                0x66 | 0x76 | 0x6e | 0x7e => {
-                    self.ror(&opcode.mode);
+            /* BBR, BBS */
+            0x0f | 0x1f | 0x2f | 0x3f | 0x4f | 0x5f | 0x6f | 0x7f | 0x8f | 0x9f | 0xaf | 0xbf
+            | 0xcf | 0xdf | 0xef | 0xff => {
+                let mask = 1 << ((opcode.code >> 4) & 0b111);
+                let zero_page = self.mem_read(self.program_counter) as u16;
+                let data = self.mem_read(zero_page);
+                self.mem_read(zero_page);
+                let bit_set = data & mask != 0;
+                let condition = bit_set == (opcode.code & 0x80 != 0);
+
+                self.program_counter = self.program_counter.wrapping_add(1);
+                self.branch(condition);
+                if !condition {
+                    self.jump(self.program_counter.wrapping_add(1));
                 }
+            }
 
-                /* INC */
-                0xe6 | 0xf6 | 0xee | 0xfe => {
-                    self.inc(&opcode.mode);
-                }
+            /* WAI */ 0xcb => self.waiting = true,
 
-                /* INY */
-                0xc8 => self.iny(),
+            /* STP */
+            0xdb => {
+                self.jump(self.program_counter.wrapping_sub(1));
+                self.halted = true;
+            }
 
-                /* DEC */
-                0xc6 | 0xd6 | 0xce | 0xde => {
-                    self.dec(&opcode.mode);
+            /* NOPs */
+            0x02 | 0x22 | 0x42 | 0x62 | 0x82 | 0xc2 | 0xe2 | 0x44 | 0x54 | 0xd4 | 0xf4 | 0xdc
+            | 0xfc => {
+                let addr = self.get_operand_address(&opcode.mode);
+                self.mem_read(addr);
+            }
+            0x5c => {
+                let addr = self.get_operand_address(&opcode.mode);
+                for _ in 0..5 {
+                    self.mem_read(addr);
                 }
+            }
+            code if code & 0b111 == 0b011 => { /* single cycle NOPs */ }
 
-                /* DEX */
-                0xca => {
-                    self.dex();
-                }
+            _ => return false,
+        }
+        true
+    }
 
-                /* DEY */
-                0x88 => {
-                    self.dey();
-                }
+    // returns the executed opcode
+    fn execute(&mut self) -> u8 {
+        // https://www.nesdev.org/wiki/CPU_interrupts#Delayed_IRQ_response_after_CLI,_SEI,_and_PLP
+        // the IRQ line is polled before CLI/SEI/PLP change the I flag
+        let interrupt_disable = self.status.contains(CpuFlags::INTERRUPT_DISABLE);
 
-                /* CMP */
-                0xc9 | 0xc5 | 0xd5 | 0xcd | 0xdd | 0xd9 | 0xc1 | 0xd1 => {
-                    self.compare(&opcode.mode, self.register_a);
-                }
+        let code = self.mem_read(self.program_counter);
+        self.program_counter = self.program_counter.wrapping_add(1);
+        self.jumped = false;
 
-                /* CPY */
-                0xc0 | 0xc4 | 0xcc => {
-                    self.compare(&opcode.mode, self.register_y);
-                }
+        let opcode = match &self.variant.opcodes()[code as usize] {
+            Some(opcode) => opcode,
+            None => panic!("OpCode {:x} is not recognized", code),
+        };
 
-                /* CPX */
-                0xe0 | 0xe4 | 0xec => self.compare(&opcode.mode, self.register_x),
+        // single byte instructions still read the byte after the opcode,
+        // except for the one cycle NOPs of the 65C02
+        if opcode.len == 1 && opcode.cycles > 1 {
+            self.mem_read(self.program_counter);
+        }
 
-                /* JMP Absolute */
-                0x4c => {
-                    let mem_address = self.mem_read_u16(self.program_counter);
-                    self.program_counter = mem_address;
-                }
+        let cmos_only = self.variant == Variant::Cmos65C02 && self.execute_65c02(opcode);
 
-                /* JMP Indirect */
-                0x6c => {
-                    let mem_address = self.mem_read_u16(self.program_counter);
-                    // let indirect_ref = self.mem_read_u16(mem_address);
-                    //6502 bug mode with with page boundary:
-                    //  if address $3000 contains $40, $30FF contains $80, and $3100 contains $50,
-                    // the result of JMP ($30FF) will be a transfer of control to $4080 rather than $5080 as you intended
-                    // i.e. the 6502 took the low byte of the address from $30FF and the high byte from $3000
-
-                    let indirect_ref = if mem_address & 0x00FF == 0x00FF {
-                        let lo = self.mem_read(mem_address);
-                        let hi = self.mem_read(mem_address & 0xFF00);
-                        (hi as u16) << 8 | (lo as u16)
-                    } else {
-                        self.mem_read_u16(mem_address)
-                    };
-
-                    self.program_counter = indirect_ref;
-                }
+        // if opcode.code == 0x24 {
+        //     panic!(format!("mem 01 = {}", self.mem_read(0x01)));
+        // }
+        match code {
+            _ if cmos_only => {}
 
-                /* JSR */
-                0x20 => {
-                    self.stack_push_u16(self.program_counter + 2 - 1);
-                    let target_address = self.mem_read_u16(self.program_counter);
-                    self.program_counter = target_address
-                }
+            0xa9 | 0xa5 | 0xb5 | 0xad | 0xbd | 0xb9 | 0xa1 | 0xb1 => {
+                self.lda(&opcode.mode);
+            }
 
-                /* RTS */
-                0x60 => {
-                    self.program_counter = self.stack_pop_u16() + 1;
-                }
+            0xAA => self.tax(),
+            0xe8 => self.inx(),
+            /* BRK */
+            0x00 => {
+                // skip the padding byte, the handler returns to PC+2
+                self.program_counter = self.program_counter.wrapping_add(1);
+                self.interrupt(interrupt::BRK);
+            }
 
-                /* RTI */
-                0x40 => {
-                    self.status.bits = self.stack_pop();
-                    self.status.remove(CpuFlags::BREAK);
-                    self.status.insert(CpuFlags::BREAK2);
+            /* CLD */ 0xd8 => self.status.remove(CpuFlags::DECIMAL_MODE),
 
-                    self.program_counter = self.stack_pop_u16();
-                }
+            /* CLI */ 0x58 => self.status.remove(CpuFlags::INTERRUPT_DISABLE),
 
-                /* BNE */
-                0xd0 => {
-                    self.branch(!self.status.contains(CpuFlags::ZERO));
-                }
+            /* CLV */ 0xb8 => self.status.remove(CpuFlags::OVERFLOW),
 
-                /* BVS */
-                0x70 => {
-                    self.branch(self.status.contains(CpuFlags::OVERFLOW));
-                }
+            /* CLC */ 0x18 => self.clear_carry_flag(),
 
-                /* BVC */
-                0x50 => {
-                    self.branch(!self.status.contains(CpuFlags::OVERFLOW));
-                }
+            /* SEC */ 0x38 => self.set_carry_flag(),
 
-                /* BPL */
-                0x10 => {
-                    self.branch(!self.status.contains(CpuFlags::NEGATIV));
-                }
+            /* SEI */ 0x78 => self.status.insert(CpuFlags::INTERRUPT_DISABLE),
 
-                /* BMI */
-                0x30 => {
-                    self.branch(self.status.contains(CpuFlags::NEGATIV));
-                }
+            /* SED */ 0xf8 => self.status.insert(CpuFlags::DECIMAL_MODE),
 
-                /* BEQ */
-                0xf0 => {
-                    self.branch(self.status.contains(CpuFlags::ZERO));
-                }
+            /* PHA */ 0x48 => self.stack_push(self.register_a),
 
-                /* BCS */
-                0xb0 => {
-                    self.branch(self.status.contains(CpuFlags::CARRY));
-                }
+            /* PLA */
+            0x68 => {
+                self.pla();
+            }
 
-                /* BCC */
-                0x90 => {
-                    self.branch(!self.status.contains(CpuFlags::CARRY));
-                }
+            /* PHP */
+            0x08 => {
+                self.php();
+            }
 
-                /* BIT */
-                0x24 | 0x2c => {
-                    self.bit(&opcode.mode);
-                }
+            /* PLP */
+            0x28 => {
+                self.plp();
+            }
 
-                /* STA */
-                0x85 | 0x95 | 0x8d | 0x9d | 0x99 | 0x81 | 0x91 => {
-                    self.sta(&opcode.mode);
-                }
+            /* ADC */
+            0x69 | 0x65 | 0x75 | 0x6d | 0x7d | 0x79 | 0x61 | 0x71 => {
+                self.adc(&opcode.mode);
+            }
 
-                /* STX */
-                0x86 | 0x96 | 0x8e => {
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    self.mem_write(addr, self.register_x);
-                }
+            /* SBC */
+            0xe9 | 0xe5 | 0xf5 | 0xed | 0xfd | 0xf9 | 0xe1 | 0xf1 => {
+                self.sbc(&opcode.mode);
+            }
 
-                /* STY */
-                0x84 | 0x94 | 0x8c => {
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    self.mem_write(addr, self.register_y);
-                }
+            /* AND */
+            0x29 | 0x25 | 0x35 | 0x2d | 0x3d | 0x39 | 0x21 | 0x31 => {
+                self.and(&opcode.mode);
+            }
 
-                /* LDX */
-                0xa2 | 0xa6 | 0xb6 | 0xae | 0xbe => {
-                    self.ldx(&opcode.mode);
-                }
+            /* EOR */
+            0x49 | 0x45 | 0x55 | 0x4d | 0x5d | 0x59 | 0x41 | 0x51 => {
+                self.eor(&opcode.mode);
+            }
 
-                /* LDY */
-                0xa0 | 0xa4 | 0xb4 | 0xac | 0xbc => {
-                    self.ldy(&opcode.mode);
-                }
+            /* ORA */
+            0x09 | 0x05 | 0x15 | 0x0d | 0x1d | 0x19 | 0x01 | 0x11 => {
+                self.ora(&opcode.mode);
+            }
 
-                /* NOP */
-                0xea => {
-                    //do nothing
-                }
+            /* LSR */ 0x4a => self.lsr_accumulator(),
 
-                /* TAY */
-                0xa8 => {
-                    self.register_y = self.register_a;
-                    self.update_zero_and_negative_flags(self.register_y);
-                }
+            /* LSR */
+            0x46 | 0x56 | 0x4e | 0x5e => {
+                self.lsr(&opcode.mode);
+            }
 
-                /* TSX */
-                0xba => {
-                    self.register_x = self.stack_pointer;
-                    self.update_zero_and_negative_flags(self.register_x);
-                }
+            /*ASL*/ 0x0a => self.asl_accumulator(),
 
-                /* TXA */
-                0x8a => {
-                    self.register_a = self.register_x;
-                    self.update_zero_and_negative_flags(self.register_a);
-                }
+            /* ASL */
+            0x06 | 0x16 | 0x0e | 0x1e => {
+                self.asl(&opcode.mode);
+            }
 
-                /* TXS */
-                0x9a => {
-                    self.stack_pointer = self.register_x;
-                }
+            /*ROL*/ 0x2a => self.rol_accumulator(),
 
-                /* TYA */
-                0x98 => {
-                    self.register_a = self.register_y;
-                    self.update_zero_and_negative_flags(self.register_a);
-                }
+            /* ROL */
+            0x26 | 0x36 | 0x2e | 0x3e => {
+                self.rol(&opcode.mode);
+            }
 
-                /* unofficial */
+            /* ROR */ 0x6a => self.ror_accumulator(),
 
-                /* DCP */
-                0xc7 | 0xd7 | 0xCF | 0xdF | 0xdb | 0xd3 | 0xc3 => {
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    let mut data = self.mem_read(addr);
-                    data = data.wrapping_sub(1);
-                    self.mem_write(addr, data);
-                    // self._update_zero_and_negative_flags(data);
-                    if data <= self.register_a {
-                        self.status.insert(CpuFlags::CARRY);
-                    }
+            /* ROR */
+            0x66 | 0x76 | 0x6e | 0x7e => {
+                self.ror(&opcode.mode);
+            }
 
-                    self.update_zero_and_negative_flags(self.register_a.wrapping_sub(data));
-                }
+            /* INC */
+            0xe6 | 0xf6 | 0xee | 0xfe => {
+                self.inc(&opcode.mode);
+            }
 
-                /* RLA */
-                0x27 | 0x37 | 0x2F | 0x3F | 0x3b | 0x33 | 0x23 => {
-                    let data = self.rol(&opcode.mode);
-                    self.and_with_register_a(data);
-                }
+            /* INY */
+            0xc8 => self.iny(),
 
-                /* SLO */ //todo tests
-                0x07 | 0x17 | 0x0F | 0x1f | 0x1b | 0x03 | 0x13 => {
-                    let data = self.asl(&opcode.mode);
-                    self.or_with_register_a(data);
-                }
+            /* DEC */
+            0xc6 | 0xd6 | 0xce | 0xde => {
+                self.dec(&opcode.mode);
+            }
 
-                /* SRE */ //todo tests
-                0x47 | 0x57 | 0x4F | 0x5f | 0x5b | 0x43 | 0x53 => {
-                    let data = self.lsr(&opcode.mode);
-                    self.xor_with_register_a(data);
-                }
+            /* DEX */
+            0xca => {
+                self.dex();
+            }
 
-                /* SKB */
-                0x80 | 0x82 | 0x89 | 0xc2 | 0xe2 => {
-                    /* 2 byte NOP (immediate ) */
-                    // todo: might be worth doing the read
-                }
+            /* DEY */
+            0x88 => {
+                self.dey();
+            }
 
-                /* AXS */
-                0xCB => {
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    let data = self.mem_read(addr);
-                    let x_and_a = self.register_x & self.register_a;
-                    let result = x_and_a.wrapping_sub(data);
+            /* CMP */
+            0xc9 | 0xc5 | 0xd5 | 0xcd | 0xdd | 0xd9 | 0xc1 | 0xd1 => {
+                self.compare(&opcode.mode, self.register_a);
+            }
 
-                    if data <= x_and_a {
-                        self.status.insert(CpuFlags::CARRY);
-                    }
-                    self.update_zero_and_negative_flags(result);
+            /* CPY */
+            0xc0 | 0xc4 | 0xcc => {
+                self.compare(&opcode.mode, self.register_y);
+            }
 
-                    self.register_x = result;
-                }
+            /* CPX */
+            0xe0 | 0xe4 | 0xec => self.compare(&opcode.mode, self.register_x),
 
-                /* ARR */
-                0x6B => {
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    let data = self.mem_read(addr);
-                    self.and_with_register_a(data);
-                    self.ror_accumulator();
-                    //todo: registers
-                    let result = self.register_a;
-                    let bit_5 = (result >> 5) & 1;
-                    let bit_6 = (result >> 6) & 1;
-
-                    if bit_6 == 1 {
-                        self.status.insert(CpuFlags::CARRY)
-                    } else {
-                        self.status.remove(CpuFlags::CARRY)
-                    }
-
-                    if bit_5 ^ bit_6 == 1 {
-                        self.status.insert(CpuFlags::OVERFLOW);
-                    } else {
-                        self.status.remove(CpuFlags::OVERFLOW);
-                    }
-
-                    self.update_zero_and_negative_flags(result);
-                }
+            /* JMP Absolute */
+            0x4c => {
+                let mem_address = self.mem_read_u16(self.program_counter);
+                self.jump(mem_address);
+            }
 
-                /* unofficial SBC */
-                0xeb => {
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    let data = self.mem_read(addr);
-                    self.sub_from_register_a(data);
-                }
+            /* JMP Indirect */
+            0x6c => {
+                let mem_address = self.mem_read_u16(self.program_counter);
+                // let indirect_ref = self.mem_read_u16(mem_address);
+                //6502 bug mode with with page boundary:
+                //  if address $3000 contains $40, $30FF contains $80, and $3100 contains $50,
+                // the result of JMP ($30FF) will be a transfer of control to $4080 rather than $5080 as you intended
+                // i.e. the 6502 took the low byte of the address from $30FF and the high byte from $3000
+
+                let indirect_ref = if mem_address & 0x00FF == 0x00FF {
+                    let lo = self.mem_read(mem_address);
+                    let hi = self.mem_read(mem_address & 0xFF00);
+                    (hi as u16) << 8 | (lo as u16)
+                } else {
+                    self.mem_read_u16(mem_address)
+                };
+
+                self.jump(indirect_ref);
+            }
 
-                /* ANC */
-                0x0b | 0x2b => {
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    let data = self.mem_read(addr);
-                    self.and_with_register_a(data);
-                    if self.status.contains(CpuFlags::NEGATIV) {
-                        self.status.insert(CpuFlags::CARRY);
-                    } else {
-                        self.status.remove(CpuFlags::CARRY);
-                    }
-                }
+            /* JSR */
+            0x20 => {
+                // the return address is pushed between the two operand reads
+                let lo = self.mem_read(self.program_counter);
+                self.stack_dummy_read();
+                self.stack_push_u16(self.program_counter.wrapping_add(1));
+                let hi = self.mem_read(self.program_counter.wrapping_add(1));
+                self.jump((hi as u16) << 8 | (lo as u16));
+            }
 
-                /* ALR */
-                0x4b => {
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    let data = self.mem_read(addr);
-                    self.and_with_register_a(data);
-                    self.lsr_accumulator();
-                }
+            /* RTS */
+            0x60 => {
+                self.stack_dummy_read();
+                let return_addr = self.stack_pop_u16();
+                self.mem_read(return_addr);
+                self.jump(return_addr.wrapping_add(1));
+            }
 
-                //todo: test for everything below
-
-                /* NOP read */
-                0x04 | 0x44 | 0x64 | 0x14 | 0x34 | 0x54 | 0x74 | 0xd4 | 0xf4 | 0x0c | 0x1c
-                | 0x3c | 0x5c | 0x7c | 0xdc | 0xfc => {
-                    let (addr, page_cross) = self.get_operand_address(&opcode.mode);
-                    let _data = self.mem_read(addr);
-                    if page_cross {
-                        self.bus.tick(1);
-                    }
-                    /* do nothing */
-                }
+            /* RTI */
+            0x40 => {
+                self.stack_dummy_read();
+                self.status.bits = self.stack_pop();
+                self.status.remove(CpuFlags::BREAK);
+                self.status.insert(CpuFlags::BREAK2);
 
-                /* RRA */
-                0x67 | 0x77 | 0x6f | 0x7f | 0x7b | 0x63 | 0x73 => {
-                    let data = self.ror(&opcode.mode);
-                    self.add_to_register_a(data);
-                }
+                let return_addr = self.stack_pop_u16();
+                self.jump(return_addr);
+            }
 
-                /* ISB */
-                0xe7 | 0xf7 | 0xef | 0xff | 0xfb | 0xe3 | 0xf3 => {
-                    let data = self.inc(&opcode.mode);
-                    self.sub_from_register_a(data);
-                }
+            /* BNE */
+            0xd0 => {
+                self.branch(!self.status.contains(CpuFlags::ZERO));
+            }
 
-                /* NOPs */
-                0x02 | 0x12 | 0x22 | 0x32 | 0x42 | 0x52 | 0x62 | 0x72 | 0x92 | 0xb2 | 0xd2
-                | 0xf2 => { /* do nothing */ }
+            /* BVS */
+            0x70 => {
+                self.branch(self.status.contains(CpuFlags::OVERFLOW));
+            }
 
-                0x1a | 0x3a | 0x5a | 0x7a | 0xda | 0xfa => { /* do nothing */ }
+            /* BVC */
+            0x50 => {
+                self.branch(!self.status.contains(CpuFlags::OVERFLOW));
+            }
 
-                /* LAX */
-                0xa7 | 0xb7 | 0xaf | 0xbf | 0xa3 | 0xb3 => {
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    let data = self.mem_read(addr);
-                    self.set_register_a(data);
-                    self.register_x = self.register_a;
-                }
+            /* BPL */
+            0x10 => {
+                self.branch(!self.status.contains(CpuFlags::NEGATIV));
+            }
 
-                /* SAX */
-                0x87 | 0x97 | 0x8f | 0x83 => {
-                    let data = self.register_a & self.register_x;
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    self.mem_write(addr, data);
-                }
+            /* BMI */
+            0x30 => {
+                self.branch(self.status.contains(CpuFlags::NEGATIV));
+            }
 
-                /* LXA */
-                0xab => {
-                    self.lda(&opcode.mode);
-                    self.tax();
-                }
+            /* BEQ */
+            0xf0 => {
+                self.branch(self.status.contains(CpuFlags::ZERO));
+            }
 
-                /* XAA */
-                0x8b => {
-                    self.register_a = self.register_x;
-                    self.update_zero_and_negative_flags(self.register_a);
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    let data = self.mem_read(addr);
-                    self.and_with_register_a(data);
-                }
+            /* BCS */
+            0xb0 => {
+                self.branch(self.status.contains(CpuFlags::CARRY));
+            }
 
-                /* LAS */
-                0xbb => {
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    let mut data = self.mem_read(addr);
-                    data = data & self.stack_pointer;
-                    self.register_a = data;
-                    self.register_x = data;
-                    self.stack_pointer = data;
-                    self.update_zero_and_negative_flags(data);
-                }
+            /* BCC */
+            0x90 => {
+                self.branch(!self.status.contains(CpuFlags::CARRY));
+            }
+
+            /* BIT */
+            0x24 | 0x2c => {
+                self.bit(&opcode.mode);
+            }
+
+            /* STA */
+            0x85 | 0x95 | 0x8d | 0x9d | 0x99 | 0x81 | 0x91 => {
+                self.sta(&opcode.mode);
+            }
+
+            /* STX */
+            0x86 | 0x96 | 0x8e => {
+                let addr = self.get_store_address(&opcode.mode);
+                self.mem_write(addr, self.register_x);
+            }
+
+            /* STY */
+            0x84 | 0x94 | 0x8c => {
+                let addr = self.get_store_address(&opcode.mode);
+                self.mem_write(addr, self.register_y);
+            }
+
+            /* LDX */
+            0xa2 | 0xa6 | 0xb6 | 0xae | 0xbe => {
+                self.ldx(&opcode.mode);
+            }
+
+            /* LDY */
+            0xa0 | 0xa4 | 0xb4 | 0xac | 0xbc => {
+                self.ldy(&opcode.mode);
+            }
+
+            /* NOP */
+            0xea => {
+                //do nothing
+            }
+
+            /* TAY */
+            0xa8 => {
+                self.register_y = self.register_a;
+                self.update_zero_and_negative_flags(self.register_y);
+            }
+
+            /* TSX */
+            0xba => {
+                self.register_x = self.stack_pointer;
+                self.update_zero_and_negative_flags(self.register_x);
+            }
+
+            /* TXA */
+            0x8a => {
+                self.register_a = self.register_x;
+                self.update_zero_and_negative_flags(self.register_a);
+            }
+
+            /* TXS */
+            0x9a => {
+                self.stack_pointer = self.register_x;
+            }
+
+            /* TYA */
+            0x98 => {
+                self.register_a = self.register_y;
+                self.update_zero_and_negative_flags(self.register_a);
+            }
+
+            /* unofficial */
+
+            /* DCP */
+            0xc7 | 0xd7 | 0xCF | 0xdF | 0xdb | 0xd3 | 0xc3 => {
+                let (addr, mut data) = self.read_modify(&opcode.mode);
+                data = data.wrapping_sub(1);
+                self.mem_write(addr, data);
+                self.status.set(CpuFlags::CARRY, data <= self.register_a);
+
+                self.update_zero_and_negative_flags(self.register_a.wrapping_sub(data));
+            }
+
+            /* RLA */
+            0x27 | 0x37 | 0x2F | 0x3F | 0x3b | 0x33 | 0x23 => {
+                let data = self.rol(&opcode.mode);
+                self.and_with_register_a(data);
+            }
+
+            /* SLO */
+            0x07 | 0x17 | 0x0F | 0x1f | 0x1b | 0x03 | 0x13 => {
+                let data = self.asl(&opcode.mode);
+                self.or_with_register_a(data);
+            }
+
+            /* SRE */
+            0x47 | 0x57 | 0x4F | 0x5f | 0x5b | 0x43 | 0x53 => {
+                let data = self.lsr(&opcode.mode);
+                self.xor_with_register_a(data);
+            }
+
+            /* SKB */
+            0x80 | 0x82 | 0x89 | 0xc2 | 0xe2 => {
+                /* 2 byte NOP (immediate ) */
+                self.mem_read(self.program_counter);
+            }
+
+            /* AXS */
+            0xCB => {
+                let addr = self.get_operand_address(&opcode.mode);
+                let data = self.mem_read(addr);
+                let x_and_a = self.register_x & self.register_a;
+                let result = x_and_a.wrapping_sub(data);
+
+                self.status.set(CpuFlags::CARRY, data <= x_and_a);
+                self.update_zero_and_negative_flags(result);
 
-                /* TAS */
-                0x9b => {
-                    let data = self.register_a & self.register_x;
-                    self.stack_pointer = data;
-                    let mem_address =
-                        self.mem_read_u16(self.program_counter) + self.register_y as u16;
+                self.register_x = result;
+            }
+
+            /* ARR */
+            0x6B => {
+                let addr = self.get_operand_address(&opcode.mode);
+                let data = self.mem_read(addr);
+                self.and_with_register_a(data);
+                self.ror_accumulator();
+                //todo: registers
+                let result = self.register_a;
+                let bit_5 = (result >> 5) & 1;
+                let bit_6 = (result >> 6) & 1;
 
-                    let data = ((mem_address >> 8) as u8 + 1) & self.stack_pointer;
-                    self.mem_write(mem_address, data)
+                if bit_6 == 1 {
+                    self.status.insert(CpuFlags::CARRY)
+                } else {
+                    self.status.remove(CpuFlags::CARRY)
                 }
 
-                /* AHX  Indirect Y */
-                0x93 => {
-                    let pos: u8 = self.mem_read(self.program_counter);
-                    let mem_address = self.mem_read_u16(pos as u16) + self.register_y as u16;
-                    let data = self.register_a & self.register_x & (mem_address >> 8) as u8;
-                    self.mem_write(mem_address, data)
+                if bit_5 ^ bit_6 == 1 {
+                    self.status.insert(CpuFlags::OVERFLOW);
+                } else {
+                    self.status.remove(CpuFlags::OVERFLOW);
                 }
 
-                /* AHX Absolute Y*/
-                0x9f => {
-                    let mem_address =
-                        self.mem_read_u16(self.program_counter) + self.register_y as u16;
+                self.update_zero_and_negative_flags(result);
+            }
+
+            /* unofficial SBC */
+            0xeb => {
+                let addr = self.get_operand_address(&opcode.mode);
+                let data = self.mem_read(addr);
+                self.sub_from_register_a(data);
+            }
 
-                    let data = self.register_a & self.register_x & (mem_address >> 8) as u8;
-                    self.mem_write(mem_address, data)
+            /* ANC */
+            0x0b | 0x2b => {
+                let addr = self.get_operand_address(&opcode.mode);
+                let data = self.mem_read(addr);
+                self.and_with_register_a(data);
+                if self.status.contains(CpuFlags::NEGATIV) {
+                    self.status.insert(CpuFlags::CARRY);
+                } else {
+                    self.status.remove(CpuFlags::CARRY);
                 }
+            }
 
-                /* SHX */
-                0x9e => {
-                    let mem_address =
-                        self.mem_read_u16(self.program_counter) + self.register_y as u16;
+            /* ALR */
+            0x4b => {
+                let addr = self.get_operand_address(&opcode.mode);
+                let data = self.mem_read(addr);
+                self.and_with_register_a(data);
+                self.lsr_accumulator();
+            }
 
-                    // todo if cross page boundry {
-                    //     mem_address &= (self.x as u16) << 8;
-                    // }
-                    let data = self.register_x & ((mem_address >> 8) as u8 + 1);
-                    self.mem_write(mem_address, data)
-                }
+            /* NOP read */
+            0x04 | 0x44 | 0x64 | 0x14 | 0x34 | 0x54 | 0x74 | 0xd4 | 0xf4 | 0x0c | 0x1c
+            | 0x3c | 0x5c | 0x7c | 0xdc | 0xfc => {
+                let addr = self.get_operand_address(&opcode.mode);
+                let _data = self.mem_read(addr);
+                /* do nothing */
+            }
 
-                /* SHY */
-                0x9c => {
-                    let mem_address =
-                        self.mem_read_u16(self.program_counter) + self.register_x as u16;
-                    let data = self.register_y & ((mem_address >> 8) as u8 + 1);
-                    self.mem_write(mem_address, data)
-                }
+            /* RRA */
+            0x67 | 0x77 | 0x6f | 0x7f | 0x7b | 0x63 | 0x73 => {
+                let data = self.ror(&opcode.mode);
+                self.add_to_register_a(data);
+            }
+
+            /* ISB */
+            0xe7 | 0xf7 | 0xef | 0xff | 0xfb | 0xe3 | 0xf3 => {
+                let data = self.inc(&opcode.mode);
+                self.sub_from_register_a(data);
+            }
+
+            /* JAM */
+            0x02 | 0x12 | 0x22 | 0x32 | 0x42 | 0x52 | 0x62 | 0x72 | 0x92 | 0xb2 | 0xd2
+            | 0xf2 => {
+                // the CPU locks up until reset, PC is left on the opcode
+                self.jump(self.program_counter.wrapping_sub(1));
+                self.halted = true;
+            }
+
+            0x1a | 0x3a | 0x5a | 0x7a | 0xda | 0xfa => { /* do nothing */ }
+
+            /* LAX */
+            0xa7 | 0xb7 | 0xaf | 0xbf | 0xa3 | 0xb3 => {
+                let addr = self.get_operand_address(&opcode.mode);
+                let data = self.mem_read(addr);
+                self.set_register_a(data);
+                self.register_x = self.register_a;
+            }
+
+            /* SAX */
+            0x87 | 0x97 | 0x8f | 0x83 => {
+                let data = self.register_a & self.register_x;
+                let addr = self.get_store_address(&opcode.mode);
+                self.mem_write(addr, data);
             }
 
-            self.bus.tick(opcode.cycles);
+            /* LXA */
+            0xab => {
+                let addr = self.get_operand_address(&opcode.mode);
+                let data = self.mem_read(addr);
+                self.set_register_a((self.register_a | UNSTABLE_MAGIC) & data);
+                self.register_x = self.register_a;
+            }
+
+            /* XAA (ANE) */
+            0x8b => {
+                let addr = self.get_operand_address(&opcode.mode);
+                let data = self.mem_read(addr);
+                self.set_register_a((self.register_a | UNSTABLE_MAGIC) & self.register_x & data);
+            }
+
+            /* LAS */
+            0xbb => {
+                let addr = self.get_operand_address(&opcode.mode);
+                let data = self.mem_read(addr) & self.stack_pointer;
+                self.register_a = data;
+                self.register_x = data;
+                self.stack_pointer = data;
+                self.update_zero_and_negative_flags(data);
+            }
 
-            if program_counter_state == self.program_counter {
-                self.program_counter += (opcode.len - 1) as u16;
+            /* TAS */
+            0x9b => {
+                self.stack_pointer = self.register_a & self.register_x;
+                self.store_and_high_byte(&opcode.mode, self.stack_pointer);
             }
+
+            /* AHX */
+            0x93 | 0x9f => {
+                self.store_and_high_byte(&opcode.mode, self.register_a & self.register_x);
+            }
+
+            /* SHX */
+            0x9e => self.store_and_high_byte(&opcode.mode, self.register_x),
+
+            /* SHY */
+            0x9c => self.store_and_high_byte(&opcode.mode, self.register_y),
         }
+
+        if !self.jumped {
+            self.program_counter = self.program_counter.wrapping_add((opcode.len - 1) as u16);
+        }
+
+        let interrupt_disable = match code {
+            /* CLI, SEI, PLP */ 0x58 | 0x78 | 0x28 => interrupt_disable,
+            _ => self.status.contains(CpuFlags::INTERRUPT_DISABLE),
+        };
+        self.irq_pending = self.bus.poll_irq_status() && !interrupt_disable;
+        code
     }
 }
 
 #[cfg(test)]
-mod test {
+pub mod test {
     use super::*;
+    use crate::bus::Bus;
+    use crate::bus::IrqSource;
     use crate::cartridge::test;
 
+    fn run_until_brk(cpu: &mut CPU<Bus>) {
+        cpu.run_until(|cpu| cpu.mem_read(cpu.program_counter) == 0x00);
+    }
+
+    // program at $8000, BRK/IRQ vector pointing to $9000
+    fn rom_with_irq_handler(program: Vec<u8>, handler: Vec<u8>) -> Vec<u8> {
+        let mut prg_rom = program;
+        prg_rom.resize(0x8000, 0);
+        prg_rom[0x1000..0x1000 + handler.len()].copy_from_slice(&handler);
+        prg_rom[0x7FFE] = 0x00;
+        prg_rom[0x7FFF] = 0x90;
+        prg_rom
+    }
+
     #[test]
     fn test_0xa9_lda_immediate_load_data() {
         let bus = Bus::new(test::test_rom_containing(vec![0xa9, 0x05, 0x00]), |_ppu, _joypad| {});
         let mut cpu = CPU::new(bus);
 
-        cpu.run();
+        run_until_brk(&mut cpu);
 
         assert_eq!(cpu.register_a, 5);
         assert!(cpu.status.bits() & 0b0000_0010 == 0b00);
@@ -1230,7 +1749,7 @@ mod test {
         let mut cpu = CPU::new(bus);
         cpu.register_a = 10;
 
-        cpu.run();
+        run_until_brk(&mut cpu);
 
         assert_eq!(cpu.register_x, 10)
     }
@@ -1240,7 +1759,7 @@ mod test {
         let bus = Bus::new(test::test_rom_containing(vec![0xa9, 0xc0, 0xaa, 0xe8, 0x00]), |_ppu, _joypad| {});
         let mut cpu = CPU::new(bus);
 
-        cpu.run();
+        run_until_brk(&mut cpu);
 
         assert_eq!(cpu.register_x, 0xc1)
     }
@@ -1251,7 +1770,7 @@ mod test {
         let mut cpu = CPU::new(bus);
         cpu.register_x = 0xff;
 
-        cpu.run();
+        run_until_brk(&mut cpu);
 
         assert_eq!(cpu.register_x, 1)
     }
@@ -1262,8 +1781,725 @@ mod test {
         let mut cpu = CPU::new(bus);
         cpu.mem_write(0x10, 0x55);
 
-        cpu.run();
+        run_until_brk(&mut cpu);
 
         assert_eq!(cpu.register_a, 0x55);
     }
+
+    #[test]
+    fn test_brk_pushes_pc_plus_2_with_b_flag() {
+        let program = rom_with_irq_handler(vec![0x00, 0xff, 0xe8], vec![0xa9, 0x42]);
+        let bus = Bus::new(test::test_rom_containing(program), |_ppu, _joypad| {});
+        let mut cpu = CPU::new(bus);
+
+        cpu.step();
+
+        assert_eq!(cpu.program_counter, 0x9000);
+        assert!(cpu.status.contains(CpuFlags::INTERRUPT_DISABLE));
+        assert_eq!(cpu.stack_pointer, STACK_RESET - 3);
+        let pushed_status = cpu.mem_read(STACK + STACK_RESET as u16 - 2);
+        assert_eq!(pushed_status, 0b0011_0100);
+        assert_eq!(cpu.mem_read_u16(STACK + STACK_RESET as u16 - 1), 0x8002);
+    }
+
+    #[test]
+    fn test_rti_returns_after_brk_padding_byte() {
+        // BRK, padding, INX
+        let program = rom_with_irq_handler(vec![0x00, 0xff, 0xe8], vec![0x40]);
+        let bus = Bus::new(test::test_rom_containing(program), |_ppu, _joypad| {});
+        let mut cpu = CPU::new(bus);
+
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.program_counter, 0x8002);
+        cpu.step();
+        assert_eq!(cpu.register_x, 1);
+    }
+
+    #[test]
+    fn test_sei_lets_a_pending_irq_through() {
+        // CLI, SEI, INX
+        let program = rom_with_irq_handler(vec![0x58, 0x78, 0xe8], vec![0xea]);
+        let bus = Bus::new(test::test_rom_containing(program), |_ppu, _joypad| {});
+        let mut cpu = CPU::new(bus);
+        cpu.bus.assert_irq(IrqSource::DMC);
+
+        cpu.step(); // CLI
+        cpu.step(); // SEI, the IRQ was already polled
+        cpu.step(); // IRQ, then NOP in the handler
+
+        assert_eq!(cpu.register_x, 0);
+        assert_eq!(cpu.program_counter, 0x9001);
+        assert_eq!(cpu.mem_read_u16(STACK + STACK_RESET as u16 - 1), 0x8002);
+        let pushed_status = cpu.mem_read(STACK + STACK_RESET as u16 - 2);
+        assert_eq!(pushed_status, 0b0010_0100);
+    }
+
+    #[test]
+    fn test_cli_delays_irq_by_one_instruction() {
+        // CLI, INX, INX
+        let program = rom_with_irq_handler(vec![0x58, 0xe8, 0xe8], vec![0xea, 0xea]);
+        let bus = Bus::new(test::test_rom_containing(program), |_ppu, _joypad| {});
+        let mut cpu = CPU::new(bus);
+        cpu.bus.assert_irq(IrqSource::FRAME_COUNTER);
+
+        cpu.step(); // CLI
+        cpu.step(); // INX runs before the IRQ is taken
+        assert_eq!(cpu.register_x, 1);
+        assert_eq!(cpu.program_counter, 0x8002);
+
+        cpu.step(); // IRQ, then NOP in the handler
+        assert_eq!(cpu.register_x, 1);
+        assert_eq!(cpu.program_counter, 0x9001);
+
+        // the handler runs with I set, the line is still asserted but masked
+        cpu.step();
+        assert_eq!(cpu.program_counter, 0x9002);
+    }
+
+    #[test]
+    fn test_step_reports_cycles_and_interrupts() {
+        // LDA #$05, BRK
+        let program = rom_with_irq_handler(vec![0xa9, 0x05, 0x00], vec![0xea]);
+        let bus = Bus::new(test::test_rom_containing(program), |_ppu, _joypad| {});
+        let mut cpu = CPU::new(bus);
+
+        let result = cpu.step();
+        assert_eq!(
+            result,
+            StepResult {
+                cycles: 2,
+                interrupt: None,
+                frame_complete: false
+            }
+        );
+
+        let result = cpu.step();
+        assert_eq!(result.cycles, 7);
+        assert_eq!(result.interrupt, Some(InterruptType::BRK));
+    }
+
+    #[test]
+    fn test_every_opcode_takes_its_listed_cycles() {
+        for opcode in opcodes::CPU_OPS_CODES {
+            // conditional branches are covered separately
+            if opcode.len == 2 && matches!(opcode.mode, AddressingMode::NoneAddressing) {
+                continue;
+            }
+            // operand $0210 (or $10 for zero page), the pointer at $10 points to $0200
+            let bus = Bus::new(
+                test::test_rom_containing(vec![opcode.code, 0x10, 0x02]),
+                |_ppu, _joypad| {},
+            );
+            let mut cpu = CPU::new(bus);
+            cpu.bus.mem_write_u16(0x10, 0x0200);
+
+            let result = cpu.step();
+            assert_eq!(
+                result.cycles, opcode.cycles as usize,
+                "{:02x} {}",
+                opcode.code, opcode.mnemonic
+            );
+        }
+    }
+
+    #[test]
+    fn test_page_crossing_costs_a_cycle_only_for_reads() {
+        // LDA $02F0,X, STA $02F0,X, INC $02F0,X
+        let bus = Bus::new(
+            test::test_rom_containing(vec![0xbd, 0xf0, 0x02, 0x9d, 0xf0, 0x02, 0xfe, 0xf0, 0x02]),
+            |_ppu, _joypad| {},
+        );
+        let mut cpu = CPU::new(bus);
+        cpu.register_x = 0x20;
+
+        assert_eq!(cpu.step().cycles, 5);
+        assert_eq!(cpu.step().cycles, 5);
+        assert_eq!(cpu.step().cycles, 7);
+        assert_eq!(cpu.bus.mem_read(0x0310), 0x01);
+    }
+
+    #[derive(Debug, PartialEq)]
+    pub enum Access {
+        Read(u16, u8),
+        Write(u16, u8),
+    }
+
+    // flat 64KB of RAM that records every access
+    pub struct MockBus {
+        pub memory: Vec<u8>,
+        pub cycles: usize,
+        pub log: Vec<Access>,
+    }
+
+    impl MockBus {
+        pub fn new(program: &[u8], start: u16) -> Self {
+            let mut memory = vec![0; 0x10000];
+            memory[start as usize..start as usize + program.len()].copy_from_slice(program);
+            MockBus {
+                memory,
+                cycles: 0,
+                log: vec![],
+            }
+        }
+    }
+
+    impl Mem for MockBus {
+        fn mem_read(&mut self, addr: u16) -> u8 {
+            let data = self.memory[addr as usize];
+            self.log.push(Access::Read(addr, data));
+            data
+        }
+
+        fn mem_write(&mut self, addr: u16, data: u8) {
+            self.log.push(Access::Write(addr, data));
+            self.memory[addr as usize] = data;
+        }
+
+        fn peek(&self, addr: u16) -> u8 {
+            self.memory[addr as usize]
+        }
+    }
+
+    impl Clock for MockBus {
+        fn tick(&mut self, cycles: u8) {
+            self.cycles += cycles as usize;
+        }
+
+        fn cycles(&self) -> usize {
+            self.cycles
+        }
+    }
+
+    #[test]
+    fn test_indexed_store_reads_the_unfixed_address_first() {
+        // LDA #$55, STA $02F0,X
+        let mut cpu = CPU::new(MockBus::new(&[0xa9, 0x55, 0x9d, 0xf0, 0x02], 0x0600));
+        cpu.program_counter = 0x0600;
+        cpu.register_x = 0x20;
+        cpu.step();
+        cpu.bus.log.clear();
+
+        assert_eq!(cpu.step().cycles, 5);
+        assert_eq!(
+            cpu.bus.log,
+            vec![
+                Access::Read(0x0602, 0x9d),
+                Access::Read(0x0603, 0xf0),
+                Access::Read(0x0604, 0x02),
+                Access::Read(0x0210, 0x00),
+                Access::Write(0x0310, 0x55),
+            ]
+        );
+        assert_eq!(cpu.bus.cycles(), 7);
+    }
+
+    #[test]
+    fn test_65c02_decimal_adc_reads_the_operand_again() {
+        // ADC $10
+        let mut cpu = CPU::with_variant(MockBus::new(&[0x65, 0x10], 0x0600), Variant::Cmos65C02);
+        cpu.program_counter = 0x0600;
+        cpu.status.insert(CpuFlags::DECIMAL_MODE);
+        cpu.bus.memory[0x10] = 0x01;
+
+        assert_eq!(cpu.step().cycles, 4);
+        assert_eq!(
+            cpu.bus.log,
+            vec![
+                Access::Read(0x0600, 0x65),
+                Access::Read(0x0601, 0x10),
+                Access::Read(0x0010, 0x01),
+                Access::Read(0x0010, 0x01),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_branch_cycles() {
+        // BNE +0, BEQ +0, then at $80F0: BNE +16 (crosses into $8102)
+        let mut program = vec![0xd0, 0x00, 0xf0, 0x00];
+        program.resize(0xf0, 0xea);
+        program.extend(vec![0xd0, 0x10]);
+        let bus = Bus::new(test::test_rom_containing(program), |_ppu, _joypad| {});
+        let mut cpu = CPU::new(bus);
+
+        assert_eq!(cpu.step().cycles, 3);
+        assert_eq!(cpu.step().cycles, 2);
+        cpu.program_counter = 0x80f0;
+        assert_eq!(cpu.step().cycles, 4);
+        assert_eq!(cpu.program_counter, 0x8102);
+    }
+
+    #[test]
+    fn test_branch_back_onto_its_operand() {
+        // BNE -1, lands on its own offset byte
+        let mut cpu = CPU::new(MockBus::new(&[0xd0, 0xff], 0x0200));
+        cpu.program_counter = 0x0200;
+
+        assert_eq!(cpu.step().cycles, 3);
+        assert_eq!(cpu.program_counter, 0x0201);
+    }
+
+    #[test]
+    fn test_program_counter_wraps_at_ffff() {
+        // NOP
+        let mut cpu = CPU::new(MockBus::new(&[0xea], 0xffff));
+        cpu.program_counter = 0xffff;
+        cpu.step();
+        assert_eq!(cpu.program_counter, 0x0000);
+
+        // LDA $0010
+        let mut cpu = CPU::new(MockBus::new(&[0xad, 0x10, 0x00], 0xfffd));
+        cpu.program_counter = 0xfffd;
+        cpu.bus.memory[0x10] = 0x42;
+        cpu.step();
+        assert_eq!(cpu.register_a, 0x42);
+        assert_eq!(cpu.program_counter, 0x0000);
+
+        // JSR $0300, pushes $FFFF
+        let mut cpu = CPU::new(MockBus::new(&[0x20, 0x00, 0x03], 0xfffd));
+        cpu.program_counter = 0xfffd;
+        cpu.stack_pointer = 0xfd;
+        cpu.step();
+        assert_eq!(cpu.program_counter, 0x0300);
+        assert_eq!(cpu.bus.memory[0x1fc..0x1fe], [0xff, 0xff]);
+
+        // RTS to $FFFF
+        let mut cpu = CPU::new(MockBus::new(&[0x60], 0x0200));
+        cpu.program_counter = 0x0200;
+        cpu.stack_pointer = 0xfb;
+        cpu.bus.memory[0x1fc] = 0xff;
+        cpu.bus.memory[0x1fd] = 0xff;
+        cpu.step();
+        assert_eq!(cpu.program_counter, 0x0000);
+    }
+
+    #[test]
+    fn test_u16_access_wraps_at_ffff() {
+        let mut bus = MockBus::new(&[], 0);
+        bus.mem_write_u16(0xffff, 0x1234);
+        assert_eq!(bus.memory[0xffff], 0x34);
+        assert_eq!(bus.memory[0x0000], 0x12);
+        assert_eq!(bus.mem_read_u16(0xffff), 0x1234);
+    }
+
+    #[test]
+    fn test_irq_takes_seven_cycles() {
+        // CLI, NOP
+        let program = rom_with_irq_handler(vec![0x58, 0xea], vec![0xea]);
+        let bus = Bus::new(test::test_rom_containing(program), |_ppu, _joypad| {});
+        let mut cpu = CPU::new(bus);
+        cpu.bus.assert_irq(IrqSource::DMC);
+
+        cpu.step(); // CLI
+        cpu.step(); // NOP
+        let result = cpu.step(); // IRQ, then NOP in the handler
+        assert_eq!(result.interrupt, Some(InterruptType::IRQ));
+        assert_eq!(result.cycles, 7 + 2);
+    }
+
+    #[test]
+    fn test_slo_shifts_memory_and_ors_into_a() {
+        // SLO $10
+        let bus = Bus::new(test::test_rom_containing(vec![0x07, 0x10]), |_ppu, _joypad| {});
+        let mut cpu = CPU::new(bus);
+        cpu.bus.mem_write(0x10, 0b1100_0001);
+        cpu.register_a = 0b0000_0001;
+
+        cpu.step();
+
+        assert_eq!(cpu.bus.mem_read(0x10), 0b1000_0010);
+        assert_eq!(cpu.register_a, 0b1000_0011);
+        assert!(cpu.status.contains(CpuFlags::CARRY));
+        assert!(cpu.status.contains(CpuFlags::NEGATIV));
+    }
+
+    #[test]
+    fn test_sre_shifts_memory_and_eors_into_a() {
+        // SRE $10
+        let bus = Bus::new(test::test_rom_containing(vec![0x47, 0x10]), |_ppu, _joypad| {});
+        let mut cpu = CPU::new(bus);
+        cpu.bus.mem_write(0x10, 0b0000_0011);
+        cpu.register_a = 0b0000_0001;
+
+        cpu.step();
+
+        assert_eq!(cpu.bus.mem_read(0x10), 0b0000_0001);
+        assert_eq!(cpu.register_a, 0);
+        assert!(cpu.status.contains(CpuFlags::CARRY));
+        assert!(cpu.status.contains(CpuFlags::ZERO));
+    }
+
+    #[test]
+    fn test_dcp_clears_carry() {
+        // SEC, DCP $10
+        let bus = Bus::new(test::test_rom_containing(vec![0x38, 0xc7, 0x10]), |_ppu, _joypad| {});
+        let mut cpu = CPU::new(bus);
+        cpu.bus.mem_write(0x10, 0x06);
+        cpu.register_a = 0x04;
+
+        cpu.step();
+        cpu.step();
+
+        assert_eq!(cpu.bus.mem_read(0x10), 0x05);
+        assert!(!cpu.status.contains(CpuFlags::CARRY));
+        assert!(cpu.status.contains(CpuFlags::NEGATIV));
+    }
+
+    #[test]
+    fn test_lxa_and_xaa_use_magic_constant() {
+        // LXA #$ff, XAA #$0f
+        let bus = Bus::new(test::test_rom_containing(vec![0xab, 0xff, 0x8b, 0x0f]), |_ppu, _joypad| {});
+        let mut cpu = CPU::new(bus);
+        cpu.register_a = 0x01;
+
+        cpu.step();
+        assert_eq!(cpu.register_a, 0xef);
+        assert_eq!(cpu.register_x, 0xef);
+
+        cpu.register_x = 0x3c;
+        cpu.step();
+        assert_eq!(cpu.register_a, 0x0c);
+    }
+
+    #[test]
+    fn test_las_ands_memory_with_stack_pointer() {
+        // LAS $0200,Y
+        let bus = Bus::new(test::test_rom_containing(vec![0xbb, 0x00, 0x02]), |_ppu, _joypad| {});
+        let mut cpu = CPU::new(bus);
+        cpu.bus.mem_write(0x0205, 0b1010_1111);
+        cpu.register_y = 5;
+
+        cpu.step();
+
+        let expected = 0b1010_1111 & STACK_RESET;
+        assert_eq!(cpu.register_a, expected);
+        assert_eq!(cpu.register_x, expected);
+        assert_eq!(cpu.stack_pointer, expected);
+    }
+
+    #[test]
+    fn test_shx_shy_and_with_high_byte_plus_one() {
+        // SHX $0200,Y, SHY $0200,X
+        let bus = Bus::new(
+            test::test_rom_containing(vec![0x9e, 0x00, 0x02, 0x9c, 0x00, 0x02]),
+            |_ppu, _joypad| {},
+        );
+        let mut cpu = CPU::new(bus);
+        cpu.register_x = 0xff;
+        cpu.register_y = 0x07;
+
+        // X & ($02 + 1)
+        cpu.step();
+        assert_eq!(cpu.bus.mem_read(0x0207), 0x03);
+
+        // $0200 + $FF stays on the page
+        cpu.step();
+        assert_eq!(cpu.bus.mem_read(0x02ff), 0x07 & 0x03);
+    }
+
+    #[test]
+    fn test_sh_page_cross_replaces_address_high_byte() {
+        // AHX $02F0,Y with A & X = 0x05 stores 0x05 & 0x03 at $0100 | 0x10
+        let bus = Bus::new(test::test_rom_containing(vec![0x9f, 0xf0, 0x02]), |_ppu, _joypad| {});
+        let mut cpu = CPU::new(bus);
+        cpu.register_a = 0x0f;
+        cpu.register_x = 0x05;
+        cpu.register_y = 0x20;
+
+        cpu.step();
+
+        assert_eq!(cpu.bus.mem_read(0x0110), 0x01);
+        assert_eq!(cpu.bus.mem_read(0x0310), 0x00);
+    }
+
+    #[test]
+    fn test_tas_sets_stack_pointer() {
+        // TAS $0200,Y
+        let bus = Bus::new(test::test_rom_containing(vec![0x9b, 0x00, 0x02]), |_ppu, _joypad| {});
+        let mut cpu = CPU::new(bus);
+        cpu.register_a = 0b1111_0110;
+        cpu.register_x = 0b0011_1111;
+
+        cpu.step();
+
+        assert_eq!(cpu.stack_pointer, 0b0011_0110);
+        assert_eq!(cpu.bus.mem_read(0x0200), 0b0000_0010);
+    }
+
+    #[test]
+    fn test_jam_halts_the_cpu() {
+        // INX, JAM, INX
+        let bus = Bus::new(test::test_rom_containing(vec![0xe8, 0x02, 0xe8]), |_ppu, _joypad| {});
+        let mut cpu = CPU::new(bus);
+
+        cpu.step();
+        cpu.step();
+        assert!(cpu.is_halted());
+        assert_eq!(cpu.program_counter, 0x8001);
+
+        // the rest of the console keeps going
+        cpu.run_frame();
+        assert_eq!(cpu.register_x, 1);
+        assert_eq!(cpu.program_counter, 0x8001);
+
+        // returns instead of spinning on the jammed opcode
+        cpu.run();
+
+        cpu.reset();
+        assert!(!cpu.is_halted());
+    }
+
+    fn cpu_with_variant(program: Vec<u8>, variant: Variant) -> CPU<Bus<'static>> {
+        let bus = Bus::new(test::test_rom_containing(program), |_ppu, _joypad| {});
+        CPU::with_variant(bus, variant)
+    }
+
+    #[test]
+    fn test_2a03_ignores_decimal_flag() {
+        // SED, CLC, LDA #$15, ADC #$27
+        let program = vec![0xf8, 0x18, 0xa9, 0x15, 0x69, 0x27];
+        let mut cpu = cpu_with_variant(program, Variant::Ricoh2A03);
+        for _ in 0..4 {
+            cpu.step();
+        }
+        assert_eq!(cpu.register_a, 0x3c);
+    }
+
+    #[test]
+    fn test_nmos_decimal_arithmetic() {
+        // SED, CLC, LDA #$15, ADC #$27, SEC, SBC #$29
+        let program = vec![0xf8, 0x18, 0xa9, 0x15, 0x69, 0x27, 0x38, 0xe9, 0x29];
+        let mut cpu = cpu_with_variant(program, Variant::Nmos6502);
+        for _ in 0..4 {
+            cpu.step();
+        }
+        assert_eq!(cpu.register_a, 0x42);
+        assert!(!cpu.status.contains(CpuFlags::CARRY));
+
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.register_a, 0x13);
+        assert!(cpu.status.contains(CpuFlags::CARRY));
+    }
+
+    #[test]
+    fn test_decimal_zero_flag_differs_between_nmos_and_cmos() {
+        // SED, CLC, LDA #$99, ADC #$01
+        let program = vec![0xf8, 0x18, 0xa9, 0x99, 0x69, 0x01];
+
+        let mut nmos = cpu_with_variant(program.clone(), Variant::Nmos6502);
+        let mut cmos = cpu_with_variant(program, Variant::Cmos65C02);
+        for _ in 0..3 {
+            nmos.step();
+            cmos.step();
+        }
+        assert_eq!(nmos.step().cycles, 2);
+        assert_eq!(cmos.step().cycles, 3);
+
+        for cpu in [&nmos, &cmos].iter() {
+            assert_eq!(cpu.register_a, 0x00);
+            assert!(cpu.status.contains(CpuFlags::CARRY));
+        }
+        // NMOS takes Z from the binary sum 0x9A
+        assert!(!nmos.status.contains(CpuFlags::ZERO));
+        assert!(cmos.status.contains(CpuFlags::ZERO));
+    }
+
+    #[test]
+    fn test_65c02_stack_and_store_opcodes() {
+        // LDX #$42, PHX, PLY, STZ $10, LDA #$0f, TSB $11, TRB $12
+        let program = vec![
+            0xa2, 0x42, 0xda, 0x7a, 0x64, 0x10, 0xa9, 0x0f, 0x04, 0x11, 0x14, 0x12,
+        ];
+        let mut cpu = cpu_with_variant(program, Variant::Cmos65C02);
+        cpu.bus.mem_write(0x10, 0xff);
+        cpu.bus.mem_write(0x11, 0xf0);
+        cpu.bus.mem_write(0x12, 0xff);
+
+        for _ in 0..7 {
+            cpu.step();
+        }
+        assert_eq!(cpu.register_y, 0x42);
+        assert_eq!(cpu.bus.mem_read(0x10), 0x00);
+        assert_eq!(cpu.bus.mem_read(0x11), 0xff);
+        assert_eq!(cpu.bus.mem_read(0x12), 0xf0);
+        assert!(!cpu.status.contains(CpuFlags::ZERO));
+    }
+
+    #[test]
+    fn test_65c02_zero_page_indirect_and_bit_branches() {
+        // LDA ($10), SMB3 $20, BBS3 $20 +2, BRK, BRK, RMB3 $20, BBR3 $20 -6
+        let program = vec![
+            0xb2, 0x10, 0xb7, 0x20, 0xbf, 0x20, 0x02, 0x00, 0x00, 0x37, 0x20, 0x3f, 0x20,
+            0xfa,
+        ];
+        let mut cpu = cpu_with_variant(program, Variant::Cmos65C02);
+        cpu.bus.mem_write_u16(0x10, 0x0300);
+        cpu.bus.mem_write(0x0300, 0x99);
+
+        cpu.step();
+        assert_eq!(cpu.register_a, 0x99);
+
+        cpu.step();
+        assert_eq!(cpu.bus.mem_read(0x20), 0b1000);
+        cpu.step();
+        assert_eq!(cpu.program_counter, 0x8009);
+
+        cpu.step();
+        assert_eq!(cpu.bus.mem_read(0x20), 0);
+        cpu.step();
+        assert_eq!(cpu.program_counter, 0x8008);
+    }
+
+    #[test]
+    fn test_65c02_jmp_indirect_does_not_wrap() {
+        // JMP ($02FF)
+        let mut cpu = cpu_with_variant(vec![0x6c, 0xff, 0x02], Variant::Cmos65C02);
+        cpu.bus.mem_write(0x02ff, 0x34);
+        cpu.bus.mem_write(0x0300, 0x12);
+        cpu.bus.mem_write(0x0200, 0x56);
+
+        assert_eq!(cpu.step().cycles, 6);
+        assert_eq!(cpu.program_counter, 0x1234);
+    }
+
+    #[test]
+    fn test_65c02_replaces_unofficial_opcodes() {
+        // NOP (1 cycle), BRA +1, JAM on NMOS, INC A
+        let mut cpu = cpu_with_variant(vec![0x03, 0x80, 0x01, 0x02, 0x1a], Variant::Cmos65C02);
+
+        assert_eq!(cpu.step().cycles, 1);
+        assert_eq!(cpu.step().cycles, 3);
+        assert_eq!(cpu.program_counter, 0x8004);
+        cpu.step();
+        assert_eq!(cpu.register_a, 1);
+        assert!(!cpu.is_halted());
+    }
+
+    #[test]
+    fn test_65c02_brk_clears_decimal_flag() {
+        // SED, BRK
+        let program = rom_with_irq_handler(vec![0xf8, 0x00], vec![0xea]);
+        let mut cpu = cpu_with_variant(program, Variant::Cmos65C02);
+
+        cpu.step();
+        cpu.step();
+        assert!(!cpu.status.contains(CpuFlags::DECIMAL_MODE));
+    }
+
+    #[test]
+    fn test_65c02_wai_waits_for_irq() {
+        // CLI, WAI
+        let program = rom_with_irq_handler(vec![0x58, 0xcb], vec![0xea]);
+        let mut cpu = cpu_with_variant(program, Variant::Cmos65C02);
+
+        cpu.step();
+        cpu.step();
+        for _ in 0..10 {
+            assert_eq!(cpu.step().cycles, 1);
+        }
+        assert_eq!(cpu.program_counter, 0x8002);
+
+        cpu.bus.assert_irq(IrqSource::DMC);
+        let result = cpu.step();
+        assert_eq!(result.interrupt, Some(InterruptType::IRQ));
+        assert_eq!(cpu.program_counter, 0x9000);
+    }
+
+    #[test]
+    fn test_run_frame() {
+        // JMP $8000
+        let bus = Bus::new(test::test_rom_containing(vec![0x4c, 0x00, 0x80]), |_ppu, _joypad| {});
+        let mut cpu = CPU::new(bus);
+
+        cpu.run_frame();
+        let first = cpu.bus.cycles();
+        cpu.run_frame();
+
+        // 262 scanlines * 341 dots / 3 dots per CPU cycle, give or take an instruction
+        let frame = cpu.bus.cycles() - first;
+        assert!((29780..=29784).contains(&frame), "{}", frame);
+    }
+
+    // cargo test --release bench_headless_frame_loop -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_headless_frame_loop() {
+        // LDA #$01, ADC #$02, STA $10, INC $10, INX, BNE -11, JMP $8000
+        let program = vec![
+            0xa9, 0x01, 0x69, 0x02, 0x85, 0x10, 0xe6, 0x10, 0xe8, 0xd0, 0xf5, 0x4c, 0x00, 0x80,
+        ];
+        let bus = Bus::new(test::test_rom_containing(program), |_ppu, _joypad| {});
+        let mut cpu = CPU::new(bus);
+
+        let frames = 3000;
+        let start = std::time::Instant::now();
+        for _ in 0..frames {
+            cpu.run_frame();
+        }
+        let elapsed = start.elapsed();
+
+        println!(
+            "{} frames in {:?}, {:.0} frames/s",
+            frames,
+            elapsed,
+            frames as f64 / elapsed.as_secs_f64()
+        );
+    }
+
+    // the decode step on its own: the HashMap lookup and the expect message it formatted
+    // on every instruction, against the 256-entry table
+    // cargo test --release bench_decode_hashmap_vs_table -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_decode_hashmap_vs_table() {
+        use std::collections::HashMap;
+        use std::hint::black_box;
+
+        let map: HashMap<u8, &opcodes::OpCode> = opcodes::CPU_OPS_CODES
+            .iter()
+            .map(|op| (op.code, op))
+            .collect();
+        // the opcode stream of nestest's code, decoded back to back
+        let rom = std::fs::read("nestest.nes").unwrap();
+        let codes: Vec<u8> = rom[16..16 + 0x4000]
+            .iter()
+            .copied()
+            .filter(|code| map.contains_key(code))
+            .collect();
+        let rounds = 1000;
+
+        let start = std::time::Instant::now();
+        for _ in 0..rounds {
+            for &code in &codes {
+                #[allow(clippy::expect_fun_call)]
+                let op = map
+                    .get(black_box(&code))
+                    .expect(&format!("OpCode {:x} is not recognized", code));
+                black_box(op);
+            }
+        }
+        let hashmap = start.elapsed();
+
+        let start = std::time::Instant::now();
+        for _ in 0..rounds {
+            for &code in &codes {
+                let op = match &opcodes::OPCODES[black_box(code) as usize] {
+                    Some(op) => op,
+                    None => panic!("OpCode {:x} is not recognized", code),
+                };
+                black_box(op);
+            }
+        }
+        let table = start.elapsed();
+
+        let decodes = (rounds * codes.len()) as f64;
+        println!(
+            "HashMap: {:.1} ns/decode, table: {:.1} ns/decode, {:.0}x",
+            hashmap.as_nanos() as f64 / decodes,
+            table.as_nanos() as f64 / decodes,
+            hashmap.as_secs_f64() / table.as_secs_f64()
+        );
+    }
 }
@@ -0,0 +1,428 @@
+use crate::cpu::AddressingMode;
+use crate::opcodes;
+use crate::opcodes::OpCode;
+use std::collections::BTreeMap;
+use std::fmt;
+
+// https://www.nesdev.org/wiki/CPU_memory_map
+const VECTORS: u16 = 0xfffa;
+const VECTOR_NAMES: [&str; 3] = ["nmi", "reset", "irq"];
+
+// Static disassembly of a PRG bank mapped at `origin`.
+//
+// Code is found by following the control flow from the interrupt vectors,
+// bytes that are never reached that way are data. The listing is ca65 source
+// that assembles back to the same bytes.
+pub struct Disassembly<'a> {
+    prg: &'a [u8],
+    origin: u16,
+    // the byte belongs to a traced instruction
+    code: Vec<bool>,
+    // a traced instruction starts at the byte
+    starts: Vec<bool>,
+    labels: BTreeMap<u16, String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum DisasmError {
+    EmptyPrg,
+    OutOfAddressSpace { origin: u16, len: usize },
+}
+
+impl fmt::Display for DisasmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DisasmError::EmptyPrg => write!(f, "PRG is empty"),
+            DisasmError::OutOfAddressSpace { origin, len } => write!(
+                f,
+                "${:04X} + {} bytes doesn't fit in the address space",
+                origin, len
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DisasmError {}
+
+pub fn disassemble(prg: &[u8], origin: u16) -> Result<Disassembly<'_>, DisasmError> {
+    if prg.is_empty() {
+        return Err(DisasmError::EmptyPrg);
+    }
+    if origin as usize + prg.len() > 0x10000 {
+        return Err(DisasmError::OutOfAddressSpace {
+            origin,
+            len: prg.len(),
+        });
+    }
+
+    let mut disasm = Disassembly {
+        prg,
+        origin,
+        code: vec![false; prg.len()],
+        starts: vec![false; prg.len()],
+        labels: BTreeMap::new(),
+    };
+
+    let mut pending = vec![];
+    for (n, name) in VECTOR_NAMES.iter().enumerate() {
+        if let Some(target) = disasm.word(VECTORS + 2 * n as u16) {
+            if disasm.contains(target) {
+                disasm
+                    .labels
+                    .entry(target)
+                    .or_insert_with(|| name.to_string());
+                pending.push(target);
+            }
+        }
+    }
+    while let Some(addr) = pending.pop() {
+        disasm.trace_from(addr, &mut pending);
+    }
+    Ok(disasm)
+}
+
+fn is_branch(op: &OpCode) -> bool {
+    op.len == 2 && matches!(op.mode, AddressingMode::NoneAddressing)
+}
+
+impl<'a> Disassembly<'a> {
+    fn contains(&self, addr: u16) -> bool {
+        addr >= self.origin && ((addr - self.origin) as usize) < self.prg.len()
+    }
+
+    fn index(&self, addr: u16) -> usize {
+        (addr - self.origin) as usize
+    }
+
+    fn byte(&self, addr: u16) -> Option<u8> {
+        if self.contains(addr) {
+            Some(self.prg[self.index(addr)])
+        } else {
+            None
+        }
+    }
+
+    fn word(&self, addr: u16) -> Option<u16> {
+        let lo = self.byte(addr)?;
+        let hi = self.byte(addr.wrapping_add(1))?;
+        Some((hi as u16) << 8 | lo as u16)
+    }
+
+    pub fn is_code(&self, addr: u16) -> bool {
+        self.contains(addr) && self.code[self.index(addr)]
+    }
+
+    pub fn label(&self, addr: u16) -> Option<&str> {
+        self.labels.get(&addr).map(|label| label.as_str())
+    }
+
+    // documented opcodes only, a path that runs into anything else is taken to be data
+    fn decode(&self, addr: u16) -> Option<&'static OpCode> {
+        let op = opcodes::OPCODES[self.byte(addr)? as usize].as_ref()?;
+        if op.mnemonic.starts_with('*') {
+            return None;
+        }
+        let last = addr.checked_add(op.len as u16 - 1)?;
+        if !self.contains(last) {
+            return None;
+        }
+        let index = self.index(addr);
+        if self.code[index..index + op.len as usize]
+            .iter()
+            .any(|&code| code)
+        {
+            return None;
+        }
+        Some(op)
+    }
+
+    fn add_target(&mut self, target: u16, pending: &mut Vec<u16>) {
+        if self.contains(target) {
+            self.labels
+                .entry(target)
+                .or_insert_with(|| format!("L{:04X}", target));
+            pending.push(target);
+        }
+    }
+
+    // follows one path until it leaves the bank, returns or joins already traced code
+    fn trace_from(&mut self, start: u16, pending: &mut Vec<u16>) {
+        let mut addr = start;
+        while let Some(op) = self.decode(addr) {
+            let index = self.index(addr);
+            self.starts[index] = true;
+            for code in self.code[index..index + op.len as usize].iter_mut() {
+                *code = true;
+            }
+
+            match op.code {
+                // JMP abs
+                0x4c => {
+                    if let Some(target) = self.word(addr + 1) {
+                        self.add_target(target, pending);
+                    }
+                    return;
+                }
+                // JSR
+                0x20 => {
+                    if let Some(target) = self.word(addr + 1) {
+                        self.add_target(target, pending);
+                    }
+                }
+                // JMP (ind), RTS, RTI, BRK
+                0x6c | 0x60 | 0x40 | 0x00 => return,
+                _ if is_branch(op) => {
+                    let offset = self.byte(addr + 1).unwrap() as i8;
+                    let target = addr.wrapping_add(2).wrapping_add(offset as u16);
+                    self.add_target(target, pending);
+                }
+                _ => {}
+            }
+
+            addr = match addr.checked_add(op.len as u16) {
+                Some(next) => next,
+                None => return,
+            };
+        }
+    }
+
+    fn address(&self, addr: u16) -> String {
+        match self.label(addr) {
+            Some(label) => label.to_string(),
+            None => format!("${:04X}", addr),
+        }
+    }
+
+    // ca65 picks zero page encodings on its own, absolute ones below $100 are forced with a:
+    fn absolute(&self, addr: u16) -> String {
+        if addr < 0x100 {
+            format!("a:{}", self.address(addr))
+        } else {
+            self.address(addr)
+        }
+    }
+
+    fn operand(&self, op: &OpCode, addr: u16) -> String {
+        let arg = self.byte(addr + 1).unwrap_or(0);
+        let word = self.word(addr + 1).unwrap_or(0);
+        match op.mode {
+            AddressingMode::Immediate => format!("#${:02X}", arg),
+            AddressingMode::ZeroPage => format!("${:02X}", arg),
+            AddressingMode::ZeroPage_X => format!("${:02X},x", arg),
+            AddressingMode::ZeroPage_Y => format!("${:02X},y", arg),
+            AddressingMode::Absolute => self.absolute(word),
+            AddressingMode::Absolute_X => format!("{},x", self.absolute(word)),
+            AddressingMode::Absolute_Y => format!("{},y", self.absolute(word)),
+            AddressingMode::Indirect_X => format!("(${:02X},x)", arg),
+            AddressingMode::Indirect_Y => format!("(${:02X}),y", arg),
+            AddressingMode::NoneAddressing => match op.code {
+                0x0a | 0x4a | 0x2a | 0x6a => String::from("a"),
+                0x6c => format!("({})", self.address(word)),
+                _ if is_branch(op) => {
+                    let target = addr.wrapping_add(2).wrapping_add(arg as i8 as u16);
+                    self.address(target)
+                }
+                _ if op.len == 3 => self.absolute(word),
+                _ => String::new(),
+            },
+            _ => panic!(
+                "unexpected addressing mode {:?} for {:02x}",
+                op.mode, op.code
+            ),
+        }
+    }
+
+    fn vector_table_at(&self, addr: u16) -> bool {
+        addr == VECTORS
+            && self.contains(0xffff)
+            && (0..6).all(|n| !self.code[self.index(VECTORS + n)])
+            && (1..6).all(|n| self.label(VECTORS + n).is_none())
+    }
+
+    pub fn listing(&self) -> String {
+        let mut out = vec![
+            format!(
+                "; PRG ${:04X}-${:04X}",
+                self.origin,
+                self.origin as usize + self.prg.len() - 1
+            ),
+            String::from(".setcpu \"6502\""),
+        ];
+        // labels that point inside another instruction can't be placed in the listing
+        for (addr, label) in self.labels.iter() {
+            if self.code[self.index(*addr)] && !self.starts[self.index(*addr)] {
+                out.push(format!("{} = ${:04X}", label, addr));
+            }
+        }
+        out.push(format!(".org ${:04X}", self.origin));
+        out.push(String::new());
+
+        let mut index = 0;
+        while index < self.prg.len() {
+            let addr = self.origin + index as u16;
+            if let Some(label) = self.label(addr) {
+                if self.starts[index] || !self.code[index] {
+                    out.push(format!("{}:", label));
+                }
+            }
+
+            if self.starts[index] {
+                let op = self.decode_traced(addr);
+                let text = format!(
+                    "{} {}",
+                    op.mnemonic.to_ascii_lowercase(),
+                    self.operand(op, addr)
+                );
+                out.push(format!("    {:<24}; {:04X}", text.trim_end(), addr));
+                index += op.len as usize;
+            } else if self.vector_table_at(addr) {
+                let vectors: Vec<String> = (0..3)
+                    .map(|n| self.address(self.word(VECTORS + 2 * n).unwrap()))
+                    .collect();
+                out.push(format!("    .word {}", vectors.join(", ")));
+                index += 6;
+            } else {
+                let mut bytes = vec![];
+                loop {
+                    bytes.push(format!("${:02X}", self.prg[index]));
+                    index += 1;
+                    let next = self.origin.wrapping_add(index as u16);
+                    if index >= self.prg.len()
+                        || bytes.len() == 16
+                        || self.code[index]
+                        || self.label(next).is_some()
+                        || self.vector_table_at(next)
+                    {
+                        break;
+                    }
+                }
+                out.push(format!("    .byte {}", bytes.join(", ")));
+            }
+        }
+        out.push(String::new());
+        out.join("\n")
+    }
+
+    fn decode_traced(&self, addr: u16) -> &'static OpCode {
+        opcodes::OPCODES[self.prg[self.index(addr)] as usize]
+            .as_ref()
+            .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // 16KB bank at $C000 with the program at the start and the vectors at the end
+    fn bank(program: &[u8], nmi: u16, reset: u16, irq: u16) -> Vec<u8> {
+        let mut prg = vec![0xff; 0x4000];
+        prg[..program.len()].copy_from_slice(program);
+        for (n, vector) in [nmi, reset, irq].iter().enumerate() {
+            prg[0x3ffa + 2 * n] = (*vector & 0xff) as u8;
+            prg[0x3ffb + 2 * n] = (*vector >> 8) as u8;
+        }
+        prg
+    }
+
+    #[test]
+    fn test_code_is_traced_from_the_vectors() {
+        let prg = bank(
+            &[
+                0x78, // C000 reset: SEI
+                0x20, 0x09, 0xc0, // C001 JSR $C009
+                0xd0, 0xfb, // C004 BNE $C001
+                0x4c, 0x04, 0xc0, // C006 JMP $C004
+                0x60, // C009 RTS
+                0x01, 0x02, // C00A data
+                0x40, // C00C nmi: RTI
+            ],
+            0xc00c,
+            0xc000,
+            0x0000,
+        );
+        let disasm = disassemble(&prg, 0xc000).unwrap();
+
+        assert!(disasm.is_code(0xc000));
+        assert!(disasm.is_code(0xc009));
+        assert!(!disasm.is_code(0xc00a));
+        assert!(!disasm.is_code(0xc00b));
+        assert!(disasm.is_code(0xc00c));
+        assert_eq!(disasm.label(0xc000), Some("reset"));
+        assert_eq!(disasm.label(0xc001), Some("LC001"));
+        assert_eq!(disasm.label(0xc009), Some("LC009"));
+        assert_eq!(disasm.label(0xc00c), Some("nmi"));
+
+        let listing = disasm.listing();
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(
+            lines[..14],
+            [
+                "; PRG $C000-$FFFF",
+                ".setcpu \"6502\"",
+                ".org $C000",
+                "",
+                "reset:",
+                "    sei                     ; C000",
+                "LC001:",
+                "    jsr LC009               ; C001",
+                "LC004:",
+                "    bne LC001               ; C004",
+                "    jmp LC004               ; C006",
+                "LC009:",
+                "    rts                     ; C009",
+                "    .byte $01, $02",
+            ]
+        );
+        assert_eq!(lines[lines.len() - 1], "    .word nmi, reset, $0000");
+    }
+
+    #[test]
+    fn test_operand_formats() {
+        let prg = bank(
+            &[
+                0xad, 0x10, 0x00, // LDA $0010 in its absolute encoding
+                0xa5, 0x10, // LDA $10
+                0xbd, 0x00, 0x02, // LDA $0200,X
+                0xb1, 0x20, // LDA ($20),Y
+                0x0a, // ASL A
+                0x6c, 0xfc, 0xff, // JMP ($FFFC)
+            ],
+            0xc000,
+            0xc000,
+            0xc000,
+        );
+        let listing = disassemble(&prg, 0xc000).unwrap().listing();
+
+        assert!(listing.contains("    lda a:$0010"));
+        assert!(listing.contains("    lda $10 "));
+        assert!(listing.contains("    lda $0200,x"));
+        assert!(listing.contains("    lda ($20),y"));
+        assert!(listing.contains("    asl a "));
+        assert!(listing.contains("    jmp ($FFFC)"));
+    }
+
+    #[test]
+    fn test_unofficial_opcodes_are_data() {
+        // SLO $10 is not traced, the path stops there
+        let prg = bank(&[0xea, 0x07, 0x10], 0xc000, 0xc000, 0xc000);
+        let disasm = disassemble(&prg, 0xc000).unwrap();
+
+        assert!(disasm.is_code(0xc000));
+        assert!(!disasm.is_code(0xc001));
+        assert!(disasm.listing().contains("    .byte $07, $10"));
+    }
+
+    #[test]
+    fn test_prg_must_fit_below_10000() {
+        assert_eq!(disassemble(&[], 0x8000).err(), Some(DisasmError::EmptyPrg));
+        assert_eq!(
+            disassemble(&[0xea; 0x4000], 0xc001).err(),
+            Some(DisasmError::OutOfAddressSpace {
+                origin: 0xc001,
+                len: 0x4000
+            })
+        );
+        assert!(disassemble(&[0xea; 0x4000], 0xc000).is_ok());
+    }
+}
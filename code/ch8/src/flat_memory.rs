@@ -0,0 +1,182 @@
+use crate::cpu::Clock;
+use crate::cpu::Mem;
+
+// 64KB of RAM and nothing else, the machine from the early chapters.
+// Big enough for test binaries that fill the whole address space.
+pub struct FlatMemory {
+    memory: Vec<u8>,
+    cycles: usize,
+}
+
+impl FlatMemory {
+    pub fn new() -> Self {
+        FlatMemory {
+            memory: vec![0; 0x10000],
+            cycles: 0,
+        }
+    }
+
+    pub fn load(&mut self, start: u16, data: &[u8]) {
+        let start = start as usize;
+        let len = data.len().min(self.memory.len() - start);
+        self.memory[start..start + len].copy_from_slice(&data[..len]);
+    }
+}
+
+impl Default for FlatMemory {
+    fn default() -> Self {
+        FlatMemory::new()
+    }
+}
+
+impl Mem for FlatMemory {
+    fn mem_read(&mut self, addr: u16) -> u8 {
+        self.memory[addr as usize]
+    }
+
+    fn mem_write(&mut self, addr: u16, data: u8) {
+        self.memory[addr as usize] = data;
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        self.memory[addr as usize]
+    }
+}
+
+impl Clock for FlatMemory {
+    fn tick(&mut self, cycles: u8) {
+        self.cycles += cycles as usize;
+    }
+
+    fn cycles(&self) -> usize {
+        self.cycles
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::{Variant, CPU};
+    use crate::trace::trace;
+
+    // https://github.com/Klaus2m5/6502_65C02_functional_tests
+    // the binary assembled with the default options: loaded at $0000, entry at $0400
+    const FUNCTIONAL_TEST_START: u16 = 0x0400;
+    const FUNCTIONAL_TEST_SUCCESS: u16 = 0x3469;
+    // 6502_decimal_test.a65 from the same repo, Bruce Clark's BCD test: entry at $0200,
+    // the ERROR byte is 0 when the test traps at the end
+    const DECIMAL_TEST_START: u16 = 0x0200;
+    const DECIMAL_TEST_ERROR: u16 = 0x000b;
+    const TRACE_TAIL: usize = 20;
+
+    fn cpu_with_image(image: &[u8], start: u16) -> CPU<FlatMemory> {
+        cpu_with_variant(image, start, Variant::Nmos6502)
+    }
+
+    fn cpu_with_variant(image: &[u8], start: u16, variant: Variant) -> CPU<FlatMemory> {
+        let mut memory = FlatMemory::new();
+        memory.load(0, image);
+        let mut cpu = CPU::with_variant(memory, variant);
+        cpu.program_counter = start;
+        cpu
+    }
+
+    // the test programs report by jumping to themselves, runs until that happens
+    fn run_to_trap(cpu: &mut CPU<FlatMemory>, max_steps: usize) -> usize {
+        for steps in 1..=max_steps {
+            let pc = cpu.program_counter;
+            cpu.step();
+            if cpu.program_counter == pc || cpu.is_halted() {
+                return steps;
+            }
+        }
+        max_steps
+    }
+
+    // the run is deterministic, so the tail is collected by replaying it
+    fn trace_tail(image: &[u8], start: u16, steps: usize) -> Vec<String> {
+        let mut cpu = cpu_with_image(image, start);
+        let skip = steps.saturating_sub(TRACE_TAIL);
+        for _ in 0..skip {
+            cpu.step();
+        }
+        let mut tail = vec![];
+        for _ in skip..steps {
+            tail.push(trace(&cpu));
+            cpu.step();
+        }
+        tail
+    }
+
+    #[test]
+    fn test_run_to_trap() {
+        // LDX #$05, DEX, BNE -3, JMP $0005
+        let image = [0xa2, 0x05, 0xca, 0xd0, 0xfd, 0x4c, 0x05, 0x00];
+        let mut cpu = cpu_with_image(&image, 0);
+
+        assert_eq!(run_to_trap(&mut cpu, 100), 12);
+        assert_eq!(cpu.program_counter, 0x0005);
+        assert_eq!(cpu.register_x, 0);
+        let tail = trace_tail(&image, 0, 12);
+        assert_eq!(tail.len(), 12);
+        assert_eq!(
+            tail[10..],
+            [
+                "0003  D0 FD     BNE $0002                       A:00 X:00 Y:00 P:26 SP:FD",
+                "0005  4C 05 00  JMP $0005                       A:00 X:00 Y:00 P:26 SP:FD",
+            ]
+        );
+    }
+
+    // cargo test --release klaus_functional_test -- --ignored
+    // KLAUS_FUNCTIONAL_TEST points to 6502_functional_test.bin, not part of the repo
+    #[test]
+    #[ignore]
+    fn klaus_functional_test() {
+        let path = std::env::var("KLAUS_FUNCTIONAL_TEST")
+            .unwrap_or_else(|_| "6502_functional_test.bin".into());
+        let image = std::fs::read(&path).unwrap();
+        let mut cpu = cpu_with_image(&image, FUNCTIONAL_TEST_START);
+
+        let steps = run_to_trap(&mut cpu, 100_000_000);
+        if cpu.program_counter != FUNCTIONAL_TEST_SUCCESS {
+            panic!(
+                "trapped at ${:04x} after {} instructions\n{}",
+                cpu.program_counter,
+                steps,
+                trace_tail(&image, FUNCTIONAL_TEST_START, steps).join("\n")
+            );
+        }
+    }
+
+    // the test is assembled per CPU, with cputype = 0 for the 6502 and 1 for the 65C02
+    fn decimal_test(path: &str, variant: Variant) {
+        let image = std::fs::read(path).unwrap();
+        let mut cpu = cpu_with_variant(&image, DECIMAL_TEST_START, variant);
+
+        let steps = run_to_trap(&mut cpu, 100_000_000);
+        let error = cpu.bus.peek(DECIMAL_TEST_ERROR);
+        assert_eq!(
+            error, 0,
+            "{:?} failed, trapped at ${:04x} after {} instructions",
+            variant, cpu.program_counter, steps
+        );
+    }
+
+    // cargo test --release decimal_test -- --ignored
+    // DECIMAL_TEST and DECIMAL_TEST_65C02 point to the two builds of 6502_decimal_test.bin
+    #[test]
+    #[ignore]
+    fn nmos6502_decimal_test() {
+        let path = std::env::var("DECIMAL_TEST").unwrap_or_else(|_| "6502_decimal_test.bin".into());
+        decimal_test(&path, Variant::Nmos6502);
+    }
+
+    #[test]
+    #[ignore]
+    fn cmos65c02_decimal_test() {
+        let path =
+            std::env::var("DECIMAL_TEST_65C02").unwrap_or_else(|_| "65C02_decimal_test.bin".into());
+        decimal_test(&path, Variant::Cmos65C02);
+    }
+}
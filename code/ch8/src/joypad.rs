@@ -45,6 +45,14 @@ impl Joypad {
         response
     }
 
+    // the bit the next read returns, without moving on to the next button
+    pub fn peek(&self) -> u8 {
+        if self.button_index > 7 {
+            return 1;
+        }
+        (self.button_status.bits & (1 << self.button_index)) >> self.button_index
+    }
+
     pub fn set_button_pressed_status(&mut self, button: JoypadButton, pressed: bool) {
         self.button_status.set(button, pressed);
     }
@@ -64,6 +72,18 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_peek_does_not_shift() {
+        let mut joypad = Joypad::new();
+        joypad.set_button_pressed_status(JoypadButton::BUTTON_A, true);
+
+        assert_eq!(joypad.peek(), 1);
+        assert_eq!(joypad.peek(), 1);
+        assert_eq!(joypad.read(), 1);
+        assert_eq!(joypad.peek(), 0);
+        assert_eq!(joypad.read(), 0);
+    }
+
     #[test]
     fn test_strobe_mode_on_off() {
         let mut joypad = Joypad::new();
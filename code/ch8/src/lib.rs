@@ -0,0 +1,25 @@
+pub mod bus;
+pub mod cartridge;
+pub mod checksum;
+pub mod cpu;
+pub mod disasm;
+pub mod flat_memory;
+pub mod joypad;
+pub mod mapper;
+pub mod opcodes;
+pub mod patch;
+pub mod ppu;
+pub mod render;
+pub mod romdb;
+pub mod trace;
+
+#[cfg(test)]
+mod blargg;
+#[cfg(test)]
+mod single_step;
+
+#[macro_use]
+extern crate lazy_static;
+
+#[macro_use]
+extern crate bitflags;
@@ -1,29 +1,29 @@
-pub mod bus;
-pub mod cartridge;
-pub mod cpu;
-pub mod joypad;
-pub mod opcodes;
-pub mod ppu;
-pub mod render;
-pub mod trace;
-
-use bus::Bus;
-use cartridge::Rom;
-use cpu::CPU;
-use ppu::NesPPU;
-use render::frame::Frame;
-// use trace::trace;
-
+use nes_book_emu::bus::Bus;
+use nes_book_emu::bus::AUDIO_SAMPLE_RATE;
+use nes_book_emu::cartridge::Rom;
+use nes_book_emu::cartridge::RomError;
+use nes_book_emu::cpu::CPU;
+use nes_book_emu::joypad;
+use nes_book_emu::patch;
+use nes_book_emu::ppu::NesPPU;
+use nes_book_emu::render;
+use nes_book_emu::render::frame::Frame;
+use nes_book_emu::romdb;
+// use nes_book_emu::trace::trace;
+
+use sdl2::audio::AudioSpecDesired;
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
 use sdl2::pixels::PixelFormatEnum;
+use std::cell::Cell;
 use std::collections::HashMap;
+use std::path::Path;
+use std::rc::Rc;
 
-#[macro_use]
-extern crate lazy_static;
-
-#[macro_use]
-extern crate bitflags;
+// Famicom Disk System BIOS, not distributed with the emulator
+const FDS_BIOS_PATH: &str = "disksys.rom";
+// NES 2.0 header database, used to fix bad headers when it's there
+const NES20DB_PATH: &str = "nes20db.xml";
 
 fn main() {
     // init sdl2
@@ -39,15 +39,45 @@ fn main() {
     let mut event_pump = sdl_context.event_pump().unwrap();
     canvas.set_scale(2.0, 2.0).unwrap();
 
+    // only the cartridge's expansion audio is played, without a sound device the game runs silent
+    let audio_spec = AudioSpecDesired {
+        freq: Some(AUDIO_SAMPLE_RATE as i32),
+        channels: Some(1),
+        samples: None,
+    };
+    let audio_queue = sdl_context
+        .audio()
+        .and_then(|audio| audio.open_queue::<f32, _>(None, &audio_spec))
+        .ok();
+    if let Some(queue) = &audio_queue {
+        queue.resume();
+    }
+
     let creator = canvas.texture_creator();
     let mut texture = creator
         .create_texture_target(PixelFormatEnum::RGB24, 256 * 2, 240)
         .unwrap();
 
+    if let Ok(xml) = std::fs::read_to_string(NES20DB_PATH) {
+        romdb::load_nes20db(&xml);
+    }
+
     //load the game
-    let bytes: Vec<u8> = std::fs::read("super.nes").unwrap();
+    let rom_path = Path::new("super.nes");
+    let bytes: Vec<u8> = std::fs::read(rom_path).unwrap();
+    let bytes = patch::auto_patch(rom_path, bytes).unwrap();
     // let bytes: Vec<u8> = std::fs::read("pacman.nes").unwrap();
-    let rom = Rom::new(&bytes).unwrap();
+    let rom = match Rom::new(&bytes) {
+        Err(RomError::MissingBios) => {
+            let bios = std::fs::read(FDS_BIOS_PATH).unwrap();
+            Rom::from_fds(&bytes, &bios)
+        }
+        rom => rom,
+    }
+    .unwrap();
+    for correction in &rom.corrections {
+        println!("{}: {}", rom_path.display(), correction);
+    }
 
     let mut frame = Frame::new();
 
@@ -62,6 +92,9 @@ fn main() {
     key_map.insert(Keycode::S, joypad::JoypadButton::BUTTON_B);
 
 
+    let quit = Rc::new(Cell::new(false));
+    let quit_requested = quit.clone();
+
     // run the game cycle
     let bus = Bus::new(rom, move |ppu: &NesPPU, joypad: &mut joypad::Joypad| {
         render::render(ppu, &mut frame);
@@ -76,7 +109,20 @@ fn main() {
                 | Event::KeyDown {
                     keycode: Some(Keycode::Escape),
                     ..
-                } => std::process::exit(0),
+                } => quit_requested.set(true),
+
+                Event::KeyDown {
+                    keycode: Some(Keycode::D),
+                    ..
+                } => {
+                    // flip to the next disk side
+                    let mut mapper = ppu.mapper.borrow_mut();
+                    let sides = mapper.disk_side_count();
+                    if sides > 0 {
+                        let next = mapper.inserted_disk_side().map_or(0, |side| (side + 1) % sides);
+                        mapper.insert_disk_side(Some(next));
+                    }
+                }
 
 
                 Event::KeyDown { keycode, .. } => {
@@ -96,8 +142,19 @@ fn main() {
     });
 
     let mut cpu = CPU::new(bus);
+    cpu.bus.load_save_file(rom_path).unwrap();
     cpu.reset();
-    cpu.run();
+    while !quit.get() && !cpu.is_halted() {
+        cpu.run_frame();
+        let samples = cpu.bus.take_audio_samples();
+        if let Some(queue) = &audio_queue {
+            queue.queue(&samples);
+        }
+    }
+    if cpu.is_halted() {
+        println!("CPU jammed at ${:04x}", cpu.program_counter);
+    }
+    cpu.bus.flush_save_file(rom_path).unwrap();
     /*
     cpu.run_with_callback(|cpu| {
         println!("{}", trace(cpu));
@@ -0,0 +1,94 @@
+use crate::cartridge::Mirroring;
+use crate::mapper::chr::Chr;
+use crate::mapper::Mapper;
+
+const PRG_BANK_SIZE: usize = 0x8000;
+
+// https://www.nesdev.org/wiki/AxROM
+//
+// 7  bit  0
+// ---- ----
+// xxxM xPPP
+//    |  |||
+//    |  +++- Select 32 KB PRG-ROM bank for CPU $8000-$FFFF
+//    +------ Select 1 KB VRAM page for all 4 nametables
+//
+// Boards use 8KB of CHR-RAM.
+pub struct Axrom {
+    prg_rom: Vec<u8>,
+    chr: Chr,
+    bank_select: u8,
+}
+
+impl Axrom {
+    pub fn new(prg_rom: Vec<u8>, chr_rom: Vec<u8>) -> Self {
+        Axrom {
+            prg_rom,
+            chr: Chr::new(chr_rom),
+            bank_select: 0,
+        }
+    }
+}
+
+impl Mapper for Axrom {
+    fn read_prg(&self, addr: u16) -> u8 {
+        // a 16KB image is a single bank, mirrored in both halves
+        let bank_count = (self.prg_rom.len() / PRG_BANK_SIZE).max(1);
+        let bank = (self.bank_select & 0b111) as usize % bank_count;
+        let index = bank * PRG_BANK_SIZE + (addr - 0x8000) as usize;
+        self.prg_rom[index % self.prg_rom.len()]
+    }
+
+    fn write_prg(&mut self, _addr: u16, data: u8) {
+        self.bank_select = data;
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr.read(addr as usize)
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        self.chr.write(addr as usize, data);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        if self.bank_select & 0b1_0000 == 0 {
+            Mirroring::SingleScreenLower
+        } else {
+            Mirroring::SingleScreenUpper
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_prg_bank_and_mirroring() {
+        let mut prg_rom = vec![];
+        for bank in 0..4 {
+            prg_rom.extend(vec![bank as u8; PRG_BANK_SIZE]);
+        }
+        let mut axrom = Axrom::new(prg_rom, vec![]);
+
+        assert_eq!(axrom.read_prg(0x8000), 0);
+        assert_eq!(axrom.mirroring(), Mirroring::SingleScreenLower);
+
+        axrom.write_prg(0x8000, 0b1_0010);
+        assert_eq!(axrom.read_prg(0x8000), 2);
+        assert_eq!(axrom.read_prg(0xFFFF), 2);
+        assert_eq!(axrom.mirroring(), Mirroring::SingleScreenUpper);
+    }
+
+    #[test]
+    fn test_16kb_prg_rom_is_mirrored() {
+        let mut prg_rom = vec![0; PRG_BANK_SIZE / 2];
+        prg_rom[0] = 0x66;
+        let mut axrom = Axrom::new(prg_rom, vec![]);
+        axrom.write_prg(0x8000, 0b11);
+
+        assert_eq!(axrom.read_prg(0x8000), 0x66);
+        assert_eq!(axrom.read_prg(0xC000), 0x66);
+    }
+}
@@ -0,0 +1,57 @@
+const CHR_RAM_SIZE: usize = 0x2000;
+
+// The pattern table memory on the cartridge: the CHR-ROM, or 8KB of CHR-RAM on
+// boards that come without one. Indexes past the end wrap around, so a bank
+// number larger than the chip mirrors a smaller one.
+pub struct Chr {
+    data: Vec<u8>,
+    writable: bool,
+}
+
+impl Chr {
+    pub fn new(chr_rom: Vec<u8>) -> Self {
+        if chr_rom.is_empty() {
+            Chr {
+                data: vec![0; CHR_RAM_SIZE],
+                writable: true,
+            }
+        } else {
+            Chr {
+                data: chr_rom,
+                writable: false,
+            }
+        }
+    }
+
+    pub fn read(&self, index: usize) -> u8 {
+        self.data[index % self.data.len()]
+    }
+
+    // writes to CHR-ROM are ignored
+    pub fn write(&mut self, index: usize, data: u8) {
+        if self.writable {
+            let len = self.data.len();
+            self.data[index % len] = data;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_chr_ram_when_there_is_no_chr_rom() {
+        let mut chr = Chr::new(vec![]);
+        chr.write(0x1234, 0x66);
+        assert_eq!(chr.read(0x1234), 0x66);
+        assert_eq!(chr.read(0x3234), 0x66);
+    }
+
+    #[test]
+    fn test_chr_rom_ignores_writes() {
+        let mut chr = Chr::new(vec![0x11; 0x2000]);
+        chr.write(0x1234, 0x66);
+        assert_eq!(chr.read(0x1234), 0x11);
+    }
+}
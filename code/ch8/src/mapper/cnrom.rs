@@ -0,0 +1,73 @@
+use crate::cartridge::Mirroring;
+use crate::mapper::chr::Chr;
+use crate::mapper::Mapper;
+
+const CHR_BANK_SIZE: usize = 0x2000;
+
+// https://www.nesdev.org/wiki/CNROM
+//
+// PRG-ROM is fixed like on NROM (16KB carts are mirrored into $C000-$FFFF).
+// Any write to $8000-$FFFF selects the 8KB CHR-ROM bank.
+pub struct Cnrom {
+    prg_rom: Vec<u8>,
+    chr: Chr,
+    mirroring: Mirroring,
+    chr_bank: u8,
+}
+
+impl Cnrom {
+    pub fn new(prg_rom: Vec<u8>, chr_rom: Vec<u8>, mirroring: Mirroring) -> Self {
+        Cnrom {
+            prg_rom,
+            chr: Chr::new(chr_rom),
+            mirroring,
+            chr_bank: 0,
+        }
+    }
+
+    fn chr_index(&self, addr: u16) -> usize {
+        self.chr_bank as usize * CHR_BANK_SIZE + addr as usize
+    }
+}
+
+impl Mapper for Cnrom {
+    fn read_prg(&self, addr: u16) -> u8 {
+        let addr = (addr - 0x8000) as usize;
+        self.prg_rom[addr % self.prg_rom.len()]
+    }
+
+    fn write_prg(&mut self, _addr: u16, data: u8) {
+        self.chr_bank = data;
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr.read(self.chr_index(addr))
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        self.chr.write(self.chr_index(addr), data);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_chr_bank_switching() {
+        let mut chr_rom = vec![];
+        for bank in 0..4 {
+            chr_rom.extend(vec![bank as u8; CHR_BANK_SIZE]);
+        }
+        let mut cnrom = Cnrom::new(vec![1; 0x4000], chr_rom, Mirroring::Vertical);
+
+        assert_eq!(cnrom.read_chr(0x1FFF), 0);
+        cnrom.write_prg(0x8000, 3);
+        assert_eq!(cnrom.read_chr(0x0000), 3);
+        assert_eq!(cnrom.read_prg(0xFFFF), 1);
+    }
+}
@@ -0,0 +1,84 @@
+use crate::cartridge::Mirroring;
+use crate::mapper::chr::Chr;
+use crate::mapper::Mapper;
+
+const PRG_BANK_SIZE: usize = 0x8000;
+const CHR_BANK_SIZE: usize = 0x2000;
+
+// https://www.nesdev.org/wiki/Color_Dreams
+//
+// 7  bit  0
+// ---- ----
+// CCCC LLPP
+// |||| ||||
+// |||| ||++- Select 32 KB PRG-ROM bank for CPU $8000-$FFFF
+// |||| ++--- Used for lockout defeat
+// ++++------ Select 8 KB CHR-ROM bank for PPU $0000-$1FFF
+pub struct ColorDreams {
+    prg_rom: Vec<u8>,
+    chr: Chr,
+    mirroring: Mirroring,
+    bank_select: u8,
+}
+
+impl ColorDreams {
+    pub fn new(prg_rom: Vec<u8>, chr_rom: Vec<u8>, mirroring: Mirroring) -> Self {
+        ColorDreams {
+            prg_rom,
+            chr: Chr::new(chr_rom),
+            mirroring,
+            bank_select: 0,
+        }
+    }
+
+    fn chr_index(&self, addr: u16) -> usize {
+        let bank = (self.bank_select >> 4) as usize;
+        bank * CHR_BANK_SIZE + addr as usize
+    }
+}
+
+impl Mapper for ColorDreams {
+    fn read_prg(&self, addr: u16) -> u8 {
+        let bank = (self.bank_select & 0b11) as usize;
+        let index = bank * PRG_BANK_SIZE + (addr - 0x8000) as usize;
+        self.prg_rom[index % self.prg_rom.len()]
+    }
+
+    fn write_prg(&mut self, _addr: u16, data: u8) {
+        self.bank_select = data;
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr.read(self.chr_index(addr))
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        self.chr.write(self.chr_index(addr), data);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bank_switching() {
+        let mut prg_rom = vec![];
+        for bank in 0..4 {
+            prg_rom.extend(vec![bank as u8; PRG_BANK_SIZE]);
+        }
+        let mut chr_rom = vec![];
+        for bank in 0..16 {
+            chr_rom.extend(vec![bank as u8; CHR_BANK_SIZE]);
+        }
+        let mut color_dreams = ColorDreams::new(prg_rom, chr_rom, Mirroring::Vertical);
+
+        color_dreams.write_prg(0x8000, 0xB1);
+        assert_eq!(color_dreams.read_prg(0xC000), 1);
+        assert_eq!(color_dreams.read_chr(0x0000), 0xB);
+    }
+}
@@ -0,0 +1,295 @@
+// https://www.nesdev.org/wiki/FDS_audio
+//
+// One wavetable channel: a 64 step, 6-bit waveform played back at a 12-bit
+// frequency, with a volume envelope and a frequency modulator driven by its own
+// 64 entry table of pitch deltas.
+//
+//  $4040-$407F  Wavetable RAM (writable only while $4089.7 is set)
+//  $4080        Volume envelope (MDSSSSSS: disable, direction, speed/gain)
+//  $4082-$4083  Wave frequency (low 8 bits, high 4 bits; $4083.7 halts wave, $4083.6 halts envelopes)
+//  $4084        Mod envelope (same layout as $4080)
+//  $4085        Mod counter (7-bit signed)
+//  $4086-$4087  Mod frequency (low 8 bits, high 4 bits; $4087.7 halts modulator)
+//  $4088        Mod table write (only while modulator is halted)
+//  $4089        Wave write enable / master volume
+//  $408A        Envelope speed
+//  $4090        Volume gain (read)
+//  $4092        Mod gain (read)
+const MOD_ADJUSTMENT: [i8; 8] = [0, 1, 2, 4, 0, -4, -2, -1];
+const MOD_RESET: u8 = 4;
+// master volume 2/2, 2/3, 2/4, 2/5
+const MASTER_VOLUME: [f32; 4] = [1.0, 2.0 / 3.0, 0.5, 0.4];
+
+#[derive(Default)]
+struct Envelope {
+    speed: u8,
+    gain: u8,
+    increase: bool,
+    disabled: bool,
+    timer: u32,
+}
+
+impl Envelope {
+    fn write(&mut self, data: u8) {
+        self.speed = data & 0b11_1111;
+        self.increase = data & 0b0100_0000 != 0;
+        self.disabled = data & 0b1000_0000 != 0;
+        if self.disabled {
+            self.gain = self.speed;
+        }
+        self.timer = 0;
+    }
+
+    fn clock(&mut self, master_speed: u8) {
+        if self.disabled {
+            return;
+        }
+        self.timer += 1;
+        if self.timer < 8 * (self.speed as u32 + 1) * master_speed as u32 {
+            return;
+        }
+        self.timer = 0;
+        if self.increase && self.gain < 32 {
+            self.gain += 1;
+        } else if !self.increase && self.gain > 0 {
+            self.gain -= 1;
+        }
+    }
+}
+
+pub struct FdsAudio {
+    wave_table: [u8; 64],
+    wave_write: bool,
+    wave_halted: bool,
+    wave_frequency: u16,
+    wave_accumulator: u32,
+    wave_position: u8,
+
+    envelopes_halted: bool,
+    envelope_speed: u8,
+    volume: Envelope,
+    master_volume: u8,
+    // the volume gain only takes effect at the start of a waveform cycle
+    output_gain: u8,
+
+    mod_envelope: Envelope,
+    mod_table: [u8; 64],
+    mod_position: u8,
+    mod_counter: i8,
+    mod_halted: bool,
+    mod_frequency: u16,
+    mod_accumulator: u32,
+}
+
+impl FdsAudio {
+    pub(super) fn new() -> Self {
+        FdsAudio {
+            wave_table: [0; 64],
+            wave_write: false,
+            wave_halted: true,
+            wave_frequency: 0,
+            wave_accumulator: 0,
+            wave_position: 0,
+            envelopes_halted: true,
+            envelope_speed: 0xE8,
+            volume: Envelope::default(),
+            master_volume: 0,
+            output_gain: 0,
+            mod_envelope: Envelope::default(),
+            mod_table: [0; 64],
+            mod_position: 0,
+            mod_counter: 0,
+            mod_halted: true,
+            mod_frequency: 0,
+            mod_accumulator: 0,
+        }
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0x4040..=0x407F => self.wave_table[(addr - 0x4040) as usize] | 0b0100_0000,
+            0x4090 => self.volume.gain | 0b0100_0000,
+            0x4092 => self.mod_envelope.gain | 0b0100_0000,
+            _ => 0,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4040..=0x407F if self.wave_write => {
+                self.wave_table[(addr - 0x4040) as usize] = data & 0b11_1111;
+            }
+            0x4080 => self.volume.write(data),
+            0x4082 => self.wave_frequency = (self.wave_frequency & 0x0F00) | data as u16,
+            0x4083 => {
+                self.wave_frequency = (self.wave_frequency & 0x00FF) | ((data as u16 & 0x0F) << 8);
+                self.wave_halted = data & 0b1000_0000 != 0;
+                self.envelopes_halted = data & 0b0100_0000 != 0;
+                if self.wave_halted {
+                    self.wave_accumulator = 0;
+                    self.wave_position = 0;
+                }
+                if self.envelopes_halted {
+                    self.volume.timer = 0;
+                    self.mod_envelope.timer = 0;
+                }
+            }
+            0x4084 => self.mod_envelope.write(data),
+            0x4085 => {
+                // sign-extend the 7-bit value
+                self.mod_counter = ((data << 1) as i8) >> 1;
+            }
+            0x4086 => self.mod_frequency = (self.mod_frequency & 0x0F00) | data as u16,
+            0x4087 => {
+                self.mod_frequency = (self.mod_frequency & 0x00FF) | ((data as u16 & 0x0F) << 8);
+                self.mod_halted = data & 0b1000_0000 != 0;
+                if self.mod_halted {
+                    self.mod_accumulator = 0;
+                }
+            }
+            0x4088 if self.mod_halted => {
+                // each write fills two consecutive entries
+                let pos = self.mod_position as usize;
+                self.mod_table[pos] = data & 0b111;
+                self.mod_table[pos + 1] = data & 0b111;
+                self.mod_position = (self.mod_position + 2) & 0b11_1111;
+            }
+            0x4089 => {
+                self.wave_write = data & 0b1000_0000 != 0;
+                self.master_volume = data & 0b11;
+            }
+            0x408A => self.envelope_speed = data,
+            _ => {}
+        }
+    }
+
+    // one CPU cycle
+    pub fn clock(&mut self) {
+        if !self.envelopes_halted && !self.wave_halted && self.envelope_speed != 0 {
+            self.volume.clock(self.envelope_speed);
+            self.mod_envelope.clock(self.envelope_speed);
+        }
+
+        if !self.mod_halted && self.mod_frequency != 0 {
+            self.mod_accumulator += self.mod_frequency as u32;
+            if self.mod_accumulator >= 0x1_0000 {
+                self.mod_accumulator &= 0xFFFF;
+                self.clock_modulator();
+            }
+        }
+
+        if !self.wave_halted && !self.wave_write {
+            let frequency = self.modulated_frequency();
+            self.wave_accumulator += frequency;
+            if self.wave_accumulator >= 0x1_0000 {
+                self.wave_accumulator &= 0xFFFF;
+                self.wave_position = (self.wave_position + 1) & 0b11_1111;
+                if self.wave_position == 0 {
+                    self.output_gain = self.volume.gain.min(32);
+                }
+            }
+        }
+    }
+
+    fn clock_modulator(&mut self) {
+        let step = self.mod_table[self.mod_position as usize];
+        self.mod_position = (self.mod_position + 1) & 0b11_1111;
+        if step == MOD_RESET {
+            self.mod_counter = 0;
+        } else {
+            // 7-bit counter wraps around
+            let counter = self.mod_counter as i16 + MOD_ADJUSTMENT[step as usize] as i16;
+            self.mod_counter = (((counter as u8) << 1) as i8) >> 1;
+        }
+    }
+
+    // https://www.nesdev.org/wiki/FDS_audio#Frequency_calculation
+    fn modulated_frequency(&self) -> u32 {
+        if self.mod_halted {
+            return self.wave_frequency as u32;
+        }
+
+        let mut temp = self.mod_counter as i32 * self.mod_envelope.gain as i32;
+        let remainder = temp & 0xF;
+        temp >>= 4;
+        if remainder > 0 && temp & 0x80 == 0 {
+            temp += if self.mod_counter < 0 { -1 } else { 2 };
+        }
+        if temp >= 192 {
+            temp -= 256;
+        } else if temp < -64 {
+            temp += 256;
+        }
+
+        let mut pitch = self.wave_frequency as i32 * temp;
+        let remainder = pitch & 0x3F;
+        pitch >>= 6;
+        if remainder >= 32 {
+            pitch += 1;
+        }
+
+        (self.wave_frequency as i32 + pitch).max(0) as u32
+    }
+
+    // current channel level in 0.0..=1.0, to be mixed with the APU output
+    pub fn output(&self) -> f32 {
+        if self.wave_write {
+            return 0.0;
+        }
+        let sample = self.wave_table[self.wave_position as usize] as f32;
+        let gain = self.output_gain as f32;
+        sample * gain / (63.0 * 32.0) * MASTER_VOLUME[self.master_volume as usize]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_wavetable_playback() {
+        let mut audio = FdsAudio::new();
+        audio.write(0x4089, 0x80);
+        for i in 0..64 {
+            audio.write(0x4040 + i, i as u8);
+        }
+        audio.write(0x4089, 0x00);
+        // fixed gain of 32
+        audio.write(0x4080, 0x80 | 32);
+        // frequency 0x800: a step every 32 cycles
+        audio.write(0x4082, 0x00);
+        audio.write(0x4083, 0x08);
+
+        assert_eq!(audio.read(0x4090), 32 | 0x40);
+        for _ in 0..64 * 32 {
+            audio.clock();
+        }
+        // full cycle latched the gain, back at step 0
+        assert_eq!(audio.wave_position, 0);
+        for _ in 0..10 * 32 {
+            audio.clock();
+        }
+        assert_eq!(audio.wave_position, 10);
+        assert!((audio.output() - 10.0 / 63.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_mod_table_and_counter() {
+        let mut audio = FdsAudio::new();
+        audio.write(0x4087, 0x80);
+        audio.write(0x4088, 3); // +4
+        audio.write(0x4088, 7); // -1
+        for _ in 2..32 {
+            audio.write(0x4088, 0);
+        }
+        audio.write(0x4085, 0x3E);
+        assert_eq!(audio.mod_counter, 62);
+
+        audio.clock_modulator();
+        // 62 + 4 wraps around to -62
+        assert_eq!(audio.mod_counter, -62);
+        audio.clock_modulator();
+        audio.clock_modulator();
+        assert_eq!(audio.mod_counter, -59);
+    }
+}
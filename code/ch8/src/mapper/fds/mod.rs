@@ -0,0 +1,633 @@
+use crate::cartridge::Mirroring;
+use crate::mapper::chr::Chr;
+use crate::mapper::Mapper;
+
+pub mod audio;
+
+use audio::FdsAudio;
+
+pub const BIOS_SIZE: usize = 0x2000;
+pub const SIDE_SIZE: usize = 65500;
+const RAM_START: u16 = 0x8000;
+const RAM_SIZE: usize = 0x6000;
+
+// the drive starts spinning ~50000 cycles after the head returns, then a byte passes every ~150
+const HEAD_RETURN_DELAY: u32 = 50000;
+const BYTE_DELAY: u32 = 150;
+// after a disk is inserted the drive reports no disk for a while so the BIOS notices the swap
+const INSERT_DELAY: u32 = 1_000_000;
+
+// .fds images store the blocks back to back; on a real disk each block is
+// preceded by a gap and a start mark and followed by a CRC
+const LEAD_IN_GAP: usize = 28300 / 8;
+const BLOCK_GAP: usize = 976 / 8;
+const BLOCK_START_MARK: u8 = 0x80;
+
+// https://www.nesdev.org/wiki/Family_Computer_Disk_System
+//
+// The RAM adapter replaces the cartridge: 32KB of RAM at $6000-$DFFF, the 8KB BIOS at
+// $E000-$FFFF, 8KB of CHR-RAM and the disk drive controller. The first 8KB of RAM
+// ($6000-$7FFF) is the bus PRG-RAM, the rest lives here.
+//
+//  $4020  IRQ reload value low
+//  $4021  IRQ reload value high
+//  $4022  IRQ control (bit 0: repeat, bit 1: enabled)
+//  $4023  Master I/O enable (bit 0: disk registers, bit 1: sound registers)
+//  $4024  Write data
+//  $4025  FDS control
+//  $4026  External connector
+//  $4030  Disk status (read)
+//  $4031  Read data (read)
+//  $4032  Drive status (read)
+//  $4033  External connector / battery status (read)
+//
+// FDS control ($4025):
+//
+// 7  bit  0
+// ---- ----
+// IS1B MRTD
+// |||| ||||
+// |||| |||+- Drive motor (0: stop, 1: start)
+// |||| ||+-- Transfer reset (1: hold the head at the start of the disk)
+// |||| |+--- Transfer mode (0: write, 1: read)
+// |||| +---- Mirroring (0: vertical, 1: horizontal)
+// |||+------ CRC control (set while the CRC is being transferred)
+// ||+------- Always 1
+// |+-------- Transfer start (0: gap, 1: block data)
+// +--------- IRQ on each transferred byte
+pub struct Fds {
+    bios: Vec<u8>,
+    ram: Vec<u8>,
+    chr: Chr,
+    disk_sides: Vec<Vec<u8>>,
+    disk_modified: bool,
+    inserted_side: Option<usize>,
+    insert_delay: u32,
+    audio: FdsAudio,
+
+    disk_registers_enabled: bool,
+    sound_registers_enabled: bool,
+
+    irq_reload: u16,
+    irq_counter: u16,
+    irq_repeat: bool,
+    irq_enabled: bool,
+    timer_irq: bool,
+
+    motor_on: bool,
+    reset_transfer: bool,
+    read_mode: bool,
+    mirroring: Mirroring,
+    crc_control: bool,
+    transfer_start: bool,
+    disk_irq_enabled: bool,
+    disk_irq: bool,
+
+    read_data: u8,
+    write_data: u8,
+    transfer_complete: bool,
+    end_of_head: bool,
+    scanning: bool,
+    gap_ended: bool,
+    position: usize,
+    delay: u32,
+}
+
+impl Fds {
+    pub fn new(bios: Vec<u8>, disk_sides: Vec<Vec<u8>>) -> Self {
+        let inserted_side = if disk_sides.is_empty() { None } else { Some(0) };
+        Fds {
+            bios,
+            ram: vec![0; RAM_SIZE],
+            chr: Chr::new(vec![]),
+            disk_sides: disk_sides.iter().map(|side| add_gaps(side)).collect(),
+            disk_modified: false,
+            inserted_side,
+            insert_delay: 0,
+            audio: FdsAudio::new(),
+            disk_registers_enabled: true,
+            sound_registers_enabled: true,
+            irq_reload: 0,
+            irq_counter: 0,
+            irq_repeat: false,
+            irq_enabled: false,
+            timer_irq: false,
+            motor_on: false,
+            reset_transfer: false,
+            read_mode: true,
+            mirroring: Mirroring::Horizontal,
+            crc_control: false,
+            transfer_start: false,
+            disk_irq_enabled: false,
+            disk_irq: false,
+            read_data: 0,
+            write_data: 0,
+            transfer_complete: false,
+            end_of_head: true,
+            scanning: false,
+            gap_ended: false,
+            position: 0,
+            delay: 0,
+        }
+    }
+
+    fn disk_inserted(&self) -> bool {
+        self.inserted_side.is_some() && self.insert_delay == 0
+    }
+
+    fn clock_timer(&mut self) {
+        if !self.irq_enabled {
+            return;
+        }
+        if self.irq_counter == 0 {
+            self.timer_irq = true;
+            self.irq_counter = self.irq_reload;
+            if !self.irq_repeat {
+                self.irq_enabled = false;
+            }
+        } else {
+            self.irq_counter -= 1;
+        }
+    }
+
+    fn clock_drive(&mut self) {
+        if self.insert_delay > 0 {
+            self.insert_delay -= 1;
+        }
+
+        let side = match self.inserted_side {
+            Some(side) if self.motor_on && self.insert_delay == 0 => side,
+            _ => {
+                self.end_of_head = true;
+                self.scanning = false;
+                return;
+            }
+        };
+        if self.reset_transfer && !self.scanning {
+            return;
+        }
+        if self.end_of_head {
+            self.delay = HEAD_RETURN_DELAY;
+            self.end_of_head = false;
+            self.position = 0;
+            self.gap_ended = false;
+            return;
+        }
+        if self.delay > 0 {
+            self.delay -= 1;
+            return;
+        }
+
+        self.scanning = true;
+        let disk = &mut self.disk_sides[side];
+        let mut need_irq = self.disk_irq_enabled;
+
+        if self.read_mode {
+            let data = disk[self.position];
+            if !self.transfer_start {
+                self.gap_ended = false;
+            } else if data != 0 && !self.gap_ended {
+                // no IRQ for the start mark
+                self.gap_ended = true;
+                need_irq = false;
+            }
+
+            if self.gap_ended {
+                self.transfer_complete = true;
+                self.read_data = data;
+                self.disk_irq |= need_irq;
+            }
+        } else {
+            let mut data = 0;
+            if !self.crc_control {
+                self.transfer_complete = true;
+                data = self.write_data;
+                self.disk_irq |= need_irq;
+            }
+            if !self.transfer_start {
+                data = 0;
+            }
+            // the CRC is not computed, whatever the BIOS sends is stored instead
+            disk[self.position] = data;
+            self.disk_modified = true;
+            self.gap_ended = false;
+        }
+
+        self.position += 1;
+        if self.position >= disk.len() {
+            self.motor_on = false;
+        } else {
+            self.delay = BYTE_DELAY;
+        }
+    }
+
+    fn read_register(&mut self, addr: u16) -> u8 {
+        let data = self.peek_register(addr);
+        match addr {
+            0x4030 => {
+                self.transfer_complete = false;
+                self.timer_irq = false;
+                self.disk_irq = false;
+            }
+            0x4031 => {
+                self.transfer_complete = false;
+                self.disk_irq = false;
+            }
+            _ => {}
+        }
+        data
+    }
+
+    fn peek_register(&self, addr: u16) -> u8 {
+        match addr {
+            0x4030 => {
+                let mut status = self.timer_irq as u8 | (self.transfer_complete as u8) << 1;
+                if self.end_of_head {
+                    status |= 0b0100_0000;
+                }
+                status
+            }
+            0x4031 => self.read_data,
+            0x4032 => {
+                let mut status = 0b0100_0000;
+                if !self.disk_inserted() {
+                    // no disk, not ready, write protected
+                    status |= 0b111;
+                } else if !self.scanning {
+                    status |= 0b010;
+                }
+                status
+            }
+            // battery is good
+            0x4033 => 0b1000_0000,
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4020 => self.irq_reload = (self.irq_reload & 0xFF00) | data as u16,
+            0x4021 => self.irq_reload = (self.irq_reload & 0x00FF) | (data as u16) << 8,
+            0x4022 => {
+                self.irq_repeat = data & 0b01 != 0;
+                self.irq_enabled = data & 0b10 != 0 && self.disk_registers_enabled;
+                if self.irq_enabled {
+                    self.irq_counter = self.irq_reload;
+                } else {
+                    self.timer_irq = false;
+                }
+            }
+            0x4023 => {
+                self.disk_registers_enabled = data & 0b01 != 0;
+                self.sound_registers_enabled = data & 0b10 != 0;
+                if !self.disk_registers_enabled {
+                    self.irq_enabled = false;
+                    self.timer_irq = false;
+                    self.disk_irq = false;
+                }
+            }
+            0x4024 => {
+                self.write_data = data;
+                self.transfer_complete = false;
+                self.disk_irq = false;
+            }
+            0x4025 => {
+                self.motor_on = data & 0b0000_0001 != 0;
+                self.reset_transfer = data & 0b0000_0010 != 0;
+                self.read_mode = data & 0b0000_0100 != 0;
+                self.mirroring = if data & 0b0000_1000 != 0 {
+                    Mirroring::Horizontal
+                } else {
+                    Mirroring::Vertical
+                };
+                self.crc_control = data & 0b0001_0000 != 0;
+                self.transfer_start = data & 0b0100_0000 != 0;
+                self.disk_irq_enabled = data & 0b1000_0000 != 0;
+                self.disk_irq = false;
+            }
+            _ => { /* $4026 external connector */ }
+        }
+    }
+}
+
+// converts a side from the .fds layout to what the drive head sees
+fn add_gaps(side: &[u8]) -> Vec<u8> {
+    let mut disk = vec![0; LEAD_IN_GAP];
+    let mut pos = 0;
+    while pos < side.len() {
+        let len = match side[pos] {
+            1 => 56,
+            2 => 2,
+            3 => 16,
+            // file size comes from the preceding file header block
+            4 if pos >= 3 => 1 + (side[pos - 3] as usize | (side[pos - 2] as usize) << 8),
+            _ => break,
+        };
+        let block = match side.get(pos..pos + len) {
+            Some(block) => block,
+            None => break,
+        };
+        disk.push(BLOCK_START_MARK);
+        disk.extend_from_slice(block);
+        // fake CRC
+        disk.extend_from_slice(&[0x4D, 0x62]);
+        disk.extend(vec![0; BLOCK_GAP]);
+        pos += len;
+    }
+    disk.resize(disk.len().max(side.len()), 0);
+    disk
+}
+
+// the other way around, back to the .fds layout
+fn remove_gaps(disk: &[u8]) -> Vec<u8> {
+    let mut side = vec![];
+    let mut file_size = 0;
+    let mut pos = 0;
+    while let Some(gap) = disk[pos..].iter().position(|&byte| byte == BLOCK_START_MARK) {
+        pos += gap + 1;
+        let len = match disk.get(pos) {
+            Some(1) => 56,
+            Some(2) => 2,
+            Some(3) => 16,
+            Some(4) => 1 + file_size,
+            _ => break,
+        };
+        let block = match disk.get(pos..pos + len) {
+            Some(block) => block,
+            None => break,
+        };
+        if block[0] == 3 {
+            file_size = block[13] as usize | (block[14] as usize) << 8;
+        }
+        side.extend_from_slice(block);
+        // skip the CRC
+        pos = (pos + len + 2).min(disk.len());
+    }
+    side.resize(SIDE_SIZE, 0);
+    side
+}
+
+impl Mapper for Fds {
+    fn read_prg(&self, addr: u16) -> u8 {
+        match addr {
+            RAM_START..=0xDFFF => self.ram[(addr - RAM_START) as usize],
+            _ => self.bios[(addr as usize - 0xE000) % self.bios.len()],
+        }
+    }
+
+    fn write_prg(&mut self, addr: u16, data: u8) {
+        if let RAM_START..=0xDFFF = addr {
+            self.ram[(addr - RAM_START) as usize] = data;
+        }
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr.read(addr as usize)
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        self.chr.write(addr as usize, data);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring.clone()
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.timer_irq || self.disk_irq
+    }
+
+    fn read_expansion(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x4030..=0x4033 if self.disk_registers_enabled => self.read_register(addr),
+            0x4040..=0x4097 if self.sound_registers_enabled => self.audio.read(addr),
+            _ => 0,
+        }
+    }
+
+    fn peek_expansion(&self, addr: u16) -> u8 {
+        match addr {
+            0x4030..=0x4033 if self.disk_registers_enabled => self.peek_register(addr),
+            0x4040..=0x4097 if self.sound_registers_enabled => self.audio.read(addr),
+            _ => 0,
+        }
+    }
+
+    fn write_expansion(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4023 => self.write_register(addr, data),
+            0x4020..=0x4026 if self.disk_registers_enabled => self.write_register(addr, data),
+            0x4040..=0x4097 if self.sound_registers_enabled => self.audio.write(addr, data),
+            _ => {}
+        }
+    }
+
+    fn tick(&mut self, cycles: u8) {
+        for _ in 0..cycles {
+            self.clock_timer();
+            self.clock_drive();
+            self.audio.clock();
+        }
+    }
+
+    fn expansion_audio(&self) -> f32 {
+        self.audio.output()
+    }
+
+    fn disk_side_count(&self) -> usize {
+        self.disk_sides.len()
+    }
+
+    fn inserted_disk_side(&self) -> Option<usize> {
+        self.inserted_side
+    }
+
+    fn save_data(&self) -> Option<Vec<u8>> {
+        if !self.disk_modified {
+            return None;
+        }
+        Some(
+            self.disk_sides
+                .iter()
+                .flat_map(|disk| remove_gaps(disk))
+                .collect(),
+        )
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        if data.len() == self.disk_sides.len() * SIDE_SIZE {
+            self.disk_sides = data.chunks(SIDE_SIZE).map(add_gaps).collect();
+        }
+    }
+
+    fn insert_disk_side(&mut self, side: Option<usize>) {
+        self.inserted_side = side.filter(|&side| side < self.disk_sides.len());
+        self.insert_delay = if self.inserted_side.is_some() {
+            INSERT_DELAY
+        } else {
+            0
+        };
+        self.motor_on = false;
+        self.scanning = false;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_side() -> Vec<u8> {
+        let mut side = vec![1];
+        side.extend(b"*NINTENDO-HVC*");
+        side.resize(56, 0);
+        // file amount
+        side.extend(&[2, 1]);
+        // file header with a 3 byte file
+        let mut header = vec![3; 16];
+        header[13] = 3;
+        header[14] = 0;
+        side.extend(header);
+        side.extend(&[4, 0xAA, 0xBB, 0xCC]);
+        side.resize(SIDE_SIZE, 0);
+        side
+    }
+
+    fn test_fds() -> Fds {
+        let mut bios = vec![0; BIOS_SIZE];
+        bios[BIOS_SIZE - 1] = 0xE0;
+        Fds::new(bios, vec![test_side(), test_side()])
+    }
+
+    #[test]
+    fn test_memory_map() {
+        let mut fds = test_fds();
+        fds.write_prg(0x8000, 0x11);
+        fds.write_prg(0xDFFF, 0x22);
+        fds.write_prg(0xFFFF, 0x33);
+
+        assert_eq!(fds.read_prg(0x8000), 0x11);
+        assert_eq!(fds.read_prg(0xDFFF), 0x22);
+        assert_eq!(fds.read_prg(0xFFFF), 0xE0);
+    }
+
+    #[test]
+    fn test_gaps_are_added_around_blocks() {
+        let disk = add_gaps(&test_side());
+        assert_eq!(disk[LEAD_IN_GAP], BLOCK_START_MARK);
+        assert_eq!(disk[LEAD_IN_GAP + 1], 1);
+        assert_eq!(&disk[LEAD_IN_GAP + 57..LEAD_IN_GAP + 59], &[0x4D, 0x62]);
+        let second_block = LEAD_IN_GAP + 1 + 56 + 2 + BLOCK_GAP;
+        assert_eq!(
+            &disk[second_block..second_block + 3],
+            &[BLOCK_START_MARK, 2, 1]
+        );
+    }
+
+    #[test]
+    fn test_gaps_are_removed_for_saving() {
+        let side = test_side();
+        assert_eq!(remove_gaps(&add_gaps(&side)), side);
+    }
+
+    #[test]
+    fn test_written_disk_is_saved() {
+        let mut fds = test_fds();
+        assert_eq!(fds.save_data(), None);
+
+        fds.write_expansion(0x4023, 0b11);
+        // motor on, write mode, the BIOS fills the lead-in gap with zeros
+        fds.write_expansion(0x4025, 0b0000_0001);
+        for _ in 0..(HEAD_RETURN_DELAY + 4 * (BYTE_DELAY + 1)) {
+            fds.tick(1);
+        }
+
+        let data = fds.save_data().unwrap();
+        assert_eq!(data.len(), 2 * SIDE_SIZE);
+        assert_eq!(data[..SIDE_SIZE], test_side()[..]);
+
+        // the first byte of the file on the second side
+        let mut changed = data.clone();
+        changed[SIDE_SIZE + 75] = 0xDD;
+        fds.load_save_data(&changed);
+        assert_eq!(fds.save_data(), Some(changed));
+    }
+
+    #[test]
+    fn test_wave_channel_is_expansion_audio() {
+        let mut fds = test_fds();
+        fds.write_expansion(0x4089, 0x80);
+        for i in 0..64 {
+            fds.write_expansion(0x4040 + i, 63);
+        }
+        fds.write_expansion(0x4089, 0x00);
+        assert_eq!(fds.expansion_audio(), 0.0);
+
+        fds.write_expansion(0x4080, 0x80 | 32);
+        fds.write_expansion(0x4082, 0x00);
+        fds.write_expansion(0x4083, 0x08);
+        // the gain is latched at the end of the first pass through the wavetable
+        for _ in 0..21 {
+            fds.tick(100);
+        }
+        assert!((fds.expansion_audio() - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_timer_irq() {
+        let mut fds = test_fds();
+        fds.write_expansion(0x4023, 0b11);
+        fds.write_expansion(0x4020, 10);
+        fds.write_expansion(0x4021, 0);
+        fds.write_expansion(0x4022, 0b11);
+
+        fds.tick(10);
+        assert!(!fds.irq_pending());
+        fds.tick(1);
+        assert!(fds.irq_pending());
+
+        // reading the status acknowledges the IRQ, repeat mode reloads the counter
+        assert_eq!(fds.read_expansion(0x4030) & 1, 1);
+        assert!(!fds.irq_pending());
+        fds.tick(11);
+        assert!(fds.irq_pending());
+    }
+
+    #[test]
+    fn test_reading_the_disk() {
+        let mut fds = test_fds();
+        fds.write_expansion(0x4023, 0b11);
+        // motor on, read mode, transfer start
+        fds.write_expansion(0x4025, 0b0110_0101);
+
+        let mut bytes = vec![];
+        for _ in 0..(HEAD_RETURN_DELAY + (LEAD_IN_GAP as u32 + 16) * (BYTE_DELAY + 1)) {
+            fds.tick(1);
+            if fds.read_expansion(0x4030) & 0b10 != 0 {
+                bytes.push(fds.read_expansion(0x4031));
+            }
+        }
+
+        assert_eq!(fds.read_expansion(0x4032) & 0b11, 0);
+        assert_eq!(&bytes[..3], &[BLOCK_START_MARK, 1, b'*']);
+    }
+
+    #[test]
+    fn test_disk_side_switching() {
+        let mut fds = test_fds();
+        fds.write_expansion(0x4023, 0b11);
+        assert_eq!(fds.disk_side_count(), 2);
+        assert_eq!(fds.inserted_disk_side(), Some(0));
+
+        fds.insert_disk_side(None);
+        assert_eq!(fds.read_expansion(0x4032) & 0b1, 1);
+
+        fds.insert_disk_side(Some(1));
+        assert_eq!(fds.inserted_disk_side(), Some(1));
+        // the BIOS sees the drive empty for a while
+        assert_eq!(fds.read_expansion(0x4032) & 0b1, 1);
+        for _ in 0..INSERT_DELAY / 200 + 1 {
+            fds.tick(200);
+        }
+        assert_eq!(fds.read_expansion(0x4032) & 0b1, 0);
+    }
+}
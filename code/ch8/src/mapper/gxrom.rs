@@ -0,0 +1,83 @@
+use crate::cartridge::Mirroring;
+use crate::mapper::chr::Chr;
+use crate::mapper::Mapper;
+
+const PRG_BANK_SIZE: usize = 0x8000;
+const CHR_BANK_SIZE: usize = 0x2000;
+
+// https://www.nesdev.org/wiki/GxROM
+//
+// 7  bit  0
+// ---- ----
+// xxPP xxCC
+//   ||   ||
+//   ||   ++- Select 8 KB CHR-ROM bank for PPU $0000-$1FFF
+//   ++------ Select 32 KB PRG-ROM bank for CPU $8000-$FFFF
+pub struct Gxrom {
+    prg_rom: Vec<u8>,
+    chr: Chr,
+    mirroring: Mirroring,
+    bank_select: u8,
+}
+
+impl Gxrom {
+    pub fn new(prg_rom: Vec<u8>, chr_rom: Vec<u8>, mirroring: Mirroring) -> Self {
+        Gxrom {
+            prg_rom,
+            chr: Chr::new(chr_rom),
+            mirroring,
+            bank_select: 0,
+        }
+    }
+
+    fn chr_index(&self, addr: u16) -> usize {
+        let bank = (self.bank_select & 0b11) as usize;
+        bank * CHR_BANK_SIZE + addr as usize
+    }
+}
+
+impl Mapper for Gxrom {
+    fn read_prg(&self, addr: u16) -> u8 {
+        let bank = ((self.bank_select >> 4) & 0b11) as usize;
+        let index = bank * PRG_BANK_SIZE + (addr - 0x8000) as usize;
+        self.prg_rom[index % self.prg_rom.len()]
+    }
+
+    fn write_prg(&mut self, _addr: u16, data: u8) {
+        self.bank_select = data;
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr.read(self.chr_index(addr))
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        self.chr.write(self.chr_index(addr), data);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bank_switching() {
+        let mut prg_rom = vec![];
+        for bank in 0..4 {
+            prg_rom.extend(vec![bank as u8; PRG_BANK_SIZE]);
+        }
+        let mut chr_rom = vec![];
+        for bank in 0..4 {
+            chr_rom.extend(vec![bank as u8; CHR_BANK_SIZE]);
+        }
+        let mut gxrom = Gxrom::new(prg_rom, chr_rom, Mirroring::Horizontal);
+
+        gxrom.write_prg(0x8000, 0x21);
+        assert_eq!(gxrom.read_prg(0x8000), 2);
+        assert_eq!(gxrom.read_chr(0x1000), 1);
+    }
+}
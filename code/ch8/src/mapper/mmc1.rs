@@ -0,0 +1,315 @@
+use crate::cartridge::Mirroring;
+use crate::mapper::chr::Chr;
+use crate::mapper::Mapper;
+
+const PRG_BANK_SIZE: usize = 0x4000;
+const CHR_BANK_SIZE: usize = 0x1000;
+const PRG_OUTER_BANK_SIZE: usize = 0x40000;
+
+// https://www.nesdev.org/wiki/MMC1
+//
+// Registers are loaded serially: each write to $8000-$FFFF shifts bit 0 into a
+// 5-bit shift register. The 5th write copies the value into the register selected
+// by bits 13-14 of the address. Writing a value with bit 7 set resets the shift
+// register and locks PRG-ROM into mode 3.
+//
+//  $8000-$9FFF  Control
+//  $A000-$BFFF  CHR bank 0
+//  $C000-$DFFF  CHR bank 1
+//  $E000-$FFFF  PRG bank
+//
+// Control register:
+//
+// 4bit0
+// -----
+// CPPMM
+// |||||
+// |||++- Mirroring (0: one-screen, lower bank; 1: one-screen, upper bank;
+// |||               2: vertical; 3: horizontal)
+// |++--- PRG-ROM bank mode (0, 1: switch 32 KB at $8000, ignoring low bit of bank number;
+// |                         2: fix first bank at $8000 and switch 16 KB bank at $C000;
+// |                         3: fix last bank at $C000 and switch 16 KB bank at $8000)
+// +----- CHR-ROM bank mode (0: switch 8 KB at a time; 1: switch two separate 4 KB banks)
+//
+// The board only notices a write if the previous CPU cycle was not a write, so the
+// second write of a read-modify-write instruction is ignored.
+//
+// SUROM and SXROM carry 512KB of PRG-ROM, more than the 4-bit PRG bank reaches.
+// Bit 4 of CHR bank 0 selects the 256KB half, the fixed banks included. The board
+// takes it from whichever CHR register drives the current pattern table; only CHR
+// bank 0 is looked at here, which is what games write in practice.
+pub struct Mmc1 {
+    prg_rom: Vec<u8>,
+    chr: Chr,
+
+    shift_register: u8,
+    write_count: u8,
+
+    control: u8,
+    chr_bank_0: u8,
+    chr_bank_1: u8,
+    prg_bank: u8,
+
+    cycles: usize,
+    last_write_cycle: Option<usize>,
+}
+
+impl Mmc1 {
+    pub fn new(prg_rom: Vec<u8>, chr_rom: Vec<u8>) -> Self {
+        Mmc1 {
+            prg_rom,
+            chr: Chr::new(chr_rom),
+            shift_register: 0,
+            write_count: 0,
+            control: 0x0C,
+            chr_bank_0: 0,
+            chr_bank_1: 0,
+            prg_bank: 0,
+            cycles: 0,
+            last_write_cycle: None,
+        }
+    }
+
+    fn prg_bank_count(&self) -> usize {
+        (self.prg_rom.len() / PRG_BANK_SIZE).max(1)
+    }
+
+    // first 16KB bank of the selected 256KB half, and the number of banks in it
+    fn prg_outer_bank(&self) -> (usize, usize) {
+        let banks_per_outer = PRG_OUTER_BANK_SIZE / PRG_BANK_SIZE;
+        if self.prg_rom.len() > PRG_OUTER_BANK_SIZE {
+            let outer = (self.chr_bank_0 as usize >> 4) & 1;
+            (outer * banks_per_outer, banks_per_outer)
+        } else {
+            (0, self.prg_bank_count())
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x8000..=0x9FFF => self.control = value,
+            0xA000..=0xBFFF => self.chr_bank_0 = value,
+            0xC000..=0xDFFF => self.chr_bank_1 = value,
+            0xE000..=0xFFFF => self.prg_bank = value & 0b1111,
+            _ => panic!("unexpected MMC1 register address {:x}", addr),
+        }
+    }
+
+    fn chr_index(&self, addr: u16) -> usize {
+        let bank = if self.control & 0b1_0000 == 0 {
+            (self.chr_bank_0 & 0b1_1110) as usize + (addr as usize >= CHR_BANK_SIZE) as usize
+        } else if (addr as usize) < CHR_BANK_SIZE {
+            self.chr_bank_0 as usize
+        } else {
+            self.chr_bank_1 as usize
+        };
+
+        let offset = (addr as usize) & (CHR_BANK_SIZE - 1);
+        bank * CHR_BANK_SIZE + offset
+    }
+}
+
+impl Mapper for Mmc1 {
+    fn read_prg(&self, addr: u16) -> u8 {
+        let (first_bank, bank_count) = self.prg_outer_bank();
+        let last_bank = bank_count - 1;
+        let upper_half = addr >= 0xC000;
+        let bank = match (self.control >> 2) & 0b11 {
+            0 | 1 => (self.prg_bank & 0b1110) as usize + upper_half as usize,
+            2 if upper_half => self.prg_bank as usize,
+            2 => 0,
+            _ if upper_half => last_bank,
+            _ => self.prg_bank as usize,
+        };
+
+        let offset = (addr as usize) & (PRG_BANK_SIZE - 1);
+        let bank = (first_bank + bank) % self.prg_bank_count();
+        self.prg_rom[(bank * PRG_BANK_SIZE + offset) % self.prg_rom.len()]
+    }
+
+    fn write_prg(&mut self, addr: u16, data: u8) {
+        let consecutive = self.last_write_cycle.map(|cycle| cycle + 1) == Some(self.cycles);
+        self.last_write_cycle = Some(self.cycles);
+        if consecutive {
+            return;
+        }
+
+        if data & 0b1000_0000 != 0 {
+            self.shift_register = 0;
+            self.write_count = 0;
+            self.control |= 0x0C;
+            return;
+        }
+
+        self.shift_register |= (data & 1) << self.write_count;
+        self.write_count += 1;
+
+        if self.write_count == 5 {
+            self.write_register(addr, self.shift_register);
+            self.shift_register = 0;
+            self.write_count = 0;
+        }
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr.read(self.chr_index(addr))
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        self.chr.write(self.chr_index(addr), data);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.control & 0b11 {
+            0 => Mirroring::SingleScreenLower,
+            1 => Mirroring::SingleScreenUpper,
+            2 => Mirroring::Vertical,
+            _ => Mirroring::Horizontal,
+        }
+    }
+
+    fn tick(&mut self, cycles: u8) {
+        self.cycles += cycles as usize;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write_serial(mmc1: &mut Mmc1, addr: u16, value: u8) {
+        for i in 0..5 {
+            mmc1.write_prg(addr, (value >> i) & 1);
+        }
+    }
+
+    fn test_mmc1() -> Mmc1 {
+        let mut prg_rom = vec![];
+        for bank in 0..8 {
+            prg_rom.extend(vec![bank as u8; PRG_BANK_SIZE]);
+        }
+        let mut chr_rom = vec![];
+        for bank in 0..8 {
+            chr_rom.extend(vec![bank as u8; CHR_BANK_SIZE]);
+        }
+        Mmc1::new(prg_rom, chr_rom)
+    }
+
+    #[test]
+    fn test_chr_ram_without_chr_rom() {
+        let mut mmc1 = Mmc1::new(vec![0; PRG_BANK_SIZE * 2], vec![]);
+        write_serial(&mut mmc1, 0xA000, 3);
+        mmc1.write_chr(0x1234, 0x66);
+        assert_eq!(mmc1.read_chr(0x1234), 0x66);
+    }
+
+    #[test]
+    fn test_power_on_fixes_last_bank() {
+        let mmc1 = test_mmc1();
+        assert_eq!(mmc1.read_prg(0x8000), 0);
+        assert_eq!(mmc1.read_prg(0xFFFF), 7);
+    }
+
+    #[test]
+    fn test_prg_bank_modes() {
+        let mut mmc1 = test_mmc1();
+        write_serial(&mut mmc1, 0xE000, 3);
+        assert_eq!(mmc1.read_prg(0x8000), 3);
+        assert_eq!(mmc1.read_prg(0xC000), 7);
+
+        // fix first bank at $8000
+        write_serial(&mut mmc1, 0x8000, 0b0_1000);
+        assert_eq!(mmc1.read_prg(0x8000), 0);
+        assert_eq!(mmc1.read_prg(0xC000), 3);
+
+        // 32KB mode ignores low bit
+        write_serial(&mut mmc1, 0x8000, 0b0_0000);
+        assert_eq!(mmc1.read_prg(0x8000), 2);
+        assert_eq!(mmc1.read_prg(0xC000), 3);
+    }
+
+    #[test]
+    fn test_reset_bit_restores_prg_mode_3() {
+        let mut mmc1 = test_mmc1();
+        write_serial(&mut mmc1, 0x8000, 0b0_0000);
+        mmc1.write_prg(0xE000, 1);
+        mmc1.write_prg(0x8000, 0x80);
+        assert_eq!(mmc1.read_prg(0xC000), 7);
+
+        // partially shifted value was discarded
+        write_serial(&mut mmc1, 0xE000, 5);
+        assert_eq!(mmc1.read_prg(0x8000), 5);
+    }
+
+    #[test]
+    fn test_write_on_the_next_cycle_is_ignored() {
+        let mut mmc1 = test_mmc1();
+        // INC $E000 on a ROM byte of 0x00: writes 0x00, then 0x01 on the following cycle
+        for _ in 0..5 {
+            mmc1.tick(1);
+            mmc1.write_prg(0xE000, 0);
+            mmc1.tick(1);
+            mmc1.write_prg(0xE000, 1);
+            mmc1.tick(1);
+        }
+        assert_eq!(mmc1.read_prg(0x8000), 0);
+
+        // a reset write right after another write is ignored as well
+        mmc1.tick(1);
+        mmc1.write_prg(0xE000, 1);
+        mmc1.tick(1);
+        mmc1.write_prg(0xE000, 0x80);
+        for bit in [0, 1, 0, 0].iter() {
+            mmc1.tick(2);
+            mmc1.write_prg(0xE000, *bit);
+        }
+        assert_eq!(mmc1.read_prg(0x8000), 0b0101);
+    }
+
+    #[test]
+    fn test_chr_banks_and_mirroring() {
+        let mut mmc1 = test_mmc1();
+        write_serial(&mut mmc1, 0x8000, 0b1_0010);
+        write_serial(&mut mmc1, 0xA000, 5);
+        write_serial(&mut mmc1, 0xC000, 2);
+
+        assert_eq!(mmc1.read_chr(0x0000), 5);
+        assert_eq!(mmc1.read_chr(0x1000), 2);
+        assert_eq!(mmc1.mirroring(), Mirroring::Vertical);
+
+        write_serial(&mut mmc1, 0x8000, 0b0_0001);
+        assert_eq!(mmc1.read_chr(0x0000), 4);
+        assert_eq!(mmc1.read_chr(0x1000), 5);
+        assert_eq!(mmc1.mirroring(), Mirroring::SingleScreenUpper);
+    }
+
+    #[test]
+    fn test_prg_rom_smaller_than_a_bank() {
+        let mut prg_rom = vec![0; 0x2000];
+        prg_rom[0x1FFC] = 0x34;
+        let mmc1 = Mmc1::new(prg_rom, vec![]);
+        assert_eq!(mmc1.read_prg(0x9FFC), 0x34);
+        assert_eq!(mmc1.read_prg(0xFFFC), 0x34);
+    }
+
+    #[test]
+    fn test_surom_outer_bank() {
+        let mut prg_rom = vec![];
+        for bank in 0..32 {
+            prg_rom.extend(vec![bank as u8; PRG_BANK_SIZE]);
+        }
+        let mut mmc1 = Mmc1::new(prg_rom, vec![]);
+        assert_eq!(mmc1.read_prg(0x8000), 0);
+        assert_eq!(mmc1.read_prg(0xC000), 15);
+
+        write_serial(&mut mmc1, 0xA000, 0b1_0000);
+        write_serial(&mut mmc1, 0xE000, 3);
+        assert_eq!(mmc1.read_prg(0x8000), 19);
+        assert_eq!(mmc1.read_prg(0xC000), 31);
+
+        // CHR-RAM is 8KB, the PRG select bit doesn't move it
+        mmc1.write_chr(0x0123, 0x66);
+        write_serial(&mut mmc1, 0xA000, 0);
+        assert_eq!(mmc1.read_chr(0x0123), 0x66);
+    }
+}
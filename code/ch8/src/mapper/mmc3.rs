@@ -0,0 +1,250 @@
+use crate::cartridge::Mirroring;
+use crate::mapper::chr::Chr;
+use crate::mapper::Mapper;
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x0400;
+
+// https://www.nesdev.org/wiki/MMC3
+//
+//  $8000-$9FFE (even)  Bank select
+//  $8001-$9FFF (odd)   Bank data
+//  $A000-$BFFE (even)  Mirroring
+//  $A001-$BFFF (odd)   PRG-RAM protect
+//  $C000-$DFFE (even)  IRQ latch
+//  $C001-$DFFF (odd)   IRQ reload
+//  $E000-$FFFE (even)  IRQ disable
+//  $E001-$FFFF (odd)   IRQ enable
+//
+// Bank select:
+//
+// 7  bit  0
+// ---- ----
+// CPMx xRRR
+// |||   |||
+// |||   +++- Specify which bank register to update on next write to Bank Data register
+// |||        (0-1: 2KB CHR banks, 2-5: 1KB CHR banks, 6-7: 8KB PRG banks)
+// ||+------- Nothing on the MMC3
+// |+-------- PRG-ROM bank mode (0: $8000-$9FFF swappable, $C000-$DFFF fixed to second-last bank;
+// |                             1: $C000-$DFFF swappable, $8000-$9FFF fixed to second-last bank)
+// +--------- CHR A12 inversion (0: two 2 KB banks at $0000-$0FFF, four 1 KB banks at $1000-$1FFF;
+//                               1: two 2 KB banks at $1000-$1FFF, four 1 KB banks at $0000-$0FFF)
+//
+// The scanline counter is clocked by rising edges of PPU A12. With the usual setup
+// (background at $0000, sprites at $1000) that happens once per rendered scanline.
+pub struct Mmc3 {
+    prg_rom: Vec<u8>,
+    chr: Chr,
+    four_screen: bool,
+
+    bank_select: u8,
+    registers: [u8; 8],
+    mirroring: Mirroring,
+
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enabled: bool,
+    irq_pending: bool,
+}
+
+impl Mmc3 {
+    pub fn new(prg_rom: Vec<u8>, chr_rom: Vec<u8>, mirroring: Mirroring) -> Self {
+        Mmc3 {
+            prg_rom,
+            chr: Chr::new(chr_rom),
+            four_screen: mirroring == Mirroring::FourScreen,
+            bank_select: 0,
+            registers: [0; 8],
+            mirroring,
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_enabled: false,
+            irq_pending: false,
+        }
+    }
+
+    fn prg_bank_count(&self) -> usize {
+        (self.prg_rom.len() / PRG_BANK_SIZE).max(1)
+    }
+
+    fn prg_bank(&self, addr: u16) -> usize {
+        // an 8KB image has no second last bank, the fixed banks all show the only one
+        let second_last = self.prg_bank_count().saturating_sub(2);
+        let prg_mode = self.bank_select & 0b0100_0000 != 0;
+        match (addr, prg_mode) {
+            (0x8000..=0x9FFF, false) => self.registers[6] as usize,
+            (0x8000..=0x9FFF, true) => second_last,
+            (0xA000..=0xBFFF, _) => self.registers[7] as usize,
+            (0xC000..=0xDFFF, false) => second_last,
+            (0xC000..=0xDFFF, true) => self.registers[6] as usize,
+            _ => self.prg_bank_count() - 1,
+        }
+    }
+
+    fn chr_bank(&self, addr: u16) -> usize {
+        let chr_inversion = self.bank_select & 0b1000_0000 != 0;
+        // with inversion the two halves of the pattern table are swapped
+        let addr = if chr_inversion { addr ^ 0x1000 } else { addr };
+        match addr {
+            0x0000..=0x07FF => {
+                (self.registers[0] & 0b1111_1110) as usize + (addr >= 0x400) as usize
+            }
+            0x0800..=0x0FFF => {
+                (self.registers[1] & 0b1111_1110) as usize + (addr >= 0xC00) as usize
+            }
+            0x1000..=0x13FF => self.registers[2] as usize,
+            0x1400..=0x17FF => self.registers[3] as usize,
+            0x1800..=0x1BFF => self.registers[4] as usize,
+            _ => self.registers[5] as usize,
+        }
+    }
+
+    fn chr_index(&self, addr: u16) -> usize {
+        self.chr_bank(addr) * CHR_BANK_SIZE + (addr as usize & (CHR_BANK_SIZE - 1))
+    }
+}
+
+impl Mapper for Mmc3 {
+    fn read_prg(&self, addr: u16) -> u8 {
+        let bank = self.prg_bank(addr) % self.prg_bank_count();
+        self.prg_rom[bank * PRG_BANK_SIZE + (addr as usize & (PRG_BANK_SIZE - 1))]
+    }
+
+    fn write_prg(&mut self, addr: u16, data: u8) {
+        let even = addr & 1 == 0;
+        match (addr, even) {
+            (0x8000..=0x9FFF, true) => self.bank_select = data,
+            (0x8000..=0x9FFF, false) => {
+                self.registers[(self.bank_select & 0b111) as usize] = data;
+            }
+            (0xA000..=0xBFFF, true) => {
+                if !self.four_screen {
+                    self.mirroring = if data & 1 == 0 {
+                        Mirroring::Vertical
+                    } else {
+                        Mirroring::Horizontal
+                    };
+                }
+            }
+            (0xA000..=0xBFFF, false) => { /* PRG-RAM protect */ }
+            (0xC000..=0xDFFF, true) => self.irq_latch = data,
+            (0xC000..=0xDFFF, false) => {
+                self.irq_counter = 0;
+                self.irq_reload = true;
+            }
+            (_, true) => {
+                self.irq_enabled = false;
+                self.irq_pending = false;
+            }
+            (_, false) => self.irq_enabled = true,
+        }
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr.read(self.chr_index(addr))
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        self.chr.write(self.chr_index(addr), data);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring.clone()
+    }
+
+    fn ppu_a12_rise(&mut self) {
+        if self.irq_counter == 0 || self.irq_reload {
+            self.irq_counter = self.irq_latch;
+            self.irq_reload = false;
+        } else {
+            self.irq_counter -= 1;
+        }
+
+        if self.irq_counter == 0 && self.irq_enabled {
+            self.irq_pending = true;
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq_pending
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_mmc3() -> Mmc3 {
+        let mut prg_rom = vec![];
+        for bank in 0..16 {
+            prg_rom.extend(vec![bank as u8; PRG_BANK_SIZE]);
+        }
+        let mut chr_rom = vec![];
+        for bank in 0..32 {
+            chr_rom.extend(vec![bank as u8; CHR_BANK_SIZE]);
+        }
+        Mmc3::new(prg_rom, chr_rom, Mirroring::Vertical)
+    }
+
+    #[test]
+    fn test_small_prg_rom() {
+        let mut mmc3 = Mmc3::new(vec![7; PRG_BANK_SIZE], vec![], Mirroring::Vertical);
+        mmc3.write_prg(0x8000, 0b0100_0000);
+        assert_eq!(mmc3.read_prg(0x8000), 7);
+        assert_eq!(mmc3.read_prg(0xE000), 7);
+    }
+
+    #[test]
+    fn test_prg_bank_modes() {
+        let mut mmc3 = test_mmc3();
+        mmc3.write_prg(0x8000, 6);
+        mmc3.write_prg(0x8001, 3);
+        mmc3.write_prg(0x8000, 7);
+        mmc3.write_prg(0x8001, 4);
+
+        assert_eq!(mmc3.read_prg(0x8000), 3);
+        assert_eq!(mmc3.read_prg(0xA000), 4);
+        assert_eq!(mmc3.read_prg(0xC000), 14);
+        assert_eq!(mmc3.read_prg(0xE000), 15);
+
+        mmc3.write_prg(0x8000, 0b0100_0000);
+        assert_eq!(mmc3.read_prg(0x8000), 14);
+        assert_eq!(mmc3.read_prg(0xC000), 3);
+    }
+
+    #[test]
+    fn test_chr_inversion() {
+        let mut mmc3 = test_mmc3();
+        mmc3.write_prg(0x8000, 0);
+        mmc3.write_prg(0x8001, 9); // low bit is ignored for 2KB banks
+        mmc3.write_prg(0x8000, 2);
+        mmc3.write_prg(0x8001, 20);
+
+        assert_eq!(mmc3.read_chr(0x0000), 8);
+        assert_eq!(mmc3.read_chr(0x0400), 9);
+        assert_eq!(mmc3.read_chr(0x1000), 20);
+
+        mmc3.write_prg(0x8000, 0b1000_0000);
+        assert_eq!(mmc3.read_chr(0x0000), 20);
+        assert_eq!(mmc3.read_chr(0x1400), 9);
+    }
+
+    #[test]
+    fn test_scanline_irq() {
+        let mut mmc3 = test_mmc3();
+        mmc3.write_prg(0xC000, 2);
+        mmc3.write_prg(0xC001, 0);
+        mmc3.write_prg(0xE001, 0);
+
+        mmc3.ppu_a12_rise(); // reload to 2
+        mmc3.ppu_a12_rise();
+        assert!(!mmc3.irq_pending());
+        mmc3.ppu_a12_rise();
+        assert!(mmc3.irq_pending());
+
+        mmc3.write_prg(0xE000, 0);
+        assert!(!mmc3.irq_pending());
+    }
+}
@@ -0,0 +1,147 @@
+use crate::cartridge::Mirroring;
+use crate::cartridge::Rom;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+pub mod axrom;
+pub mod chr;
+pub mod cnrom;
+pub mod color_dreams;
+pub mod fds;
+pub mod gxrom;
+pub mod mmc1;
+pub mod mmc3;
+pub mod nrom;
+pub mod uxrom;
+
+use axrom::Axrom;
+use cnrom::Cnrom;
+use color_dreams::ColorDreams;
+use fds::Fds;
+use gxrom::Gxrom;
+use mmc1::Mmc1;
+use mmc3::Mmc3;
+use nrom::Nrom;
+use uxrom::Uxrom;
+
+// https://www.nesdev.org/wiki/Mapper
+//
+// Cartridge board logic. The mapper sits between the CPU/PPU buses and the
+// cartridge memory chips: it decides which PRG bank is visible at $8000-$FFFF,
+// which CHR bank is visible at PPU $0000-$1FFF and, on some boards, how the
+// nametables are mirrored.
+pub trait Mapper {
+    fn read_prg(&self, addr: u16) -> u8;
+
+    fn write_prg(&mut self, addr: u16, data: u8);
+
+    fn read_chr(&self, addr: u16) -> u8;
+
+    fn write_chr(&mut self, addr: u16, data: u8);
+
+    fn mirroring(&self) -> Mirroring;
+
+    // called on every rising edge of PPU address line A12 (bit 12 of the CHR address)
+    fn ppu_a12_rise(&mut self) {}
+
+    // level of the cartridge IRQ line
+    fn irq_pending(&self) -> bool {
+        false
+    }
+
+    // $4020-$5FFF, unused by most boards
+    fn read_expansion(&mut self, _addr: u16) -> u8 {
+        0
+    }
+
+    // what read_expansion would return, without acknowledging anything
+    fn peek_expansion(&self, _addr: u16) -> u8 {
+        0
+    }
+
+    fn write_expansion(&mut self, _addr: u16, _data: u8) {}
+
+    // called with the number of CPU cycles elapsed
+    fn tick(&mut self, _cycles: u8) {}
+
+    // level of the sound channels on the cartridge, 0.0..=1.0
+    fn expansion_audio(&self) -> f32 {
+        0.0
+    }
+
+    // disk drive, only the FDS has one
+    fn disk_side_count(&self) -> usize {
+        0
+    }
+
+    fn inserted_disk_side(&self) -> Option<usize> {
+        None
+    }
+
+    // None ejects the disk
+    fn insert_disk_side(&mut self, _side: Option<usize>) {}
+
+    // what the board keeps in the .sav file besides battery-backed PRG-RAM:
+    // the disk sides in .fds layout once the game has written to them
+    fn save_data(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn load_save_data(&mut self, _data: &[u8]) {}
+}
+
+const SUPPORTED_MAPPERS: [u16; 9] = [0, 1, 2, 3, 4, 7, 11, 20, 66];
+
+pub fn is_supported(mapper: u16) -> bool {
+    SUPPORTED_MAPPERS.contains(&mapper)
+}
+
+// the smallest PRG-ROM the board can map, one PRG bank
+// (16KB AxROM images are mirrored into the 32KB window)
+pub fn min_prg_rom_size(mapper: u16) -> usize {
+    match mapper {
+        4 => 0x2000,
+        11 | 66 => 0x8000,
+        20 => fds::BIOS_SIZE,
+        _ => 0x4000,
+    }
+}
+
+pub fn from_rom(rom: Rom) -> Rc<RefCell<dyn Mapper>> {
+    match rom.mapper {
+        0 => Rc::new(RefCell::new(Nrom::new(
+            rom.prg_rom,
+            rom.chr_rom,
+            rom.screen_mirroring,
+        ))),
+        1 => Rc::new(RefCell::new(Mmc1::new(rom.prg_rom, rom.chr_rom))),
+        2 => Rc::new(RefCell::new(Uxrom::new(
+            rom.prg_rom,
+            rom.chr_rom,
+            rom.screen_mirroring,
+        ))),
+        3 => Rc::new(RefCell::new(Cnrom::new(
+            rom.prg_rom,
+            rom.chr_rom,
+            rom.screen_mirroring,
+        ))),
+        4 => Rc::new(RefCell::new(Mmc3::new(
+            rom.prg_rom,
+            rom.chr_rom,
+            rom.screen_mirroring,
+        ))),
+        7 => Rc::new(RefCell::new(Axrom::new(rom.prg_rom, rom.chr_rom))),
+        11 => Rc::new(RefCell::new(ColorDreams::new(
+            rom.prg_rom,
+            rom.chr_rom,
+            rom.screen_mirroring,
+        ))),
+        20 => Rc::new(RefCell::new(Fds::new(rom.prg_rom, rom.disk_sides))),
+        66 => Rc::new(RefCell::new(Gxrom::new(
+            rom.prg_rom,
+            rom.chr_rom,
+            rom.screen_mirroring,
+        ))),
+        _ => panic!("Mapper {} is not supported", rom.mapper),
+    }
+}
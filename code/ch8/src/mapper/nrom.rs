@@ -0,0 +1,48 @@
+use crate::cartridge::Mirroring;
+use crate::mapper::chr::Chr;
+use crate::mapper::Mapper;
+
+// https://www.nesdev.org/wiki/NROM
+// No bank switching: 16KB or 32KB of PRG-ROM and 8KB of CHR-ROM.
+// 16KB carts have $C000-$FFFF mirroring $8000-$BFFF.
+pub struct Nrom {
+    prg_rom: Vec<u8>,
+    chr: Chr,
+    mirroring: Mirroring,
+}
+
+impl Nrom {
+    pub fn new(prg_rom: Vec<u8>, chr_rom: Vec<u8>, mirroring: Mirroring) -> Self {
+        Nrom {
+            prg_rom,
+            chr: Chr::new(chr_rom),
+            mirroring,
+        }
+    }
+}
+
+impl Mapper for Nrom {
+    fn read_prg(&self, mut addr: u16) -> u8 {
+        addr -= 0x8000;
+        if self.prg_rom.len() == 0x4000 && addr >= 0x4000 {
+            //mirror if needed
+            addr %= 0x4000;
+        }
+        self.prg_rom[addr as usize]
+    }
+
+    // no registers on the board, the write goes nowhere
+    fn write_prg(&mut self, _addr: u16, _data: u8) {}
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr.read(addr as usize)
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        self.chr.write(addr as usize, data);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring.clone()
+    }
+}
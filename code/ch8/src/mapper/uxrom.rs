@@ -0,0 +1,89 @@
+use crate::cartridge::Mirroring;
+use crate::mapper::chr::Chr;
+use crate::mapper::Mapper;
+
+const PRG_BANK_SIZE: usize = 0x4000;
+
+// https://www.nesdev.org/wiki/UxROM
+//
+//  $8000-$BFFF  16 KB switchable PRG-ROM bank
+//  $C000-$FFFF  16 KB PRG-ROM bank, fixed to the last bank
+//
+// Any write to $8000-$FFFF selects the bank visible at $8000.
+// Boards almost always use 8KB of CHR-RAM instead of CHR-ROM.
+pub struct Uxrom {
+    prg_rom: Vec<u8>,
+    chr: Chr,
+    mirroring: Mirroring,
+    prg_bank: u8,
+}
+
+impl Uxrom {
+    pub fn new(prg_rom: Vec<u8>, chr_rom: Vec<u8>, mirroring: Mirroring) -> Self {
+        Uxrom {
+            prg_rom,
+            chr: Chr::new(chr_rom),
+            mirroring,
+            prg_bank: 0,
+        }
+    }
+}
+
+impl Mapper for Uxrom {
+    fn read_prg(&self, addr: u16) -> u8 {
+        let bank_count = (self.prg_rom.len() / PRG_BANK_SIZE).max(1);
+        let bank = match addr {
+            0x8000..=0xBFFF => self.prg_bank as usize % bank_count,
+            _ => bank_count - 1,
+        };
+        let index = bank * PRG_BANK_SIZE + (addr as usize & (PRG_BANK_SIZE - 1));
+        self.prg_rom[index % self.prg_rom.len()]
+    }
+
+    fn write_prg(&mut self, _addr: u16, data: u8) {
+        self.prg_bank = data;
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr.read(addr as usize)
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        self.chr.write(addr as usize, data);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bank_switching() {
+        let mut prg_rom = vec![];
+        for bank in 0..8 {
+            prg_rom.extend(vec![bank as u8; PRG_BANK_SIZE]);
+        }
+        let mut uxrom = Uxrom::new(prg_rom, vec![], Mirroring::Vertical);
+
+        assert_eq!(uxrom.read_prg(0x8000), 0);
+        assert_eq!(uxrom.read_prg(0xC000), 7);
+
+        uxrom.write_prg(0x8000, 5);
+        assert_eq!(uxrom.read_prg(0xBFFF), 5);
+        assert_eq!(uxrom.read_prg(0xFFFF), 7);
+    }
+
+    #[test]
+    fn test_prg_rom_smaller_than_a_bank() {
+        let mut prg_rom = vec![0; 0x2000];
+        prg_rom[0x1FFC] = 0x34;
+        let mut uxrom = Uxrom::new(prg_rom, vec![], Mirroring::Vertical);
+        uxrom.write_prg(0x8000, 3);
+        assert_eq!(uxrom.read_prg(0x9FFC), 0x34);
+        assert_eq!(uxrom.read_prg(0xFFFC), 0x34);
+    }
+}
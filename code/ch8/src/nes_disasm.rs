@@ -0,0 +1,114 @@
+use nes_book_emu::cartridge::Header;
+use nes_book_emu::disasm;
+use std::process;
+
+const USAGE: &str = "usage: nes-disasm [--bank N] [--bank-size KB] [--origin ADDR] <rom.nes>";
+const DEFAULT_BANK_SIZE: usize = 0x4000;
+const DEFAULT_BANK_ORIGIN: u16 = 0x8000;
+
+struct Options {
+    path: String,
+    bank: Option<usize>,
+    bank_size: usize,
+    origin: Option<u16>,
+}
+
+// accepts c000, $c000 and 0xc000
+fn parse_addr(value: &str) -> Option<u16> {
+    let digits = value
+        .strip_prefix('$')
+        .or_else(|| value.strip_prefix("0x"))
+        .unwrap_or(value);
+    u16::from_str_radix(digits, 16).ok()
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut options = Options {
+        path: String::new(),
+        bank: None,
+        bank_size: DEFAULT_BANK_SIZE,
+        origin: None,
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--bank" => {
+                let bank = value()?;
+                options.bank = Some(bank.parse().map_err(|_| format!("bad bank {}", bank))?);
+            }
+            "--bank-size" => {
+                let size = value()?;
+                let kb: usize = size
+                    .parse()
+                    .map_err(|_| format!("bad bank size {}", size))?;
+                if kb == 0 || kb > 64 {
+                    return Err(format!("bad bank size {}", size));
+                }
+                options.bank_size = kb * 1024;
+            }
+            "--origin" => {
+                let origin = value()?;
+                options.origin = Some(parse_addr(origin).ok_or(format!("bad origin {}", origin))?);
+            }
+            _ if options.path.is_empty() && !arg.starts_with("--") => options.path = arg.clone(),
+            _ => return Err(format!("unexpected argument {}", arg)),
+        }
+    }
+    if options.path.is_empty() {
+        return Err("no rom given".to_string());
+    }
+    Ok(options)
+}
+
+fn fail(message: String) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
+
+// nes-disasm game.nes > game.s
+// nes-disasm --bank 2 game.nes                            16KB bank 2 at $8000
+// nes-disasm --bank 7 --bank-size 8 --origin e000 game.nes
+//
+// Only the header and PRG-ROM are read, so the mapper doesn't have to be one
+// the emulator supports.
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let options = parse_args(&args).unwrap_or_else(|e| fail(format!("{}\n{}", e, USAGE)));
+
+    let bytes =
+        std::fs::read(&options.path).unwrap_or_else(|e| fail(format!("{}: {}", options.path, e)));
+    let prg_rom = Header::parse(&bytes)
+        .and_then(|header| header.prg_rom(&bytes))
+        .unwrap_or_else(|e| fail(format!("{}: {}", options.path, e)));
+
+    let (prg, origin) = match options.bank {
+        Some(bank) => {
+            let start = bank * options.bank_size;
+            let prg = prg_rom
+                .get(start..start + options.bank_size)
+                .unwrap_or_else(|| {
+                    fail(format!(
+                        "{}: no bank {}, PRG-ROM has {} banks of {}KB",
+                        options.path,
+                        bank,
+                        prg_rom.len() / options.bank_size,
+                        options.bank_size / 1024
+                    ))
+                });
+            (prg, options.origin.unwrap_or(DEFAULT_BANK_ORIGIN))
+        }
+        None => {
+            // the vectors are in the last bank, which every mapper keeps at the top of the address space
+            let len = prg_rom.len().min(0x8000);
+            let origin = (0x10000 - len) as u16;
+            (
+                &prg_rom[prg_rom.len() - len..],
+                options.origin.unwrap_or(origin),
+            )
+        }
+    };
+    let disassembly = disasm::disassemble(prg, origin)
+        .unwrap_or_else(|e| fail(format!("{}: {}", options.path, e)));
+    print!("{}", disassembly.listing());
+}
@@ -0,0 +1,446 @@
+use crate::checksum::crc32;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Soft-patching: applies IPS, UPS or BPS patches to the raw rom bytes before
+// they are parsed, so the original dump stays untouched on disk.
+//
+// https://zerosoft.zophar.net/ips.php
+// https://www.romhacking.net/documents/392/ (UPS)
+// https://www.romhacking.net/documents/746/ (BPS)
+
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_EOF: &[u8] = b"EOF";
+const UPS_MAGIC: &[u8] = b"UPS1";
+const BPS_MAGIC: &[u8] = b"BPS1";
+const FOOTER_SIZE: usize = 12;
+
+pub const PATCH_EXTENSIONS: [&str; 3] = ["ips", "ups", "bps"];
+
+#[derive(Debug, PartialEq)]
+pub enum PatchError {
+    UnknownFormat,
+    Truncated,
+    OutOfBounds,
+    SourceChecksumMismatch,
+    TargetChecksumMismatch,
+    PatchChecksumMismatch,
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PatchError::UnknownFormat => write!(f, "Patch is not in IPS, UPS or BPS format"),
+            PatchError::Truncated => write!(f, "Patch is truncated"),
+            PatchError::OutOfBounds => write!(f, "Patch refers to data outside of the rom"),
+            PatchError::SourceChecksumMismatch => {
+                write!(
+                    f,
+                    "Patch was made for a different rom (source checksum mismatch)"
+                )
+            }
+            PatchError::TargetChecksumMismatch => {
+                write!(f, "Patched rom doesn't match target checksum")
+            }
+            PatchError::PatchChecksumMismatch => write!(f, "Patch file is corrupted"),
+        }
+    }
+}
+
+impl std::error::Error for PatchError {}
+
+// detects the patch format from its magic
+pub fn apply(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if patch.starts_with(IPS_MAGIC) {
+        apply_ips(rom, patch)
+    } else if patch.starts_with(UPS_MAGIC) {
+        apply_ups(rom, patch)
+    } else if patch.starts_with(BPS_MAGIC) {
+        apply_bps(rom, patch)
+    } else {
+        Err(PatchError::UnknownFormat)
+    }
+}
+
+// Looks for <rom>.ips / <rom>.ups / <rom>.bps next to the rom and applies the first one found.
+pub fn auto_patch(rom_path: &Path, rom: Vec<u8>) -> io::Result<Vec<u8>> {
+    for ext in PATCH_EXTENSIONS.iter() {
+        let patch_path = rom_path.with_extension(ext);
+        if patch_path.exists() {
+            let patch = fs::read(&patch_path)?;
+            return apply(&rom, &patch).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}: {}", patch_path.display(), e),
+                )
+            });
+        }
+    }
+    Ok(rom)
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8], pos: usize) -> Self {
+        Reader { data, pos }
+    }
+
+    fn u8(&mut self) -> Result<u8, PatchError> {
+        let byte = *self.data.get(self.pos).ok_or(PatchError::Truncated)?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], PatchError> {
+        let end = self.pos.checked_add(len).ok_or(PatchError::Truncated)?;
+        let bytes = self.data.get(self.pos..end).ok_or(PatchError::Truncated)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u16_be(&mut self) -> Result<usize, PatchError> {
+        Ok((self.u8()? as usize) << 8 | self.u8()? as usize)
+    }
+
+    fn u24_be(&mut self) -> Result<usize, PatchError> {
+        Ok((self.u8()? as usize) << 16 | self.u16_be()?)
+    }
+
+    // variable-length integer shared by UPS and BPS
+    fn varint(&mut self) -> Result<usize, PatchError> {
+        let mut value: usize = 0;
+        let mut shift: usize = 1;
+        loop {
+            let byte = self.u8()?;
+            value = ((byte & 0x7f) as usize)
+                .checked_mul(shift)
+                .and_then(|v| v.checked_add(value))
+                .ok_or(PatchError::OutOfBounds)?;
+            if byte & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift.checked_shl(7).ok_or(PatchError::OutOfBounds)?;
+            value = value.checked_add(shift).ok_or(PatchError::OutOfBounds)?;
+        }
+    }
+}
+
+fn resize_checked(target: &mut Vec<u8>, len: usize) -> Result<(), PatchError> {
+    // IPS offsets are 24 bit, UPS/BPS sizes come from the patch and are checked against the footer
+    if len > 0x1000_0000 {
+        return Err(PatchError::OutOfBounds);
+    }
+    target.resize(len, 0);
+    Ok(())
+}
+
+pub fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let mut target = rom.to_vec();
+    let mut reader = Reader::new(patch, IPS_MAGIC.len());
+
+    loop {
+        if reader.data[reader.pos..].starts_with(IPS_EOF) {
+            reader.pos += IPS_EOF.len();
+            break;
+        }
+        let offset = reader.u24_be()?;
+        let size = reader.u16_be()?;
+
+        let (data, len) = if size == 0 {
+            // RLE record
+            let len = reader.u16_be()?;
+            (None, len)
+        } else {
+            (Some(reader.bytes(size)?), size)
+        };
+
+        if target.len() < offset + len {
+            resize_checked(&mut target, offset + len)?;
+        }
+        match data {
+            Some(bytes) => target[offset..offset + len].copy_from_slice(bytes),
+            None => {
+                let value = reader.u8()?;
+                target[offset..offset + len]
+                    .iter_mut()
+                    .for_each(|b| *b = value);
+            }
+        }
+    }
+
+    // optional truncation extension
+    if let Ok(len) = reader.u24_be() {
+        target.truncate(len);
+    }
+
+    Ok(target)
+}
+
+struct Footer {
+    source_crc: u32,
+    target_crc: u32,
+}
+
+fn read_footer(patch: &[u8], magic: &[u8]) -> Result<Footer, PatchError> {
+    if patch.len() < magic.len() + FOOTER_SIZE {
+        return Err(PatchError::Truncated);
+    }
+    let footer = &patch[patch.len() - FOOTER_SIZE..];
+    let crc =
+        |i: usize| u32::from_le_bytes([footer[i], footer[i + 1], footer[i + 2], footer[i + 3]]);
+
+    if crc32(&patch[..patch.len() - 4]) != crc(8) {
+        return Err(PatchError::PatchChecksumMismatch);
+    }
+    Ok(Footer {
+        source_crc: crc(0),
+        target_crc: crc(4),
+    })
+}
+
+pub fn apply_ups(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let footer = read_footer(patch, UPS_MAGIC)?;
+    if crc32(rom) != footer.source_crc {
+        return Err(PatchError::SourceChecksumMismatch);
+    }
+
+    let body_end = patch.len() - FOOTER_SIZE;
+    let mut reader = Reader::new(&patch[..body_end], UPS_MAGIC.len());
+    let source_size = reader.varint()?;
+    let target_size = reader.varint()?;
+    if source_size != rom.len() {
+        return Err(PatchError::SourceChecksumMismatch);
+    }
+
+    let mut target = rom.to_vec();
+    resize_checked(&mut target, target_size)?;
+
+    let mut pos: usize = 0;
+    while reader.pos < body_end {
+        pos = pos
+            .checked_add(reader.varint()?)
+            .ok_or(PatchError::OutOfBounds)?;
+        loop {
+            let xor = reader.u8()?;
+            if pos < target.len() {
+                target[pos] ^= xor;
+            }
+            pos = pos.checked_add(1).ok_or(PatchError::OutOfBounds)?;
+            if xor == 0 {
+                break;
+            }
+        }
+    }
+
+    if crc32(&target) != footer.target_crc {
+        return Err(PatchError::TargetChecksumMismatch);
+    }
+    Ok(target)
+}
+
+fn relative_offset(offset: usize, delta: usize) -> Result<usize, PatchError> {
+    let magnitude = delta >> 1;
+    let result = if delta & 1 == 1 {
+        offset.checked_sub(magnitude)
+    } else {
+        offset.checked_add(magnitude)
+    };
+    result.ok_or(PatchError::OutOfBounds)
+}
+
+pub fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let footer = read_footer(patch, BPS_MAGIC)?;
+    if crc32(rom) != footer.source_crc {
+        return Err(PatchError::SourceChecksumMismatch);
+    }
+
+    let body_end = patch.len() - FOOTER_SIZE;
+    let mut reader = Reader::new(&patch[..body_end], BPS_MAGIC.len());
+    let source_size = reader.varint()?;
+    let target_size = reader.varint()?;
+    let metadata_size = reader.varint()?;
+    reader.bytes(metadata_size)?;
+    if source_size != rom.len() {
+        return Err(PatchError::SourceChecksumMismatch);
+    }
+    if target_size > 0x1000_0000 {
+        return Err(PatchError::OutOfBounds);
+    }
+
+    let mut target: Vec<u8> = Vec::with_capacity(target_size);
+    let mut source_offset: usize = 0;
+    let mut target_offset: usize = 0;
+
+    while reader.pos < body_end {
+        let data = reader.varint()?;
+        let len = (data >> 2) + 1;
+        if target.len() + len > target_size {
+            return Err(PatchError::OutOfBounds);
+        }
+
+        match data & 0b11 {
+            // SourceRead
+            0 => {
+                let start = target.len();
+                let bytes = rom.get(start..start + len).ok_or(PatchError::OutOfBounds)?;
+                target.extend_from_slice(bytes);
+            }
+            // TargetRead
+            1 => target.extend_from_slice(reader.bytes(len)?),
+            // SourceCopy
+            2 => {
+                source_offset = relative_offset(source_offset, reader.varint()?)?;
+                let end = source_offset
+                    .checked_add(len)
+                    .ok_or(PatchError::OutOfBounds)?;
+                let bytes = rom.get(source_offset..end).ok_or(PatchError::OutOfBounds)?;
+                target.extend_from_slice(bytes);
+                source_offset += len;
+            }
+            // TargetCopy, byte by byte as source and destination may overlap
+            _ => {
+                target_offset = relative_offset(target_offset, reader.varint()?)?;
+                for _ in 0..len {
+                    let byte = *target.get(target_offset).ok_or(PatchError::OutOfBounds)?;
+                    target.push(byte);
+                    target_offset += 1;
+                }
+            }
+        }
+    }
+
+    if target.len() != target_size || crc32(&target) != footer.target_crc {
+        return Err(PatchError::TargetChecksumMismatch);
+    }
+    Ok(target)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn encode_varint(mut value: usize, out: &mut Vec<u8>) {
+        loop {
+            let x = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                out.push(0x80 | x);
+                return;
+            }
+            out.push(x);
+            value -= 1;
+        }
+    }
+
+    fn with_footer(mut patch: Vec<u8>, source: &[u8], target: &[u8]) -> Vec<u8> {
+        patch.extend(&crc32(source).to_le_bytes());
+        patch.extend(&crc32(target).to_le_bytes());
+        let patch_crc = crc32(&patch);
+        patch.extend(&patch_crc.to_le_bytes());
+        patch
+    }
+
+    #[test]
+    fn test_ips() {
+        let rom = vec![0; 16];
+        let mut patch = b"PATCH".to_vec();
+        patch.extend(&[0x00, 0x00, 0x02, 0x00, 0x02, 0xAA, 0xBB]);
+        // RLE: 3 times 0x11 at offset 0x0e, grows the rom
+        patch.extend(&[0x00, 0x00, 0x0e, 0x00, 0x00, 0x00, 0x03, 0x11]);
+        patch.extend(b"EOF");
+
+        let result = apply(&rom, &patch).unwrap();
+
+        assert_eq!(result.len(), 17);
+        assert_eq!(&result[0..4], &[0, 0, 0xAA, 0xBB]);
+        assert_eq!(&result[14..], &[0x11, 0x11, 0x11]);
+    }
+
+    #[test]
+    fn test_ups() {
+        let source = vec![1, 2, 3, 4, 5, 6];
+        let target = vec![1, 9, 3, 4, 5, 6, 7];
+
+        let mut patch = b"UPS1".to_vec();
+        encode_varint(source.len(), &mut patch);
+        encode_varint(target.len(), &mut patch);
+        encode_varint(1, &mut patch);
+        patch.extend(&[2 ^ 9, 0]);
+        encode_varint(3, &mut patch);
+        patch.extend(&[7, 0]);
+        let patch = with_footer(patch, &source, &target);
+
+        assert_eq!(apply(&source, &patch).unwrap(), target);
+        assert_eq!(
+            apply(&[1, 2, 3, 4, 5, 0], &patch),
+            Err(PatchError::SourceChecksumMismatch)
+        );
+    }
+
+    #[test]
+    fn test_bps() {
+        let source = vec![1, 2, 3, 4];
+        let target = vec![1, 2, 0xAA, 3, 4, 3, 4, 3];
+
+        let mut patch = b"BPS1".to_vec();
+        encode_varint(source.len(), &mut patch);
+        encode_varint(target.len(), &mut patch);
+        encode_varint(0, &mut patch);
+        // SourceRead 2
+        encode_varint((2 - 1) << 2, &mut patch);
+        // TargetRead 1
+        encode_varint(1, &mut patch);
+        patch.push(0xAA);
+        // SourceCopy 2 from offset +2
+        encode_varint((2 - 1) << 2 | 2, &mut patch);
+        encode_varint(2 << 1, &mut patch);
+        // TargetCopy 3 from offset +3 (overlapping)
+        encode_varint((3 - 1) << 2 | 3, &mut patch);
+        encode_varint(3 << 1, &mut patch);
+        let mut patch = with_footer(patch, &source, &target);
+
+        assert_eq!(apply(&source, &patch).unwrap(), target);
+
+        patch[5] ^= 1;
+        assert_eq!(
+            apply(&source, &patch),
+            Err(PatchError::PatchChecksumMismatch)
+        );
+    }
+
+    #[test]
+    fn test_bps_offset_overflow_is_an_error() {
+        let source = vec![1, 2, 3, 4];
+
+        let mut patch = b"BPS1".to_vec();
+        encode_varint(source.len(), &mut patch);
+        encode_varint(4, &mut patch);
+        encode_varint(0, &mut patch);
+        // SourceCopy 4 from an offset far past the end of the rom
+        encode_varint((4 - 1) << 2 | 2, &mut patch);
+        encode_varint(usize::MAX - 1, &mut patch);
+        let patch = with_footer(patch, &source, &[0; 4]);
+
+        assert_eq!(apply(&source, &patch), Err(PatchError::OutOfBounds));
+    }
+
+    #[test]
+    fn test_ups_offset_overflow_is_an_error() {
+        let source = vec![1, 2, 3, 4];
+
+        let mut patch = b"UPS1".to_vec();
+        encode_varint(source.len(), &mut patch);
+        encode_varint(source.len(), &mut patch);
+        encode_varint(usize::MAX, &mut patch);
+        patch.extend(&[0xAA, 0x00]);
+        let patch = with_footer(patch, &source, &source);
+
+        assert_eq!(apply(&source, &patch), Err(PatchError::OutOfBounds));
+    }
+}
@@ -1,15 +1,18 @@
 use crate::cartridge::Mirroring;
+use crate::mapper::nrom::Nrom;
+use crate::mapper::Mapper;
 use registers::addr::AddrRegister;
 use registers::control::ControlRegister;
 use registers::mask::MaskRegister;
 use registers::scroll::ScrollRegister;
 use registers::status::StatusRegister;
+use std::cell::RefCell;
+use std::rc::Rc;
 
 pub mod registers;
 
 pub struct NesPPU {
-    pub chr_rom: Vec<u8>,
-    pub mirroring: Mirroring,
+    pub mapper: Rc<RefCell<dyn Mapper>>,
     pub ctrl: ControlRegister,
     pub mask: MaskRegister,
     pub status: StatusRegister,
@@ -26,6 +29,8 @@ pub struct NesPPU {
     pub scanline: u16,
     cycles: usize,
     pub nmi_interrupt: Option<u8>,
+
+    a12: bool,
 }
 
 pub trait PPU {
@@ -44,13 +49,16 @@ pub trait PPU {
 
 impl NesPPU {
     pub fn new_empty_rom() -> Self {
-        NesPPU::new(vec![0; 2048], Mirroring::Horizontal)
+        NesPPU::new(Rc::new(RefCell::new(Nrom::new(
+            vec![],
+            vec![0; 2048],
+            Mirroring::Horizontal,
+        ))))
     }
 
-    pub fn new(chr_rom: Vec<u8>, mirroring: Mirroring) -> Self {
+    pub fn new(mapper: Rc<RefCell<dyn Mapper>>) -> Self {
         NesPPU {
-            chr_rom: chr_rom,
-            mirroring: mirroring,
+            mapper,
             ctrl: ControlRegister::new(),
             mask: MaskRegister::new(),
             status: StatusRegister::new(),
@@ -65,9 +73,23 @@ impl NesPPU {
             cycles: 0,
             scanline: 0,
             nmi_interrupt: None,
+
+            a12: false,
         }
     }
 
+    pub fn mirroring(&self) -> Mirroring {
+        self.mapper.borrow().mirroring()
+    }
+
+    pub fn read_chr(&self, addr: u16) -> u8 {
+        self.mapper.borrow().read_chr(addr)
+    }
+
+    fn write_chr(&mut self, addr: u16, value: u8) {
+        self.mapper.borrow_mut().write_chr(addr, value);
+    }
+
     // Horizontal:
     //   [ A ] [ a ]
     //   [ B ] [ b ]
@@ -75,25 +97,93 @@ impl NesPPU {
     // Vertical:
     //   [ A ] [ B ]
     //   [ a ] [ b ]
+
+    // Single screen (lower / upper):
+    //   [ A ] [ a ]
+    //   [ a ] [ a ]
     pub fn mirror_vram_addr(&self, addr: u16) -> u16 {
         let mirrored_vram = addr & 0b10111111111111; // mirror down 0x3000-0x3eff to 0x2000 - 0x2eff
         let vram_index = mirrored_vram - 0x2000; // to vram vector
         let name_table = vram_index / 0x400;
-        match (&self.mirroring, name_table) {
+        match (self.mirroring(), name_table) {
             (Mirroring::Vertical, 2) | (Mirroring::Vertical, 3) => vram_index - 0x800,
             (Mirroring::Horizontal, 2) => vram_index - 0x400,
             (Mirroring::Horizontal, 1) => vram_index - 0x400,
             (Mirroring::Horizontal, 3) => vram_index - 0x800,
+            (Mirroring::SingleScreenLower, _) => vram_index & 0x3ff,
+            (Mirroring::SingleScreenUpper, _) => 0x400 | (vram_index & 0x3ff),
             _ => vram_index,
         }
     }
 
+    // dot within the current scanline
+    pub fn dot(&self) -> usize {
+        self.cycles
+    }
+
+    // $2002 as a read would return it, without clearing vblank or the latches
+    pub fn peek_status(&self) -> u8 {
+        self.status.snapshot()
+    }
+
+    // $2007 as a read would return it, without advancing the address or refilling the buffer
+    pub fn peek_data(&self) -> u8 {
+        let addr = self.addr.get();
+        match addr {
+            0x3f00..=0x3fff => {
+                let mut index = addr & 0x1f;
+                // $3F10/$3F14/$3F18/$3F1C are mirrors of $3F00/$3F04/$3F08/$3F0C
+                if index & 0x13 == 0x10 {
+                    index -= 0x10;
+                }
+                self.palette_table[index as usize]
+            }
+            _ => self.internal_data_buf,
+        }
+    }
+
     fn increment_vram_addr(&mut self) {
         self.addr.increment(self.ctrl.vram_addr_increment());
     }
 
-    pub fn tick(&mut self, cycles: u8) -> bool {
+    // Mappers like MMC3 watch PPU address line A12 to count scanlines.
+    fn set_a12(&mut self, addr: u16) {
+        let a12 = addr & 0x1000 != 0;
+        if a12 && !self.a12 {
+            self.mapper.borrow_mut().ppu_a12_rise();
+        }
+        self.a12 = a12;
+    }
+
+    // While rendering, background tiles are fetched on dots 1-256 and 321-336,
+    // sprite tiles on dots 257-320. A12 follows the pattern table being read,
+    // so it can only change when switching between these fetch phases.
+    fn track_rendering_a12(&mut self, from_dot: usize, to_dot: usize) {
+        if !self.mask.show_background() && !self.mask.show_sprites() {
+            return;
+        }
+        if self.scanline >= 240 && self.scanline != 261 {
+            return;
+        }
+
+        let bknd_addr = self.ctrl.bknd_pattern_addr();
+        let sprt_addr = if self.ctrl.sprite_size() == 16 {
+            0x1000 // 8x16 sprites pick the table per tile, games put them at $1000
+        } else {
+            self.ctrl.sprt_pattern_addr()
+        };
+
+        for &(dot, addr) in &[(257, sprt_addr), (321, bknd_addr)] {
+            if from_dot < dot && dot <= to_dot {
+                self.set_a12(addr);
+            }
+        }
+    }
+
+    pub fn tick(&mut self, cycles: u16) -> bool {
+        let from_dot = self.cycles;
         self.cycles += cycles as usize;
+        self.track_rendering_a12(from_dot, self.cycles);
         if self.cycles >= 341 {
             if self.is_sprite_0_hit(self.cycles) {
                 self.status.set_sprite_zero_hit(true);
@@ -130,7 +220,6 @@ impl NesPPU {
         let x = self.oam_data[3] as usize;
         (y == self.scanline as usize) && x <= cycle && self.mask.show_sprites()
     }
-
 }
 
 impl PPU for NesPPU {
@@ -173,12 +262,14 @@ impl PPU for NesPPU {
 
     fn write_to_ppu_addr(&mut self, value: u8) {
         self.addr.update(value);
+        self.set_a12(self.addr.get());
     }
 
     fn write_to_data(&mut self, value: u8) {
         let addr = self.addr.get();
+        self.set_a12(addr);
         match addr {
-            0..=0x1fff => println!("attempt to write to chr rom space {}", addr),
+            0..=0x1fff => self.write_chr(addr, value),
             0x2000..=0x2fff => {
                 self.vram[self.mirror_vram_addr(addr) as usize] = value;
             }
@@ -199,13 +290,14 @@ impl PPU for NesPPU {
 
     fn read_data(&mut self) -> u8 {
         let addr = self.addr.get();
+        self.set_a12(addr);
 
         self.increment_vram_addr();
 
         match addr {
             0..=0x1fff => {
                 let result = self.internal_data_buf;
-                self.internal_data_buf = self.chr_rom[addr as usize];
+                self.internal_data_buf = self.read_chr(addr);
                 result
             }
             0x2000..=0x2fff => {
@@ -237,6 +329,15 @@ impl PPU for NesPPU {
 #[cfg(test)]
 pub mod test {
     use super::*;
+    use crate::mapper::mmc3::Mmc3;
+
+    fn ppu_with_mirroring(mirroring: Mirroring) -> NesPPU {
+        NesPPU::new(Rc::new(RefCell::new(Nrom::new(
+            vec![],
+            vec![0; 2048],
+            mirroring,
+        ))))
+    }
 
     #[test]
     fn test_ppu_vram_writes() {
@@ -248,6 +349,23 @@ pub mod test {
         assert_eq!(ppu.vram[0x0305], 0x66);
     }
 
+    #[test]
+    fn test_ppu_chr_ram_writes() {
+        let mut ppu = NesPPU::new(Rc::new(RefCell::new(Nrom::new(
+            vec![],
+            vec![],
+            Mirroring::Horizontal,
+        ))));
+        ppu.write_to_ppu_addr(0x12);
+        ppu.write_to_ppu_addr(0x34);
+        ppu.write_to_data(0x66);
+
+        ppu.write_to_ppu_addr(0x12);
+        ppu.write_to_ppu_addr(0x34);
+        ppu.read_data(); //load_into_buffer
+        assert_eq!(ppu.read_data(), 0x66);
+    }
+
     #[test]
     fn test_ppu_vram_reads() {
         let mut ppu = NesPPU::new_empty_rom();
@@ -328,7 +446,7 @@ pub mod test {
     //   [0x2800 a ] [0x2C00 b ]
     #[test]
     fn test_vram_vertical_mirror() {
-        let mut ppu = NesPPU::new(vec![0; 2048], Mirroring::Vertical);
+        let mut ppu = ppu_with_mirroring(Mirroring::Vertical);
 
         ppu.write_to_ppu_addr(0x20);
         ppu.write_to_ppu_addr(0x05);
@@ -353,6 +471,46 @@ pub mod test {
         assert_eq!(ppu.read_data(), 0x77); //read from B
     }
 
+    #[test]
+    fn test_vram_single_screen_mirror() {
+        let mut ppu = ppu_with_mirroring(Mirroring::SingleScreenUpper);
+
+        ppu.write_to_ppu_addr(0x20);
+        ppu.write_to_ppu_addr(0x05);
+        ppu.write_to_data(0x66);
+
+        assert_eq!(ppu.vram[0x0405], 0x66);
+
+        ppu.write_to_ppu_addr(0x2C);
+        ppu.write_to_ppu_addr(0x05);
+
+        ppu.read_data(); //load into buffer
+        assert_eq!(ppu.read_data(), 0x66);
+    }
+
+    #[test]
+    fn test_rendering_a12_rise_clocks_mapper_irq() {
+        let mmc3 = Rc::new(RefCell::new(Mmc3::new(
+            vec![0; 0x8000],
+            vec![0; 0x2000],
+            Mirroring::Vertical,
+        )));
+        let mut ppu = NesPPU::new(mmc3.clone());
+        mmc3.borrow_mut().write_prg(0xC000, 1); // irq every second scanline
+        mmc3.borrow_mut().write_prg(0xE001, 0);
+
+        ppu.write_to_ctrl(0b1000); // sprites at $1000
+        ppu.write_to_mask(0b1_1000);
+
+        ppu.tick(255);
+        assert!(!mmc3.borrow().irq_pending());
+        ppu.tick(86);
+        ppu.tick(255);
+        assert!(!mmc3.borrow().irq_pending());
+        ppu.tick(3);
+        assert!(mmc3.borrow().irq_pending());
+    }
+
     #[test]
     fn test_read_status_resets_latch() {
         let mut ppu = NesPPU::new_empty_rom();
@@ -37,6 +37,14 @@ fn sprite_palette(ppu: &NesPPU, pallete_idx: u8) -> [u8; 4] {
     ]
 }
 
+fn tile(ppu: &NesPPU, bank: u16, tile_idx: u16) -> [u8; 16] {
+    let mut tile = [0; 16];
+    for (i, byte) in tile.iter_mut().enumerate() {
+        *byte = ppu.read_chr(bank + tile_idx * 16 + i as u16);
+    }
+    tile
+}
+
 struct Rect {
     x1: usize,
     y1: usize,
@@ -65,7 +73,7 @@ fn render_name_table(ppu: &NesPPU, frame: &mut Frame, name_table: &[u8],
         let tile_column = i % 32;
         let tile_row = i / 32;
         let tile_idx = name_table[i] as u16;
-        let tile = &ppu.chr_rom[(bank + tile_idx * 16) as usize..=(bank + tile_idx * 16 + 15) as usize];
+        let tile = tile(ppu, bank, tile_idx);
         let palette = bg_pallette(ppu, attribute_table, tile_column, tile_row);
 
         for y in 0..=7 {
@@ -98,15 +106,22 @@ pub fn render(ppu: &NesPPU, frame: &mut Frame) {
     let scroll_x = (ppu.scroll.scroll_x) as usize;
     let scroll_y = (ppu.scroll.scroll_y) as usize;
 
-    let (main_nametable, second_nametable) = match (&ppu.mirroring, ppu.ctrl.nametable_addr()) {
+    let mirroring = ppu.mirroring();
+    let (main_nametable, second_nametable) = match (&mirroring, ppu.ctrl.nametable_addr()) {
         (Mirroring::Vertical, 0x2000) | (Mirroring::Vertical, 0x2800) | (Mirroring::Horizontal, 0x2000) | (Mirroring::Horizontal, 0x2400) => {
             (&ppu.vram[0..0x400], &ppu.vram[0x400..0x800])
         }
         (Mirroring::Vertical, 0x2400) | (Mirroring::Vertical, 0x2C00) | (Mirroring::Horizontal, 0x2800) | (Mirroring::Horizontal, 0x2C00) => {
             ( &ppu.vram[0x400..0x800], &ppu.vram[0..0x400])
         }
+        (Mirroring::SingleScreenLower, _) => {
+            (&ppu.vram[0..0x400], &ppu.vram[0..0x400])
+        }
+        (Mirroring::SingleScreenUpper, _) => {
+            (&ppu.vram[0x400..0x800], &ppu.vram[0x400..0x800])
+        }
         (_,_) => {
-            panic!("Not supported mirroring type {:?}", ppu.mirroring);
+            panic!("Not supported mirroring type {:?}", mirroring);
         }
     };
 
@@ -148,8 +163,7 @@ pub fn render(ppu: &NesPPU, frame: &mut Frame) {
         let sprite_palette = sprite_palette(ppu, pallette_idx);
         let bank: u16 = ppu.ctrl.sprt_pattern_addr();
 
-        let tile =
-            &ppu.chr_rom[(bank + tile_idx * 16) as usize..=(bank + tile_idx * 16 + 15) as usize];
+        let tile = tile(ppu, bank, tile_idx);
 
         for y in 0..=7 {
             let mut upper = tile[y];
@@ -0,0 +1,326 @@
+use crate::cartridge::{Mirroring, Timing};
+use crate::checksum::{crc32, sha1};
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+// Embedded game database used to fix broken iNES headers.
+//
+// Entries are matched on the CRC32 of PRG-ROM + CHR-ROM and confirmed with SHA-1,
+// so a CRC collision can't silently change the mapper of an unknown rom.
+//
+// romdb.txt is generated from nes20db.xml, the NES 2.0 header database kept on the
+// nesdev forums (https://forums.nesdev.org/viewtopic.php?t=19940). To regenerate it:
+// NES20DB=~/nes20db.xml cargo test romdb_from_nes20db -- --ignored
+//
+// The whole nes20db.xml can also be added at runtime with load_nes20db.
+#[derive(Debug, PartialEq, Clone)]
+pub struct GameInfo {
+    pub name: String,
+    pub sha1: [u8; 20],
+    pub mapper: u16,
+    // None when the mapper switches mirroring and the header bit means nothing
+    pub screen_mirroring: Option<Mirroring>,
+    pub timing: Timing,
+    pub battery: bool,
+}
+
+// https://www.nesdev.org/wiki/Mirroring#Mapper-controlled
+const MAPPER_CONTROLLED_MIRRORING: [u16; 30] = [
+    1, 4, 5, 7, 9, 10, 15, 16, 18, 19, 20, 21, 22, 23, 24, 25, 26, 32, 33, 48, 64, 65, 67, 68, 69,
+    73, 75, 85, 118, 159,
+];
+
+lazy_static! {
+    static ref GAMES: RwLock<HashMap<u32, GameInfo>> =
+        RwLock::new(parse(include_str!("romdb.txt")));
+}
+
+pub fn lookup(prg_rom: &[u8], chr_rom: &[u8]) -> Option<GameInfo> {
+    find(&GAMES.read().unwrap(), prg_rom, chr_rom).cloned()
+}
+
+// adds the games of nes20db.xml to the embedded ones, returns how many were read
+pub fn load_nes20db(xml: &str) -> usize {
+    let games = parse(&nes20db_entries(xml).join("\n"));
+    let count = games.len();
+    GAMES.write().unwrap().extend(games);
+    count
+}
+
+fn find<'a>(
+    games: &'a HashMap<u32, GameInfo>,
+    prg_rom: &[u8],
+    chr_rom: &[u8],
+) -> Option<&'a GameInfo> {
+    let mut payload = Vec::with_capacity(prg_rom.len() + chr_rom.len());
+    payload.extend_from_slice(prg_rom);
+    payload.extend_from_slice(chr_rom);
+
+    games
+        .get(&crc32(&payload))
+        .filter(|game| game.sha1 == sha1(&payload))
+}
+
+fn parse(db: &str) -> HashMap<u32, GameInfo> {
+    db.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            parse_entry(line).unwrap_or_else(|| panic!("malformed rom database entry: {}", line))
+        })
+        .collect()
+}
+
+fn parse_entry(line: &str) -> Option<(u32, GameInfo)> {
+    let mut fields = line.splitn(7, ' ');
+    let crc = u32::from_str_radix(fields.next()?, 16).ok()?;
+
+    let sha1_hex = fields.next()?;
+    if sha1_hex.len() != 40 {
+        return None;
+    }
+    let mut sha1 = [0u8; 20];
+    for (i, byte) in sha1.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(sha1_hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+
+    let mapper = fields.next()?.parse().ok()?;
+    let screen_mirroring = match fields.next()? {
+        "H" => Some(Mirroring::Horizontal),
+        "V" => Some(Mirroring::Vertical),
+        "4" => Some(Mirroring::FourScreen),
+        "M" => None,
+        _ => return None,
+    };
+    let timing = match fields.next()? {
+        "NTSC" => Timing::Ntsc,
+        "PAL" => Timing::Pal,
+        "MULTI" => Timing::MultiRegion,
+        "DENDY" => Timing::Dendy,
+        _ => return None,
+    };
+    let battery = match fields.next()? {
+        "0" => false,
+        "1" => true,
+        _ => return None,
+    };
+    let name = fields.next()?.to_string();
+
+    Some((
+        crc,
+        GameInfo {
+            name,
+            sha1,
+            mapper,
+            screen_mirroring,
+            timing,
+            battery,
+        },
+    ))
+}
+
+fn attribute<'a>(line: &'a str, name: &str) -> Option<&'a str> {
+    let start = line.find(&format!(" {}=\"", name))? + name.len() + 3;
+    let len = line[start..].find('"')?;
+    Some(&line[start..start + len])
+}
+
+// one romdb.txt line per <game> that has a whole <rom> checksum
+fn nes20db_entries(xml: &str) -> Vec<String> {
+    let mut entries = vec![];
+    let mut name = "";
+    let (mut rom, mut pcb, mut console) = (None, None, None);
+    for line in xml.lines().map(str::trim) {
+        if line.starts_with("<!--") {
+            // the comment holds the file name, e.g. "\Licensed\Super Mario Bros. (World).nes"
+            let file = line
+                .trim_start_matches("<!--")
+                .trim_end_matches("-->")
+                .trim();
+            let file = file.rsplit('\\').next().unwrap_or(file);
+            name = file.strip_suffix(".nes").unwrap_or(file);
+        } else if line.starts_with("<game") {
+            rom = None;
+            pcb = None;
+            console = None;
+        } else if line.starts_with("<rom ") {
+            rom = Some(line);
+        } else if line.starts_with("<pcb ") {
+            pcb = Some(line);
+        } else if line.starts_with("<console ") {
+            console = Some(line);
+        } else if line.starts_with("</game>") {
+            if let (Some(rom), Some(pcb), Some(console)) = (rom, pcb, console) {
+                let mapper = attribute(pcb, "mapper").unwrap_or("");
+                let mapper_controlled = mapper
+                    .parse()
+                    .is_ok_and(|mapper| MAPPER_CONTROLLED_MIRRORING.contains(&mapper));
+                let mirroring = match attribute(pcb, "mirroring") {
+                    Some("4") => "4",
+                    _ if mapper_controlled => "M",
+                    Some("V") => "V",
+                    Some("H") => "H",
+                    _ => "M",
+                };
+                let region = match attribute(console, "region") {
+                    Some("1") => "PAL",
+                    Some("2") => "MULTI",
+                    Some("3") => "DENDY",
+                    _ => "NTSC",
+                };
+                entries.push(format!(
+                    "{} {} {} {} {} {} {}",
+                    attribute(rom, "crc32").unwrap_or(""),
+                    attribute(rom, "sha1").unwrap_or(""),
+                    mapper,
+                    mirroring,
+                    region,
+                    attribute(pcb, "battery").unwrap_or("0"),
+                    name
+                ));
+            }
+        }
+    }
+    entries
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_embedded_database_is_well_formed() {
+        assert!(!GAMES.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_parse_entry() {
+        let (crc, game) = parse_entry(
+            "3337EC46 EA343F4E445A9050D4B4FBAC2C77D0693B1D0922 4 H PAL 1 Some Game (E)",
+        )
+        .unwrap();
+        assert_eq!(crc, 0x3337EC46);
+        assert_eq!(game.sha1[0], 0xEA);
+        assert_eq!(game.mapper, 4);
+        assert_eq!(game.screen_mirroring, Some(Mirroring::Horizontal));
+        assert_eq!(game.timing, Timing::Pal);
+        assert!(game.battery);
+        assert_eq!(game.name, "Some Game (E)");
+
+        let (_, game) = parse_entry(
+            "3337EC46 EA343F4E445A9050D4B4FBAC2C77D0693B1D0922 1 M NTSC 1 Some Game (U)",
+        )
+        .unwrap();
+        assert_eq!(game.screen_mirroring, None);
+
+        assert_eq!(parse_entry("3337EC46 EA34 0 H NTSC 0 Short sha"), None);
+    }
+
+    #[test]
+    fn test_lookup_requires_sha1_match() {
+        let prg_rom = vec![1; 16];
+        assert_eq!(lookup(&prg_rom, &[]), None);
+
+        let line = format!(
+            "{:08X} {} 1 V NTSC 0 Test",
+            crc32(&prg_rom),
+            hex(&sha1(&prg_rom))
+        );
+        let games = parse(&line);
+        assert_eq!(find(&games, &prg_rom, &[]).unwrap().mapper, 1);
+
+        // same CRC32, different SHA-1
+        let line = format!(
+            "{:08X} {} 1 V NTSC 0 Test",
+            crc32(&prg_rom),
+            "00".repeat(20)
+        );
+        let games = parse(&line);
+        assert_eq!(find(&games, &prg_rom, &[]), None);
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02X}", byte)).collect()
+    }
+
+    #[test]
+    fn test_nes20db_entries() {
+        let xml = r#"<nes20db>
+            <game>
+                <!-- \Licensed\Super Mario Bros. (World).nes -->
+                <rom size="40960" crc32="3337EC46" sha1="EA343F4E445A9050D4B4FBAC2C77D0693B1D0922"/>
+                <pcb mapper="0" submapper="0" mirroring="V" battery="0"/>
+                <console type="0" region="0"/>
+            </game>
+            <game>
+                <!-- \Licensed\Some Game (USA).nes -->
+                <rom size="262144" crc32="0000ABCD" sha1="EA343F4E445A9050D4B4FBAC2C77D0693B1D0922"/>
+                <pcb mapper="1" submapper="0" mirroring="H" battery="1"/>
+                <console type="0" region="0"/>
+            </game>
+        </nes20db>"#;
+        let entries = nes20db_entries(xml);
+        assert_eq!(
+            entries,
+            [
+                "3337EC46 EA343F4E445A9050D4B4FBAC2C77D0693B1D0922 0 V NTSC 0 Super Mario Bros. (World)",
+                "0000ABCD EA343F4E445A9050D4B4FBAC2C77D0693B1D0922 1 M NTSC 1 Some Game (USA)"
+            ]
+        );
+        assert_eq!(parse(&entries.join("\n")).len(), 2);
+    }
+
+    #[test]
+    fn test_load_nes20db() {
+        let prg_rom = vec![0x5A; 0x4000];
+        assert_eq!(lookup(&prg_rom, &[]), None);
+
+        let xml = format!(
+            r#"<game>
+                <!-- \Homebrew\Test.nes -->
+                <rom size="16384" crc32="{:08X}" sha1="{}"/>
+                <pcb mapper="4" submapper="0" mirroring="V" battery="0"/>
+                <console type="0" region="1"/>
+            </game>"#,
+            crc32(&prg_rom),
+            hex(&sha1(&prg_rom))
+        );
+        assert_eq!(load_nes20db(&xml), 1);
+
+        let game = lookup(&prg_rom, &[]).unwrap();
+        assert_eq!(game.name, "Test");
+        assert_eq!(game.mapper, 4);
+        assert_eq!(game.screen_mirroring, None);
+        assert_eq!(game.timing, Timing::Pal);
+    }
+
+    // keeps the comment block at the top of romdb.txt and replaces the entries,
+    // except for the ones nes20db doesn't know about
+    #[test]
+    #[ignore]
+    fn romdb_from_nes20db() {
+        let xml_path = std::env::var("NES20DB").unwrap_or_else(|_| "nes20db.xml".into());
+        let xml = std::fs::read_to_string(&xml_path).unwrap();
+        let db_path = concat!(env!("CARGO_MANIFEST_DIR"), "/src/romdb.txt");
+        let db = std::fs::read_to_string(db_path).unwrap();
+
+        let mut lines: Vec<String> = db
+            .lines()
+            .take_while(|line| line.starts_with('#'))
+            .map(str::to_string)
+            .collect();
+        let entries = nes20db_entries(&xml);
+        assert!(!entries.is_empty(), "no games in {}", xml_path);
+        let known = parse(&entries.join("\n"));
+        lines.extend(
+            db.lines()
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .filter(|line| !parse_entry(line).is_some_and(|(crc, _)| known.contains_key(&crc)))
+                .map(str::to_string),
+        );
+        lines.extend(entries);
+        lines.push(String::new());
+        std::fs::write(db_path, lines.join("\n")).unwrap();
+        assert!(!parse(&std::fs::read_to_string(db_path).unwrap()).is_empty());
+    }
+}
@@ -0,0 +1,167 @@
+// Runner for the per-opcode test vectors from https://github.com/SingleStepTests/65x02
+//
+// Every file in 6502/v1 (`a9.json`, `6d.json`, ...) holds 10000 vectors of one
+// opcode: registers and RAM before and after a single instruction, plus the
+// address, value and direction of every bus access in cycle order.
+//
+// The vectors are not part of the repo, point SINGLE_STEP_TESTS to a checkout:
+// SINGLE_STEP_TESTS=~/65x02/6502/v1 cargo test single_step -- --ignored
+use crate::cpu::test::{Access, MockBus};
+use crate::cpu::{CpuFlags, Variant, CPU};
+use crate::opcodes;
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+
+const DEFAULT_TESTS_DIR: &str = "SingleStepTests/6502/v1";
+
+fn field(state: &Value, name: &str) -> u64 {
+    state[name]
+        .as_u64()
+        .unwrap_or_else(|| panic!("missing field {}", name))
+}
+
+fn load_state(cpu: &mut CPU<MockBus>, state: &Value) {
+    cpu.program_counter = field(state, "pc") as u16;
+    cpu.stack_pointer = field(state, "s") as u8;
+    cpu.register_a = field(state, "a") as u8;
+    cpu.register_x = field(state, "x") as u8;
+    cpu.register_y = field(state, "y") as u8;
+    cpu.status = CpuFlags::from_bits_truncate(field(state, "p") as u8);
+    for cell in state["ram"].as_array().unwrap() {
+        let addr = cell[0].as_u64().unwrap() as usize;
+        cpu.bus.memory[addr] = cell[1].as_u64().unwrap() as u8;
+    }
+}
+
+// describes every difference between the CPU and the expected final state
+fn compare_state(cpu: &CPU<MockBus>, state: &Value, cycles: &[Value]) -> Vec<String> {
+    let mut errors = vec![];
+    let mut check = |name: &str, actual: u64, expected: u64| {
+        if actual != expected {
+            errors.push(format!("{}: {:x}, expected {:x}", name, actual, expected));
+        }
+    };
+
+    check("pc", cpu.program_counter as u64, field(state, "pc"));
+    check("s", cpu.stack_pointer as u64, field(state, "s"));
+    check("a", cpu.register_a as u64, field(state, "a"));
+    check("x", cpu.register_x as u64, field(state, "x"));
+    check("y", cpu.register_y as u64, field(state, "y"));
+    // B and bit 5 only exist in the copies pushed to the stack
+    check(
+        "p",
+        (cpu.status.bits() & 0b1100_1111) as u64,
+        field(state, "p") & 0b1100_1111,
+    );
+    for cell in state["ram"].as_array().unwrap() {
+        let addr = cell[0].as_u64().unwrap();
+        let value = cpu.bus.memory[addr as usize] as u64;
+        check(
+            &format!("ram ${:04x}", addr),
+            value,
+            cell[1].as_u64().unwrap(),
+        );
+    }
+
+    let expected: Vec<Access> = cycles
+        .iter()
+        .map(|cycle| {
+            let addr = cycle[0].as_u64().unwrap() as u16;
+            let data = cycle[1].as_u64().unwrap() as u8;
+            match cycle[2].as_str() {
+                Some("read") => Access::Read(addr, data),
+                Some("write") => Access::Write(addr, data),
+                other => panic!("unexpected bus activity {:?}", other),
+            }
+        })
+        .collect();
+    if cpu.bus.log != expected {
+        errors.push(format!("bus: {:x?}, expected {:x?}", cpu.bus.log, expected));
+    }
+    if cpu.bus.cycles != expected.len() {
+        errors.push(format!(
+            "cycles: {}, expected {}",
+            cpu.bus.cycles,
+            expected.len()
+        ));
+    }
+    errors
+}
+
+// runs all vectors of one opcode, returns the number of failures and the first one
+fn run_opcode_file(path: &Path) -> (usize, Option<String>) {
+    let json = fs::read_to_string(path).unwrap();
+    let vectors: Value = serde_json::from_str(&json).unwrap();
+
+    let mut failures = 0;
+    let mut first_failure = None;
+    for vector in vectors.as_array().unwrap() {
+        let mut cpu = CPU::with_variant(MockBus::new(&[], 0), Variant::Nmos6502);
+        load_state(&mut cpu, &vector["initial"]);
+        cpu.step();
+
+        let errors = compare_state(&cpu, &vector["final"], vector["cycles"].as_array().unwrap());
+        if !errors.is_empty() {
+            failures += 1;
+            if first_failure.is_none() {
+                first_failure = Some(format!("{}: {}", vector["name"], errors.join("; ")));
+            }
+        }
+    }
+    (failures, first_failure)
+}
+
+#[test]
+#[ignore]
+fn single_step_tests() {
+    let dir = std::env::var("SINGLE_STEP_TESTS").unwrap_or_else(|_| DEFAULT_TESTS_DIR.into());
+    let mut report = vec![];
+    let mut missing = vec![];
+    for code in 0..=0xffu8 {
+        let opcode = opcodes::OPCODES[code as usize].as_ref().unwrap();
+        // JAM locks up the CPU, the vectors model the bus activity of the stuck CPU
+        if opcode.mnemonic == "*JAM" {
+            continue;
+        }
+        let path = Path::new(&dir).join(format!("{:02x}.json", code));
+        if !path.exists() {
+            missing.push(format!("{:02x}", code));
+            continue;
+        }
+        if let (failures, Some(first)) = run_opcode_file(&path) {
+            report.push(format!(
+                "{:02x} {}: {} failed, {}",
+                code, opcode.mnemonic, failures, first
+            ));
+        }
+    }
+
+    if !missing.is_empty() {
+        report.push(format!("no vectors in {} for {}", dir, missing.join(" ")));
+    }
+    assert!(report.is_empty(), "\n{}", report.join("\n"));
+}
+
+#[test]
+fn test_single_step_vector() {
+    // a9 in the 6502/v1 format
+    let vector: Value = serde_json::from_str(
+        r#"{
+            "name": "a9 80 1c",
+            "initial": { "pc": 4660, "s": 253, "a": 1, "x": 2, "y": 3, "p": 36,
+                         "ram": [[4660, 169], [4661, 128]] },
+            "final": { "pc": 4662, "s": 253, "a": 128, "x": 2, "y": 3, "p": 164,
+                       "ram": [[4660, 169], [4661, 128]] },
+            "cycles": [[4660, 169, "read"], [4661, 128, "read"]]
+        }"#,
+    )
+    .unwrap();
+
+    let mut cpu = CPU::with_variant(MockBus::new(&[], 0), Variant::Nmos6502);
+    load_state(&mut cpu, &vector["initial"]);
+    cpu.step();
+
+    let errors = compare_state(&cpu, &vector["final"], vector["cycles"].as_array().unwrap());
+    assert!(errors.is_empty(), "{:?}", errors);
+}